@@ -1,8 +1,12 @@
 use clap::{Parser, Subcommand};
-use kvs_project_5::{Command, KvClient, Response};
+use kvs_project_5::{Command, CompareAndSwapError, KvClient, Response, ScanOrder};
 use std::{
+    fs,
+    io::{self, Write},
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
     process::exit,
+    time::Duration,
 };
 
 #[derive(Parser, Debug)]
@@ -17,41 +21,176 @@ struct Args {
     ]
     #[clap(help = "Server Address")]
     addr: SocketAddr,
+
+    #[clap(long, global = true)]
+    #[clap(help = "Give keys and values in hex, and print them in hex")]
+    hex: bool,
 }
 
 #[derive(Subcommand, Debug)]
 enum SubCommand {
-    #[clap(about = "Get string value of a given string key")]
+    #[clap(about = "Get the value of a given key")]
     Get {
-        #[clap(help = "The string key")]
+        #[clap(help = "The key")]
         key: String,
     },
 
-    #[clap(about = "Set string value of a given string key")]
+    #[clap(about = "Set the value of a given key")]
     Set {
-        #[clap(help = "The string key")]
+        #[clap(help = "The key")]
         key: String,
+        #[clap(required_unless_present = "file")]
         #[clap(help = "The value assigned to key")]
-        val: String,
+        val: Option<String>,
+        #[clap(long, conflicts_with = "val")]
+        #[clap(help = "Read the value assigned to key from this file")]
+        file: Option<PathBuf>,
+        #[clap(long)]
+        #[clap(parse(try_from_str = humantime::parse_duration))]
+        #[clap(help = "Time after which the key expires, e.g. 30s or 5m")]
+        ttl: Option<Duration>,
     },
 
     #[clap(about = "Remove a given key")]
     Rm {
-        #[clap(help = "The string key to remove")]
+        #[clap(help = "The key to remove")]
+        key: String,
+    },
+
+    #[clap(about = "Set or remove a key only if it holds the expected value")]
+    Cas {
+        #[clap(help = "The key")]
+        key: String,
+        #[clap(long)]
+        #[clap(help = "The value key must have, leave out if it must have none")]
+        expected: Option<String>,
+        #[clap(long)]
+        #[clap(help = "The new value of key, leave out to remove it")]
+        new: Option<String>,
+    },
+
+    #[clap(about = "Add to the integer value of a key and print the sum")]
+    Incr {
+        #[clap(help = "The key")]
+        key: String,
+        #[clap(long, default_value_t = 1, allow_hyphen_values = true)]
+        #[clap(help = "The amount to add, negative to subtract")]
+        by: i64,
+    },
+
+    #[clap(about = "Append bytes to the value of a key")]
+    Append {
+        #[clap(help = "The key")]
         key: String,
+        #[clap(help = "The bytes to append")]
+        suffix: String,
     },
+
+    #[clap(about = "List key-value pairs in key order")]
+    Scan {
+        #[clap(long)]
+        #[clap(help = "Only list keys starting with this prefix")]
+        prefix: Option<String>,
+        #[clap(long)]
+        #[clap(help = "List at most this many pairs")]
+        limit: Option<usize>,
+        #[clap(long)]
+        #[clap(help = "List keys from the biggest down")]
+        reverse: bool,
+    },
+
+    #[clap(about = "Copy the server's data into a new directory while it keeps running")]
+    Checkpoint {
+        #[clap(
+            help = "The directory under the server's checkpoint directory, which must be empty"
+        )]
+        dest: PathBuf,
+    },
+
+    #[clap(about = "Report how many keys the store holds and how much space they take up")]
+    Stats,
 }
 
+// number of pairs fetched from the server at a time
+const SCAN_PAGE_SIZE: usize = 100;
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    let hex = args.hex;
 
     let command = match args.command {
-        SubCommand::Get { key } => Command::Get { key },
+        SubCommand::Get { key } => {
+            get(args.addr, decode(key, hex), hex).await;
+            exit(0);
+        }
 
-        SubCommand::Set { key, val } => Command::Set { key, val },
+        SubCommand::Set {
+            key,
+            val,
+            file,
+            ttl,
+        } => {
+            let key = decode(key, hex);
+            let val = match (val, file) {
+                (Some(val), _) => decode(val, hex),
+                (None, Some(file)) => fs::read(&file).unwrap_or_else(|err| {
+                    eprintln!("Fail to read {}: {}", file.display(), err);
+                    exit(1);
+                }),
+                (None, None) => unreachable!("clap requires val or file"),
+            };
+            match ttl {
+                Some(ttl) => Command::SetWithTtl { key, val, ttl },
+                None => Command::Set { key, val },
+            }
+        }
+
+        SubCommand::Rm { key } => Command::Remove {
+            key: decode(key, hex),
+        },
+
+        SubCommand::Cas { key, expected, new } => {
+            let key = decode(key, hex);
+            let expected = expected.map(|expected| decode(expected, hex));
+            let new = new.map(|new| decode(new, hex));
+            // a mismatch exits with its own code, so that scripts
+            // can tell it apart from an error
+            let code = compare_and_swap(args.addr, key, expected, new, hex).await;
+            exit(code);
+        }
+
+        SubCommand::Incr { key, by } => Command::IncrBy {
+            key: decode(key, hex),
+            delta: by,
+        },
+
+        SubCommand::Append { key, suffix } => Command::Append {
+            key: decode(key, hex),
+            suffix: decode(suffix, hex),
+        },
+
+        SubCommand::Scan {
+            prefix,
+            limit,
+            reverse,
+        } => {
+            let order = if reverse {
+                ScanOrder::Reverse
+            } else {
+                ScanOrder::Forward
+            };
+            let prefix = prefix.map(|prefix| decode(prefix, hex));
+            scan(args.addr, prefix, limit.unwrap_or(usize::MAX), order, hex).await;
+            exit(0);
+        }
 
-        SubCommand::Rm { key } => Command::Remove { key },
+        SubCommand::Checkpoint { dest } => Command::Checkpoint { dest },
+
+        SubCommand::Stats => {
+            stats(args.addr).await;
+            exit(0);
+        }
     };
 
     let mut client = KvClient::connect(args.addr)
@@ -69,7 +208,7 @@ async fn main() {
             message,
         } => {
             if !message.is_empty() {
-                println!("{}", message);
+                print_line(&[&message], hex);
             }
             exit(0);
         }
@@ -77,8 +216,151 @@ async fn main() {
             success: false,
             message,
         } => {
-            eprintln!("{}", message);
+            eprintln!("{}", String::from_utf8_lossy(&message));
+            exit(1);
+        }
+    }
+}
+
+// the bytes given on the command line as arg, which is hex if hex is set
+fn decode(arg: String, hex: bool) -> Vec<u8> {
+    if !hex {
+        return arg.into_bytes();
+    }
+    hex::decode(&arg).unwrap_or_else(|err| {
+        eprintln!("Invalid hex {}: {}", arg, err);
+        exit(1);
+    })
+}
+
+// print fields on a line separated by spaces, as
+// raw bytes or in hex if hex is set
+fn print_line(fields: &[&[u8]], hex: bool) {
+    let mut line = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            line.push(b' ');
+        }
+        if hex {
+            line.extend_from_slice(hex::encode(field).as_bytes());
+        } else {
+            line.extend_from_slice(field);
+        }
+    }
+    line.push(b'\n');
+    io::stdout()
+        .write_all(&line)
+        .expect("Fail to write to stdout");
+}
+
+// print the value of key, or that it has none
+async fn get(addr: SocketAddr, key: Vec<u8>, hex: bool) {
+    let mut client = KvClient::connect(addr)
+        .await
+        .expect("Fail to create connection");
+    match client.send_get(key).await {
+        Ok(Some(val)) => print_line(&[&val], hex),
+        Ok(None) => println!("Key not found"),
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    }
+}
+
+// swap the value of key, printing the current value on a mismatch,
+// and return the exit code: 0 if swapped, 2 on a mismatch
+async fn compare_and_swap(
+    addr: SocketAddr,
+    key: Vec<u8>,
+    expected: Option<Vec<u8>>,
+    new: Option<Vec<u8>>,
+    hex: bool,
+) -> i32 {
+    let mut client = KvClient::connect(addr)
+        .await
+        .expect("Fail to create connection");
+    match client.send_compare_and_swap(key, expected, new).await {
+        Ok(Ok(())) => 0,
+        Ok(Err(CompareAndSwapError { current })) => {
+            match current {
+                Some(current) => print_line(&[&current], hex),
+                None => println!("Key not found"),
+            }
+            2
+        }
+        Err(err) => {
+            eprintln!("{}", err);
             exit(1);
         }
     }
 }
+
+// print up to limit pairs, walking the scan page by page
+async fn scan(
+    addr: SocketAddr,
+    prefix: Option<Vec<u8>>,
+    mut limit: usize,
+    order: ScanOrder,
+    hex: bool,
+) {
+    let mut client = KvClient::connect(addr)
+        .await
+        .expect("Fail to create connection");
+
+    let mut cursor = None;
+    while limit > 0 {
+        let page = match client
+            .send_scan(prefix.clone(), limit.min(SCAN_PAGE_SIZE), order, cursor)
+            .await
+        {
+            Ok(page) => page,
+            Err(err) => {
+                eprintln!("{}", err);
+                exit(1);
+            }
+        };
+        limit -= page.entries.len();
+        for (key, val) in page.entries {
+            print_line(&[&key, &val], hex);
+        }
+        cursor = match page.cursor {
+            Some(cursor) => Some(cursor),
+            None => break,
+        };
+    }
+}
+
+// print the stats of the server's store one per line,
+// leaving out those the engine doesn't have
+async fn stats(addr: SocketAddr) {
+    let mut client = KvClient::connect(addr)
+        .await
+        .expect("Fail to create connection");
+    let stats = match client.send_stats().await {
+        Ok(stats) => stats,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
+
+    println!("keys {}", stats.key_count);
+    println!("live_bytes {}", stats.live_bytes);
+    if let Some(bytes) = stats.uncompacted_bytes {
+        println!("uncompacted_bytes {}", bytes);
+    }
+    println!("disk_bytes {}", stats.disk_bytes);
+    if let Some(generations) = stats.generations {
+        println!("generations {}", generations);
+    }
+    match stats.last_compaction {
+        Some(time) => println!(
+            "last_compaction {}",
+            humantime::format_rfc3339_seconds(time)
+        ),
+        // only a store with generations compacts them
+        None if stats.generations.is_some() => println!("last_compaction never"),
+        None => {}
+    }
+}
@@ -0,0 +1,47 @@
+use clap::Parser;
+use failure::Fail;
+use kvs_project_5::{thread_pool::NaiveThreadPool, KvStore, KvStoreOptions, RestorePoint};
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::SystemTime;
+
+/// Rebuild a store from the archive of a `kvs-server5 --archive` run,
+/// as it was at a given point in time
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    #[clap(help = "The archive directory")]
+    archive: PathBuf,
+
+    #[clap(help = "The directory of the new store, which must be empty")]
+    dest: PathBuf,
+
+    #[clap(long, conflicts_with = "time")]
+    #[clap(help = "Replay the records up to this sequence number")]
+    seq: Option<u64>,
+
+    #[clap(long)]
+    #[clap(parse(try_from_str = humantime::parse_rfc3339_weak))]
+    #[clap(help = "Replay the records written by this time, e.g. 2022-05-01T12:00:00Z")]
+    time: Option<SystemTime>,
+}
+
+fn main() {
+    let args = Args::parse();
+    let point = match (args.seq, args.time) {
+        (Some(seq), _) => RestorePoint::Seq(seq),
+        (None, Some(time)) => RestorePoint::Time(time),
+        (None, None) => RestorePoint::Latest,
+    };
+
+    let options = KvStoreOptions::new().pool_capacity(1);
+    if let Err(err) =
+        KvStore::<NaiveThreadPool>::restore_archive(&args.archive, &args.dest, point, &options)
+    {
+        eprintln!(
+            "Cannot restore: {}",
+            err.cause().map_or(err.to_string(), ToString::to_string)
+        );
+        exit(1);
+    }
+}
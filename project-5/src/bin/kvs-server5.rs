@@ -1,9 +1,13 @@
 use clap::Parser;
-use kvs_project_5::{thread_pool::*, KvServer, KvStore};
+use kvs_project_5::{
+    thread_pool::*, Compression, FlushPolicy, KvServer, KvStore, KvStoreOptions, KvsEngine,
+    SledKvsEngine, SyncPolicy,
+};
 use std::fmt;
 use std::fs::OpenOptions;
 use std::io::{Read, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::process::exit;
 use tracing::{info, Level};
 
@@ -20,6 +24,105 @@ struct Args {
     #[clap(long)]
     #[clap(help = "KV Engine used by server")]
     engine: Option<Engine>,
+
+    #[clap(long)]
+    #[clap(default_value_t = 5)]
+    #[clap(help = "Number of threads doing storage I/O")]
+    threads: i32,
+
+    #[clap(long)]
+    #[clap(help = "Stale bytes that trigger a compaction (kvs engine)")]
+    compaction_threshold: Option<u64>,
+
+    #[clap(long)]
+    #[clap(help = "Size in bytes after which a new logfile is started (kvs engine)")]
+    max_file_size: Option<u64>,
+
+    #[clap(long)]
+    #[clap(
+        help = "When writes are synced, `always`, `os` or an interval like `100ms` (kvs engine)"
+    )]
+    sync: Option<SyncPolicy>,
+
+    #[clap(long)]
+    #[clap(help = "Logfile handles kept open for reads (kvs engine)")]
+    reader_cache_size: Option<usize>,
+
+    #[clap(long)]
+    #[clap(help = "Bytes of hot keys and values cached in memory (kvs engine)")]
+    value_cache_size: Option<usize>,
+
+    #[clap(long)]
+    #[clap(help = "Memory-map the logfiles no longer written to (kvs engine)")]
+    mmap: bool,
+
+    #[clap(long)]
+    #[clap(help = "Codec of new values, `none`, `lz4` or `zstd` (kvs engine)")]
+    compression: Option<Compression>,
+
+    #[clap(long)]
+    #[clap(help = "Size in bytes below which values are not compressed (kvs engine)")]
+    compression_threshold: Option<usize>,
+
+    #[clap(long)]
+    #[clap(
+        help = "Archive every record written into this directory, for kvs-restore5 (kvs engine)"
+    )]
+    archive: Option<PathBuf>,
+
+    #[clap(long)]
+    #[clap(
+        help = "When writes are flushed, `every-write`, `interval=<ms>` or `never` (sled engine)"
+    )]
+    sled_flush: Option<FlushPolicy>,
+
+    #[clap(long)]
+    #[clap(help = "Directory under which clients may have checkpoints written")]
+    checkpoint_dir: Option<PathBuf>,
+}
+
+impl Args {
+    // options for the kvs engine, flags not given keep their defaults
+    fn store_options(&self) -> KvStoreOptions {
+        let mut options = KvStoreOptions::new().pool_capacity(self.threads);
+        if let Some(threshold) = self.compaction_threshold {
+            options = options.compaction_threshold(threshold);
+        }
+        if let Some(size) = self.max_file_size {
+            options = options.max_file_size(size);
+        }
+        if let Some(policy) = self.sync {
+            options = options.sync_policy(policy);
+        }
+        if let Some(size) = self.reader_cache_size {
+            options = options.reader_cache_size(size);
+        }
+        if let Some(size) = self.value_cache_size {
+            options = options.value_cache_size(size);
+        }
+        if self.mmap {
+            options = options.mmap_reads(true);
+        }
+        if let Some(compression) = self.compression {
+            options = options.compression(compression);
+        }
+        if let Some(threshold) = self.compression_threshold {
+            options = options.compression_threshold(threshold);
+        }
+        if let Some(dir) = &self.archive {
+            options = options.archive_dir(dir);
+        }
+        options
+    }
+
+    // a server for engine, set up with the flags that apply to any engine
+    fn server<T: KvsEngine>(&self, engine: T) -> KvServer<T> {
+        let mut server = KvServer::new(engine);
+        if let Some(dir) = &self.checkpoint_dir {
+            server = server.checkpoint_dir(dir);
+        }
+        server
+    }
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -88,10 +191,12 @@ async fn main() {
 
     info!("Application Started: Version {}", env!("CARGO_PKG_VERSION"));
 
-    create_storage_and_run(args.engine, args.addr).await;
+    create_storage_and_run(&args).await;
 }
 
-async fn create_storage_and_run(kind: Option<Engine>, addr: SocketAddr) {
+async fn create_storage_and_run(args: &Args) {
+    let kind = args.engine;
+    let addr = args.addr;
     let dirpath = std::env::current_dir().unwrap();
 
     let metadata_path = dirpath.join("metadata");
@@ -137,17 +242,28 @@ async fn create_storage_and_run(kind: Option<Engine>, addr: SocketAddr) {
 
     match final_engine {
         Engine::Kvs => {
-            let engine = KvStore::<SharedQueueThreadPool>::open(&dirpath, 5).unwrap();
-            let server = KvServer::new(engine);
+            // another server may have the directory open already
+            let engine: KvStore<SharedQueueThreadPool> = match args.store_options().open(&dirpath) {
+                Ok(engine) => engine,
+                Err(err) => {
+                    eprintln!("Cannot open store: {}", err);
+                    exit(1);
+                }
+            };
+            let server = args.server(engine);
             server.run(addr).await.unwrap();
         }
 
         Engine::Sled => {
-            // let engine = SledKvsEngine::open(&dirpath).unwrap();
-            // let pool = SharedQueueThreadPool::new(5).unwrap();
-            // let server = KvServer::new(engine);
-            // server.run();
-            unimplemented!()
+            let flush = args.sled_flush.unwrap_or_default();
+            let engine = SledKvsEngine::<SharedQueueThreadPool>::open_with_flush(
+                &dirpath,
+                args.threads,
+                flush,
+            )
+            .unwrap();
+            let server = args.server(engine);
+            server.run(addr).await.unwrap();
         }
     }
 }
@@ -23,6 +23,30 @@ pub enum KVErrorKind {
     /// Serialization/Deserialization Error triggered by serde
     #[fail(display = "Json parsing error")]
     JsonError,
+    /// Serialization/Deserialization Error triggered by bincode
+    #[fail(display = "Bincode encoding error")]
+    BincodeError,
+    /// A transaction wrote a key that another write
+    /// changed since the transaction began
+    #[fail(display = "Transaction conflict")]
+    TransactionConflict,
+    /// A log record failed its checksum or is malformed
+    #[fail(display = "Log record corrupted")]
+    Corruption,
+    /// Another process has the store directory
+    /// open in a conflicting mode
+    #[fail(display = "Store directory is locked")]
+    DirectoryLocked,
+    /// A write to a store opened read-only
+    #[fail(display = "Store is read-only")]
+    ReadOnly,
+    /// A value incremented is not an integer,
+    /// or the result does not fit in an i64
+    #[fail(display = "Value is not an integer or the result overflows")]
+    InvalidInteger,
+    /// A merge on an engine that has no merge operator registered
+    #[fail(display = "No merge operator registered")]
+    NoMergeOperator,
     /// Error triggered by sled engine
     #[fail(display = "Sled Error")]
     SledError,
@@ -89,6 +113,12 @@ impl From<serde_json::Error> for KVError {
     }
 }
 
+impl From<bincode::Error> for KVError {
+    fn from(error: bincode::Error) -> KVError {
+        error.context(KVErrorKind::BincodeError).into()
+    }
+}
+
 impl From<sled::Error> for KVError {
     fn from(error: sled::Error) -> KVError {
         error.context(KVErrorKind::SledError).into()
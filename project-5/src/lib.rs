@@ -14,8 +14,13 @@ extern crate failure;
 pub use error::KVError;
 pub use error::KVErrorKind;
 // pub use network::{Command, KvClient, KvServer, Response};
-pub use network::{Command, KvClient, KvServer, Response};
-pub use storage::{KvStore, KvsEngine, SledKvsEngine};
+pub use network::{Command, KvClient, KvServer, Response, ScanPage};
+pub use storage::{
+    BatchOp, CompareAndSwapError, Compression, EngineStats, FlushPolicy, KeyRange, KvStore,
+    KvStoreOptions, KvStoreSnapshot, KvStoreStats, KvStoreTransaction, KvsEngine, KvsSnapshot,
+    KvsTransaction, RecoveryMode, RestorePoint, ScanOrder, SledKvsEngine, SledSnapshot,
+    SledTransaction, SyncPolicy, WriteBatch,
+};
 
 /// Result type used by this crate
 pub type Result<T> = core::result::Result<T, KVError>;
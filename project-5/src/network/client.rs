@@ -1,8 +1,11 @@
-use super::{Command, Response};
-use crate::{KVErrorKind, Result};
+use super::{Command, Response, ScanPage};
+use crate::{CompareAndSwapError, EngineStats, KVErrorKind, Result, ScanOrder, WriteBatch};
 use futures::{SinkExt, StreamExt};
+use serde::de::DeserializeOwned;
+use std::path::PathBuf;
+use std::time::Duration;
 use tokio::net::{TcpStream, ToSocketAddrs};
-use tokio_serde::formats::SymmetricalJson;
+use tokio_serde::formats::SymmetricalBincode;
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
 /// KvClient connects to a running [KvServer](crate::KvServer) through TCP and propagate user's
@@ -36,12 +39,12 @@ impl KvClient {
         let mut deserialized: tokio_serde::Framed<_, Response, Response, _> =
             tokio_serde::SymmetricallyFramed::new(
                 length_delimited_read,
-                SymmetricalJson::<Response>::default(),
+                SymmetricalBincode::<Response>::default(),
             );
 
         let mut serialized = tokio_serde::SymmetricallyFramed::new(
             length_delimited,
-            SymmetricalJson::<Command>::default(),
+            SymmetricalBincode::<Command>::default(),
         );
 
         serialized.send(command).await?;
@@ -53,18 +56,155 @@ impl KvClient {
         }
     }
 
-    /// send a get command with key
-    pub async fn send_get(&mut self, key: String) -> Result<Response> {
-        self.send(Command::Get { key }).await
+    /// send a get command with key and return its value, None if
+    /// it has none
+    pub async fn send_get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let response = self.send(Command::Get { key: key.into() }).await?;
+        decode_response(response)
     }
 
     /// send a set command with key and val
-    pub async fn send_set(&mut self, key: String, val: String) -> Result<Response> {
-        self.send(Command::Set { key, val }).await
+    pub async fn send_set(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        val: impl Into<Vec<u8>>,
+    ) -> Result<Response> {
+        self.send(Command::Set {
+            key: key.into(),
+            val: val.into(),
+        })
+        .await
+    }
+
+    /// send a set command with key and val, which expires after ttl
+    pub async fn send_set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        val: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<Response> {
+        self.send(Command::SetWithTtl {
+            key: key.into(),
+            val: val.into(),
+            ttl,
+        })
+        .await
     }
 
     /// send a remove command with key
-    pub async fn send_rm(&mut self, key: String) -> Result<Response> {
-        self.send(Command::Remove { key }).await
+    pub async fn send_rm(&mut self, key: impl Into<Vec<u8>>) -> Result<Response> {
+        self.send(Command::Remove { key: key.into() }).await
+    }
+
+    /// start a transaction that lasts until it is committed or
+    /// rolled back, or the client disconnects
+    pub async fn send_begin(&mut self) -> Result<Response> {
+        self.send(Command::Begin).await
+    }
+
+    /// commit the transaction started by send_begin
+    pub async fn send_commit(&mut self) -> Result<Response> {
+        self.send(Command::Commit).await
+    }
+
+    /// roll back the transaction started by send_begin
+    pub async fn send_rollback(&mut self) -> Result<Response> {
+        self.send(Command::Rollback).await
+    }
+
+    /// send a compare-and-swap command, which sets key to new (or
+    /// removes it if new is None) if its value is expected, and
+    /// return the current value if it is not
+    pub async fn send_compare_and_swap(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        let response = self
+            .send(Command::CompareAndSwap {
+                key: key.into(),
+                expected,
+                new,
+            })
+            .await?;
+        decode_response(response)
+    }
+
+    /// send an increment of the integer value of key by delta,
+    /// answered with the sum
+    pub async fn send_incr_by(&mut self, key: impl Into<Vec<u8>>, delta: i64) -> Result<Response> {
+        self.send(Command::IncrBy {
+            key: key.into(),
+            delta,
+        })
+        .await
+    }
+
+    /// send an append of suffix to the value of key
+    pub async fn send_append(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        suffix: impl Into<Vec<u8>>,
+    ) -> Result<Response> {
+        self.send(Command::Append {
+            key: key.into(),
+            suffix: suffix.into(),
+        })
+        .await
+    }
+
+    /// send a batch of sets and removes to be applied atomically
+    pub async fn send_write_batch(&mut self, batch: WriteBatch) -> Result<Response> {
+        self.send(Command::WriteBatch { batch }).await
+    }
+
+    /// make the server copy its data into dest, a directory
+    /// on the server's machine
+    pub async fn send_checkpoint(&mut self, dest: impl Into<PathBuf>) -> Result<Response> {
+        self.send(Command::Checkpoint { dest: dest.into() }).await
+    }
+
+    /// send a scan command for the keys starting with prefix, or
+    /// for all keys if prefix is None, and return the page of the
+    /// result following cursor
+    pub async fn send_scan(
+        &mut self,
+        prefix: Option<Vec<u8>>,
+        limit: usize,
+        order: ScanOrder,
+        cursor: Option<Vec<u8>>,
+    ) -> Result<ScanPage> {
+        let response = self
+            .send(Command::Scan {
+                start: None,
+                end: None,
+                prefix,
+                limit,
+                order,
+                cursor,
+            })
+            .await?;
+        decode_response(response)
+    }
+
+    /// ask the server how its store is doing
+    pub async fn send_stats(&mut self) -> Result<EngineStats> {
+        let response = self.send(Command::Stats).await?;
+        decode_response(response)
+    }
+}
+
+// the bincode carried by a successful response, or
+// an error with the message of a failed one
+fn decode_response<T: DeserializeOwned>(response: Response) -> Result<T> {
+    if response.success {
+        Ok(bincode::deserialize(&response.message)?)
+    } else {
+        Err(
+            failure::err_msg(String::from_utf8_lossy(&response.message).into_owned())
+                .context(KVErrorKind::UnknownError)
+                .into(),
+        )
     }
 }
@@ -1,28 +1,131 @@
+use crate::{ScanOrder, WriteBatch};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
 
 /// A client's Command, which describes what operation client intends to perform
 /// on the KvsEngine at the Server end and the argument provided to those operations.
+///
+/// Commands and [Response]s travel as bincode in length-delimited frames,
+/// so keys and values of any bytes go over the wire at their own size.
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Command {
-    /// get the string value of key
+    /// get the value of key, answered with an `Option<Vec<u8>>`
+    /// serialized as bincode, None if key has no value
     Get {
-        /// the string key
-        key: String,
+        /// the key
+        key: Vec<u8>,
     },
 
     /// set the value of key
     Set {
-        /// the string key
-        key: String,
+        /// the key
+        key: Vec<u8>,
         /// the value
-        val: String,
+        val: Vec<u8>,
+    },
+
+    /// set the value of key, which expires after ttl
+    SetWithTtl {
+        /// the key
+        key: Vec<u8>,
+        /// the value
+        val: Vec<u8>,
+        /// how long the key lives
+        ttl: Duration,
     },
 
     /// remove the value of key
     Remove {
-        /// the string key
-        key: String,
+        /// the key
+        key: Vec<u8>,
+    },
+
+    /// set the value of key to new, or remove it if new is None, provided
+    /// its current value is expected. Once the comparison has run, it is
+    /// answered with a `Result<(), CompareAndSwapError>` serialized as
+    /// bincode, so a mismatch is told apart from a failing engine
+    CompareAndSwap {
+        /// the key
+        key: Vec<u8>,
+        /// the value key should have, None for no value
+        expected: Option<Vec<u8>>,
+        /// the value to set, None to remove key
+        new: Option<Vec<u8>>,
+    },
+
+    /// add delta to the integer value of key, answered with
+    /// the sum as decimal text, see [incr_by](crate::KvsEngine::incr_by)
+    IncrBy {
+        /// the key
+        key: Vec<u8>,
+        /// the amount to add, negative to subtract
+        delta: i64,
+    },
+
+    /// append suffix to the value of key,
+    /// see [append](crate::KvsEngine::append)
+    Append {
+        /// the key
+        key: Vec<u8>,
+        /// the bytes to append
+        suffix: Vec<u8>,
+    },
+
+    /// apply the ops of a batch atomically
+    WriteBatch {
+        /// the batch
+        batch: WriteBatch,
     },
+
+    /// get one page of the key-value pairs in a range of keys,
+    /// answered with a [ScanPage] serialized as bincode
+    Scan {
+        /// the first key of the range, included
+        start: Option<Vec<u8>>,
+        /// the key the range stops before, excluded
+        end: Option<Vec<u8>>,
+        /// only keys starting with prefix, in place of start and end
+        prefix: Option<Vec<u8>>,
+        /// the maximum number of pairs in the page, at least 1
+        limit: usize,
+        /// the order keys are visited in
+        order: ScanOrder,
+        /// the cursor of the previous page, if this is not the first one
+        cursor: Option<Vec<u8>>,
+    },
+
+    /// start a transaction on this connection. Until it ends, the
+    /// gets, sets and removes sent on the connection are part of it
+    Begin,
+
+    /// commit the transaction of this connection
+    Commit,
+
+    /// roll back the transaction of this connection
+    Rollback,
+
+    /// copy the data as it is now into dest, a relative path under the
+    /// server's [checkpoint directory](crate::KvServer::checkpoint_dir),
+    /// see [checkpoint](crate::KvsEngine::checkpoint)
+    Checkpoint {
+        /// the directory to copy into, which must be empty
+        dest: PathBuf,
+    },
+
+    /// report on the health of the store, answered with an
+    /// [EngineStats](crate::EngineStats) serialized as bincode
+    Stats,
+}
+
+/// A page of the result of a [Command::Scan]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ScanPage {
+    /// the key-value pairs, in scan order
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    /// pass this back in the next [Command::Scan] to get the
+    /// next page, None if there are no more pairs
+    pub cursor: Option<Vec<u8>>,
 }
 
 /// Server's Response that corresponds to the previous [Command](crate::Command)
@@ -32,23 +135,23 @@ pub struct Response {
     pub success: bool,
     /// the message of the previous command, it carries possible data on success
     /// and error message on failure
-    pub message: String,
+    pub message: Vec<u8>,
 }
 
 impl Response {
     /// construct a success response
-    pub fn success(message: String) -> Self {
+    pub fn success(message: impl Into<Vec<u8>>) -> Self {
         Self {
             success: true,
-            message,
+            message: message.into(),
         }
     }
 
     /// construct a failure response
-    pub fn failure(message: String) -> Self {
+    pub fn failure(message: impl Into<Vec<u8>>) -> Self {
         Self {
             success: false,
-            message,
+            message: message.into(),
         }
     }
 }
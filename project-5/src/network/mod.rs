@@ -3,5 +3,5 @@ mod common;
 pub(self) mod server;
 
 pub use client::KvClient;
-pub use common::{Command, Response};
+pub use common::{Command, Response, ScanPage};
 pub use server::KvServer;
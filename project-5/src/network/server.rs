@@ -1,8 +1,13 @@
-use super::{Command, Response};
-use crate::{KvsEngine, Result};
+use super::{Command, Response, ScanPage};
+use crate::storage::prefix_range;
+use crate::{KvsEngine, KvsTransaction, Result, ScanOrder};
 use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
-use tokio_serde::formats::SymmetricalJson;
+use tokio_serde::formats::SymmetricalBincode;
 use tokio_serde::Framed;
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tracing::{debug, error};
@@ -32,27 +37,25 @@ use tracing::{debug, error};
 ///     tokio::spawn(async move {
 ///         server.run("127.0.0.1:4000").await.unwrap();
 ///     });
+///     // give the server time to start listening
+///     tokio::time::sleep(std::time::Duration::from_millis(500)).await;
 ///
 ///     // query the server
 ///     let mut cli = KvClient::connect("127.0.0.1:4000").await.unwrap();
-///     assert_eq!(Response {
-///         success: true,
-///         message: "Key not found".to_string()    
-///     },
-///     cli.send_get("key".to_string()).await.unwrap()
-///     );
+///     assert_eq!(None, cli.send_get("key".to_string()).await.unwrap());
 ///
-///     cli.send_set("key".to_string(), "value".to_string()).await.unwrap();
-///     assert_eq!(Response {
-///         success: true,
-///         message: "value".to_string()    
-///     },
-///     cli.send_get("key".to_string()).await.unwrap()
+///     let response = cli.send_set("key".to_string(), "value".to_string()).await.unwrap();
+///     assert_eq!(Response::success(Vec::new()), response);
+///     assert_eq!(
+///         Some(b"value".to_vec()),
+///         cli.send_get("key".to_string()).await.unwrap()
 ///     );
 /// }
 ///
 pub struct KvServer<T: KvsEngine> {
     store: T,
+    // where clients may have checkpoints written, None if nowhere
+    checkpoint_dir: Option<Arc<PathBuf>>,
 }
 
 impl<T: KvsEngine> KvServer<T> {
@@ -61,7 +64,18 @@ impl<T: KvsEngine> KvServer<T> {
     /// # Error
     /// IoError generated by creating a TcpListener
     pub fn new(store: T) -> Self {
-        Self { store }
+        Self {
+            store,
+            checkpoint_dir: None,
+        }
+    }
+
+    /// let clients checkpoint the store into directories under dir.
+    /// The destination of a [Checkpoint](Command::Checkpoint) is
+    /// resolved against dir, and without one checkpoints are refused
+    pub fn checkpoint_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.checkpoint_dir = Some(Arc::new(dir.as_ref().to_path_buf()));
+        self
     }
 
     /// Run the server
@@ -71,9 +85,10 @@ impl<T: KvsEngine> KvServer<T> {
             let (socket, addr) = listener.accept().await?;
             debug!("Connected to Addr: {:?}", addr);
             let store = self.store.clone();
+            let checkpoint_dir = self.checkpoint_dir.clone();
             // spawn a new async task that handles the connection
             tokio::spawn(async move {
-                let result = serve(store, socket).await;
+                let result = serve(store, checkpoint_dir, socket).await;
                 if let Err(err) = result {
                     error!("Error handling connection: {}", err);
                 }
@@ -82,55 +97,100 @@ impl<T: KvsEngine> KvServer<T> {
     }
 }
 
-async fn serve<T: KvsEngine>(store: T, mut socket: TcpStream) -> Result<()> {
+async fn serve<T: KvsEngine>(
+    store: T,
+    checkpoint_dir: Option<Arc<PathBuf>>,
+    mut socket: TcpStream,
+) -> Result<()> {
     let (read_half, write_half) = socket.split();
 
     let length_delimited = FramedRead::new(read_half, LengthDelimitedCodec::new());
     let mut deserialized: tokio_serde::Framed<_, Command, Command, _> =
         tokio_serde::SymmetricallyFramed::new(
             length_delimited,
-            SymmetricalJson::<Command>::default(),
+            SymmetricalBincode::<Command>::default(),
         );
 
     let length_delimited_write = FramedWrite::new(write_half, LengthDelimitedCodec::new());
     let mut serialized: Framed<_, Response, Response, _> = tokio_serde::SymmetricallyFramed::new(
         length_delimited_write,
-        SymmetricalJson::<Response>::default(),
+        SymmetricalBincode::<Response>::default(),
     );
 
+    // the transaction of this connection, rolled
+    // back if the client goes away in the middle
+    let mut txn: Option<T::Transaction> = None;
+
     while let Some(msg) = deserialized.next().await {
         let msg = msg?;
-        let response = match msg {
-            Command::Get { key } => {
-                // we must create a temporary binding so that a reference
-                // to store will not be used across await point, since &T
-                // is not Sync
-                let res = store.get(key);
+        let response = match (msg, &mut txn) {
+            (Command::Begin, Some(_)) => Response::failure("Transaction already begun".to_owned()),
+            (Command::Begin, None) => {
+                let res = store.begin();
+                let res = res.await;
+                match res {
+                    Ok(new_txn) => {
+                        txn = Some(new_txn);
+                        Response::success(Vec::new())
+                    }
+                    Err(error) => Response::failure(error.to_string()),
+                }
+            }
+
+            (Command::Commit, Some(_)) => {
+                let res = txn.take().unwrap().commit();
                 let res = res.await;
                 match res {
-                    Ok(val) => Response::success(val.unwrap_or_else(|| "Key not found".to_owned())),
+                    Ok(_) => Response::success(Vec::new()),
+                    Err(error) => Response::failure(error.to_string()),
+                }
+            }
 
+            (Command::Rollback, Some(_)) => {
+                let res = txn.take().unwrap().rollback();
+                let res = res.await;
+                match res {
+                    Ok(_) => Response::success(Vec::new()),
                     Err(error) => Response::failure(error.to_string()),
                 }
             }
 
-            Command::Set { key, val } => {
-                let res = store.set(key, val);
+            (Command::Commit, None) | (Command::Rollback, None) => {
+                Response::failure("No transaction begun".to_owned())
+            }
+
+            (Command::Get { key }, Some(txn)) => {
+                let res = txn.get(key);
                 let res = res.await;
                 match res {
-                    Ok(_) => Response::success("".to_owned()),
+                    Ok(val) => encode(&val),
                     Err(error) => Response::failure(error.to_string()),
                 }
             }
 
-            Command::Remove { key } => {
-                let res = store.remove(key);
+            (Command::Set { key, val }, Some(txn)) => {
+                let res = txn.set(key, val);
                 let res = res.await;
                 match res {
-                    Ok(_) => Response::success("".to_owned()),
+                    Ok(_) => Response::success(Vec::new()),
                     Err(error) => Response::failure(error.to_string()),
                 }
             }
+
+            (Command::Remove { key }, Some(txn)) => {
+                let res = txn.remove(key);
+                let res = res.await;
+                match res {
+                    Ok(_) => Response::success(Vec::new()),
+                    Err(error) => Response::failure(error.to_string()),
+                }
+            }
+
+            (_, Some(_)) => Response::failure(
+                "Only get, set and remove can be part of a transaction".to_owned(),
+            ),
+
+            (msg, None) => serve_command(store.clone(), checkpoint_dir.as_deref(), msg).await,
         };
 
         serialized.send(response).await?;
@@ -138,3 +198,190 @@ async fn serve<T: KvsEngine>(store: T, mut socket: TcpStream) -> Result<()> {
 
     Ok(())
 }
+
+// answer a command sent outside of a transaction
+async fn serve_command<T: KvsEngine>(
+    store: T,
+    checkpoint_dir: Option<&PathBuf>,
+    msg: Command,
+) -> Response {
+    match msg {
+        Command::Get { key } => {
+            // we must create a temporary binding so that a reference
+            // to store will not be used across await point, since &T
+            // is not Sync
+            let res = store.get(key);
+            let res = res.await;
+            match res {
+                Ok(val) => encode(&val),
+                Err(error) => Response::failure(error.to_string()),
+            }
+        }
+
+        Command::Set { key, val } => {
+            let res = store.set(key, val);
+            let res = res.await;
+            match res {
+                Ok(_) => Response::success(Vec::new()),
+                Err(error) => Response::failure(error.to_string()),
+            }
+        }
+
+        Command::SetWithTtl { key, val, ttl } => {
+            let res = store.set_with_ttl(key, val, ttl);
+            let res = res.await;
+            match res {
+                Ok(_) => Response::success(Vec::new()),
+                Err(error) => Response::failure(error.to_string()),
+            }
+        }
+
+        Command::Remove { key } => {
+            let res = store.remove(key);
+            let res = res.await;
+            match res {
+                Ok(_) => Response::success(Vec::new()),
+                Err(error) => Response::failure(error.to_string()),
+            }
+        }
+
+        Command::CompareAndSwap { key, expected, new } => {
+            let res = store.compare_and_swap(key, expected, new);
+            let res = res.await;
+            match res {
+                Ok(swapped) => encode(&swapped),
+                Err(error) => Response::failure(error.to_string()),
+            }
+        }
+
+        Command::IncrBy { key, delta } => {
+            let res = store.incr_by(key, delta);
+            let res = res.await;
+            match res {
+                Ok(sum) => Response::success(sum.to_string()),
+                Err(error) => Response::failure(error.to_string()),
+            }
+        }
+
+        Command::Append { key, suffix } => {
+            let res = store.append(key, suffix);
+            let res = res.await;
+            match res {
+                Ok(_) => Response::success(Vec::new()),
+                Err(error) => Response::failure(error.to_string()),
+            }
+        }
+
+        Command::WriteBatch { batch } => {
+            let res = store.write_batch(batch);
+            let res = res.await;
+            match res {
+                Ok(_) => Response::success(Vec::new()),
+                Err(error) => Response::failure(error.to_string()),
+            }
+        }
+
+        Command::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            order,
+            cursor,
+        } => {
+            // an empty page could not tell whether there is a next one
+            if limit == 0 {
+                return Response::failure("Scan limit must be at least 1".to_owned());
+            }
+            let mut range = match prefix {
+                Some(prefix) => prefix_range(prefix),
+                None => (
+                    start.map_or(Bound::Unbounded, Bound::Included),
+                    end.map_or(Bound::Unbounded, Bound::Excluded),
+                ),
+            };
+            // resume right after the last key of the previous page
+            match (cursor, order) {
+                (Some(cursor), ScanOrder::Forward) => range.0 = Bound::Excluded(cursor),
+                (Some(cursor), ScanOrder::Reverse) => range.1 = Bound::Excluded(cursor),
+                (None, _) => {}
+            }
+
+            // ask for one more pair to know whether there is a next page
+            let res = store.scan(range, limit.saturating_add(1), order);
+            let res = res.await;
+            match res {
+                Ok(mut entries) => {
+                    let cursor = if entries.len() > limit {
+                        entries.truncate(limit);
+                        entries.last().map(|(key, _)| key.clone())
+                    } else {
+                        None
+                    };
+                    encode(&ScanPage { entries, cursor })
+                }
+                Err(error) => Response::failure(error.to_string()),
+            }
+        }
+
+        Command::Checkpoint { dest } => {
+            let dest = match checkpoint_path(checkpoint_dir, &dest) {
+                Ok(dest) => dest,
+                Err(message) => return Response::failure(message),
+            };
+            let res = store.checkpoint(dest);
+            let res = res.await;
+            match res {
+                Ok(_) => Response::success(Vec::new()),
+                Err(error) => Response::failure(error.to_string()),
+            }
+        }
+
+        Command::Stats => {
+            let res = store.stats();
+            let res = res.await;
+            match res {
+                Ok(stats) => encode(&stats),
+                Err(error) => Response::failure(error.to_string()),
+            }
+        }
+
+        Command::Begin | Command::Commit | Command::Rollback => {
+            unreachable!("transaction commands are served by serve")
+        }
+    }
+}
+
+// the directory a checkpoint into dest is written to, which must be
+// a relative path that stays under the checkpoint directory
+fn checkpoint_path(
+    checkpoint_dir: Option<&PathBuf>,
+    dest: &Path,
+) -> std::result::Result<PathBuf, &'static str> {
+    let checkpoint_dir = match checkpoint_dir {
+        Some(checkpoint_dir) => checkpoint_dir,
+        None => return Err("Checkpoints are disabled on this server"),
+    };
+    let mut names = 0;
+    for component in dest.components() {
+        match component {
+            Component::Normal(_) => names += 1,
+            Component::CurDir => {}
+            Component::Prefix(_) | Component::RootDir | Component::ParentDir => {
+                return Err("Checkpoint destination must stay inside the checkpoint directory")
+            }
+        }
+    }
+    if names == 0 {
+        return Err("Checkpoint destination must name a directory");
+    }
+    Ok(checkpoint_dir.join(dest))
+}
+
+// a success response carrying value serialized as bincode
+fn encode<V: Serialize>(value: &V) -> Response {
+    match bincode::serialize(value) {
+        Ok(message) => Response::success(message),
+        Err(error) => Response::failure(error.to_string()),
+    }
+}
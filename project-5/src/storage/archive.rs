@@ -0,0 +1,259 @@
+//! Continuous archiving of the records a store writes, and
+//! point-in-time restores from the archive.
+//!
+//! With an archive directory set, the writer copies every record it
+//! appends into `<archive>/<gen>.log` as well. The archive so ends up
+//! with each logfile as the writer wrote it, which compaction never
+//! rewrites or removes. The logfiles already in the store when it opens
+//! are archived as they are. Those the archive has already only get the
+//! records it is missing, which a crash between writing a record to the
+//! store and archiving it leaves out.
+//!
+//! The `timeline` file tells when the records were written. Each of its
+//! entries is the sequence number of the last record written at the
+//! time, and that time in milliseconds since the unix epoch, both as
+//! little-endian u64s. An entry is added whenever the writer hands
+//! records to the OS, and when a store opens, the first entry marking
+//! where the archive starts.
+//!
+//! Replaying the archived logfiles in order, skipping the records
+//! after a given sequence number, rebuilds the data as it was right
+//! after that record. An archived compaction output holds the latest
+//! records of the generations below it, which are archived as well,
+//! so replaying it after them changes nothing at any point in time.
+
+use super::checkpoint::copy_synced;
+use super::kv_util::{log_path, sorted_gen_list, sync_dir};
+use super::record::{self, RawRecord};
+use crate::Result;
+use byteorder::{ByteOrder, LittleEndian};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+const TIMELINE: &str = "timeline";
+const TIMELINE_ENTRY_LEN: usize = 16;
+
+/// How far [KvStore::restore_archive](crate::KvStore::restore_archive)
+/// replays an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// replay every archived record
+    Latest,
+    /// replay the records up to and including
+    /// the one with this sequence number
+    Seq(u64),
+    /// replay the records written by this time
+    Time(SystemTime),
+}
+
+/// Copies the records the writer appends into the archive
+#[derive(Debug)]
+pub(super) struct Archiver {
+    dirpath: PathBuf,
+    // the archived copy of the logfile the writer appends to
+    log: BufWriter<File>,
+    // length of log, counting the records still buffered
+    len: u64,
+    timeline: File,
+}
+
+impl Archiver {
+    /// start archiving into dirpath the store at store_dir, whose
+    /// writer appends to cur_gen next and has written up to last_seq
+    pub(super) fn open(
+        dirpath: &Path,
+        store_dir: &Path,
+        cur_gen: u64,
+        last_seq: u64,
+    ) -> Result<Self> {
+        fs::create_dir_all(dirpath)?;
+        for gen in sorted_gen_list(store_dir)? {
+            if gen < cur_gen {
+                archive_logfile(&log_path(store_dir, gen), &log_path(dirpath, gen))?;
+            }
+        }
+
+        let log = open_append(&log_path(dirpath, cur_gen))?;
+        let mut archiver = Self {
+            dirpath: dirpath.to_path_buf(),
+            len: log.metadata()?.len(),
+            log: BufWriter::new(log),
+            timeline: open_append(&dirpath.join(TIMELINE))?,
+        };
+        archiver.mark(last_seq)?;
+        archiver.sync()?;
+        sync_dir(dirpath)?;
+        Ok(archiver)
+    }
+
+    /// archive a record the writer appended
+    pub(super) fn append(&mut self, buf: &[u8]) -> Result<()> {
+        self.log.write_all(buf)?;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// length of the archived logfile the writer appends to,
+    /// counting the records not flushed yet
+    pub(super) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// drop the records archived after len, flushed or not
+    pub(super) fn truncate(&mut self, len: u64) -> Result<()> {
+        let file = self.log.get_ref().try_clone()?;
+        let discarded = std::mem::replace(&mut self.log, BufWriter::new(file));
+        // unlike dropping it, taking the writer apart doesn't flush it
+        let _ = discarded.into_parts();
+        self.log.get_ref().set_len(len)?;
+        self.len = len;
+        Ok(())
+    }
+
+    /// hand the archived records to the OS, noting that
+    /// the last of them has sequence number seq
+    pub(super) fn flush(&mut self, seq: u64) -> Result<()> {
+        self.log.flush()?;
+        self.mark(seq)
+    }
+
+    /// fsync the archived records and the timeline
+    pub(super) fn sync(&mut self) -> Result<()> {
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        self.timeline.sync_data()?;
+        Ok(())
+    }
+
+    /// move on to archiving the logfile of gen, after
+    /// syncing what was archived of the previous one
+    pub(super) fn switch(&mut self, gen: u64) -> Result<()> {
+        self.sync()?;
+        let log = open_append(&log_path(&self.dirpath, gen))?;
+        self.len = log.metadata()?.len();
+        self.log = BufWriter::new(log);
+        sync_dir(&self.dirpath)
+    }
+
+    // add an entry for seq at the current time to the timeline
+    fn mark(&mut self, seq: u64) -> Result<()> {
+        let mut entry = [0u8; TIMELINE_ENTRY_LEN];
+        LittleEndian::write_u64(&mut entry[0..8], seq);
+        LittleEndian::write_u64(&mut entry[8..16], millis_of(SystemTime::now()));
+        self.timeline.write_all(&entry)?;
+        Ok(())
+    }
+}
+
+/// write the records archived in archive_dir up to point into the
+/// logfile of the first generation of the empty store at dirpath.
+/// Fail if the archive starts after point
+pub(super) fn replay_archive(
+    archive_dir: &Path,
+    dirpath: &Path,
+    point: RestorePoint,
+) -> Result<()> {
+    let timeline = read_timeline(archive_dir)?;
+    let starts_at = timeline.first().copied().unwrap_or((0, 0));
+    let last_seq = match point {
+        RestorePoint::Latest => Some(u64::MAX),
+        RestorePoint::Seq(seq) => Some(seq).filter(|&seq| seq >= starts_at.0),
+        RestorePoint::Time(time) => timeline
+            .iter()
+            .filter(|&&(_, at)| at <= millis_of(time))
+            .map(|&(seq, _)| seq)
+            .max(),
+    };
+    let last_seq = match last_seq {
+        Some(last_seq) => last_seq,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "archive {} starts after the restore point",
+                    archive_dir.display()
+                ),
+            )
+            .into())
+        }
+    };
+
+    let mut restored = BufWriter::new(File::create(log_path(dirpath, 1))?);
+    for gen in sorted_gen_list(archive_dir)? {
+        let mut reader = BufReader::new(File::open(log_path(archive_dir, gen))?);
+        loop {
+            let buf = match record::read_record(&mut reader)? {
+                RawRecord::Complete(buf) => buf,
+                RawRecord::Incomplete | RawRecord::Eof => break,
+            };
+            match record::frame_seq(&buf) {
+                Ok(seq) if seq <= last_seq => restored.write_all(&buf)?,
+                Ok(_) => {}
+                // like recovery, leave out everything after a damaged record
+                Err(err) => {
+                    warn!("{}.log: archive damaged, skipping the rest: {}", gen, err);
+                    break;
+                }
+            }
+        }
+    }
+    restored.flush()?;
+    restored.get_ref().sync_all()?;
+    sync_dir(dirpath)
+}
+
+// the (seq, millis) entries of the timeline of the archive at
+// dirpath, leaving out an entry torn by a crash at the end
+fn read_timeline(dirpath: &Path) -> Result<Vec<(u64, u64)>> {
+    let mut buf = Vec::new();
+    match File::open(dirpath.join(TIMELINE)) {
+        Ok(mut file) => file.read_to_end(&mut buf)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(buf
+        .chunks_exact(TIMELINE_ENTRY_LEN)
+        .map(|entry| {
+            (
+                LittleEndian::read_u64(&entry[0..8]),
+                LittleEndian::read_u64(&entry[8..16]),
+            )
+        })
+        .collect())
+}
+
+// bring the archived copy of a finished logfile up to date with
+// it, archiving all of it if there is no copy yet
+fn archive_logfile(logfile: &Path, archived: &Path) -> Result<()> {
+    let archived_len = match fs::metadata(archived) {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            // a copy, never a link, as the archived
+            // copy is appended to on its own
+            return copy_synced(logfile, archived);
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut logfile = File::open(logfile)?;
+    if logfile.metadata()?.len() <= archived_len {
+        return Ok(());
+    }
+    logfile.seek(SeekFrom::Start(archived_len))?;
+    let mut archived = open_append(archived)?;
+    io::copy(&mut logfile, &mut archived)?;
+    archived.sync_data()?;
+    Ok(())
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+fn millis_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
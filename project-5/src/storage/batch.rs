@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+
+/// A single operation of a [WriteBatch]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// set the value of key
+    Set {
+        /// the key
+        key: Vec<u8>,
+        /// the value
+        val: Vec<u8>,
+    },
+    /// remove the value of key
+    Remove {
+        /// the key
+        key: Vec<u8>,
+    },
+}
+
+/// A group of sets and removes applied atomically by
+/// [write_batch](super::KvsEngine::write_batch): after a crash
+/// either all of them are visible or none is.
+///
+/// Ops are applied in the order they were added. If one of the
+/// removes targets a key that does not exist at that point, the
+/// whole batch fails with [KeyNotFound](crate::KVErrorKind::KeyNotFound)
+/// and nothing is written.
+///
+/// # Examples
+/// ```rust
+/// use kvs_project_5::{
+///     thread_pool::SharedQueueThreadPool,
+///     KvStore,
+///     KvsEngine,
+///     WriteBatch,
+/// };
+/// use tempfile::TempDir;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let temp_dir = TempDir::new().unwrap();
+///     let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 1).unwrap();
+///
+///     let mut batch = WriteBatch::new();
+///     batch.set(b"key1".to_vec(), b"value1".to_vec());
+///     batch.set(b"key2".to_vec(), b"value2".to_vec());
+///     batch.remove(b"key1".to_vec());
+///     store.write_batch(batch).await.unwrap();
+///
+///     assert_eq!(None, store.get(b"key1".to_vec()).await.unwrap());
+///     assert_eq!(Some(b"value2".to_vec()), store.get(b"key2".to_vec()).await.unwrap());
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// create an empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add a set of key to val
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, val: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            val: val.into(),
+        });
+        self
+    }
+
+    /// add a remove of key
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+        self
+    }
+
+    /// number of ops in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// whether the batch holds no op
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// the ops of the batch, in order
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// take the ops out of the batch, in order
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
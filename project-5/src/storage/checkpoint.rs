@@ -0,0 +1,87 @@
+//! Online backups of a store directory.
+//!
+//! A checkpoint is a store directory of its own: the logfiles of the
+//! sealed generations with their hint files, and the part of the active
+//! logfile that was written when the checkpoint was taken. All of them
+//! are copies: a hard link would share the file with the store, which
+//! truncates its logfiles when recovering from a crash.
+//! Opening it gives the data as it was at that point.
+
+use super::kv_util::{hint_path, log_path, sorted_gen_list, sync_dir};
+use crate::Result;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+/// create dirpath for a checkpoint or a restore,
+/// failing if it exists and is not empty
+pub(super) fn create_empty_dir(dirpath: &Path) -> Result<()> {
+    fs::create_dir_all(dirpath)?;
+    if fs::read_dir(dirpath)?.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("directory {} is not empty", dirpath.display()),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// copy the store at dirpath to dest: the generations before
+/// active_gen with their hint files, and the first active_len bytes of
+/// active_gen. Generations above active_gen were created since and are
+/// left out. The caller keeps compactions from removing any of them
+pub(super) fn write_checkpoint(
+    dirpath: &Path,
+    dest: &Path,
+    active_gen: u64,
+    active_len: u64,
+) -> Result<()> {
+    create_empty_dir(dest)?;
+    for gen in sorted_gen_list(dirpath)? {
+        if gen < active_gen {
+            copy_synced(&log_path(dirpath, gen), &log_path(dest, gen))?;
+            let hint = hint_path(dirpath, gen);
+            if hint.exists() {
+                copy_synced(&hint, &hint_path(dest, gen))?;
+            }
+        } else if gen == active_gen {
+            let mut active = File::open(log_path(dirpath, gen))?.take(active_len);
+            let mut copy = File::create(log_path(dest, gen))?;
+            io::copy(&mut active, &mut copy)?;
+            copy.sync_all()?;
+        }
+    }
+    sync_dir(dest)
+}
+
+/// replace the logfiles and hint files in dirpath with copies of
+/// those of checkpoint. The caller holds dirpath exclusively
+pub(super) fn restore_checkpoint(checkpoint: &Path, dirpath: &Path) -> Result<()> {
+    for entry in fs::read_dir(dirpath)? {
+        let path = entry?.path();
+        let is_store_file = ["log", "hint", "retired"]
+            .iter()
+            .any(|ext| path.extension() == Some(ext.as_ref()));
+        if path.is_file() && is_store_file {
+            fs::remove_file(path)?;
+        }
+    }
+    // the checkpoint is copied, not linked, to keep
+    // the restored store from ever touching it
+    for gen in sorted_gen_list(checkpoint)? {
+        copy_synced(&log_path(checkpoint, gen), &log_path(dirpath, gen))?;
+        let hint = hint_path(checkpoint, gen);
+        if hint.exists() {
+            copy_synced(&hint, &hint_path(dirpath, gen))?;
+        }
+    }
+    sync_dir(dirpath)
+}
+
+/// copy from to to, syncing the copy to disk
+pub(super) fn copy_synced(from: &Path, to: &Path) -> Result<()> {
+    fs::copy(from, to)?;
+    File::open(to)?.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,312 @@
+use super::hint::{remove_hint_file, write_hint_file, HintEntry};
+use super::index::Index;
+use super::kv_util::*;
+use super::kvstore::{CommandPos, PositionedBufReader, PositionedBufWriter};
+use super::log_readers::LogReaders;
+use super::record;
+use super::snapshot::Pins;
+use super::value_cache::ValueCache;
+use crate::Result;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::SystemTime;
+use tracing::{error, info, warn};
+
+/// Progress of the background compaction, shared between
+/// the writer that starts it and the task that runs it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CompactionState {
+    /// no compaction is scheduled
+    Idle,
+    /// a compaction task is queued on the thread pool
+    Pending,
+    /// a compaction task is copying entries
+    Running,
+    /// the store was dropped before the queued task started
+    Cancelled,
+}
+
+/// Handle used to coordinate with a background compaction
+#[derive(Debug, Clone)]
+pub(super) struct CompactionHandle {
+    state: Arc<(Mutex<Coordination>, Condvar)>,
+}
+
+#[derive(Debug)]
+struct Coordination {
+    state: CompactionState,
+    // number of CompactionPauses alive
+    pauses: usize,
+    // when the last successful compaction finished
+    last_compaction: Option<SystemTime>,
+}
+
+/// Keeps compactions from starting until it is dropped
+#[derive(Debug)]
+pub(super) struct CompactionPause {
+    handle: CompactionHandle,
+}
+
+impl CompactionHandle {
+    pub(super) fn new() -> Self {
+        let coordination = Coordination {
+            state: CompactionState::Idle,
+            pauses: 0,
+            last_compaction: None,
+        };
+        Self {
+            state: Arc::new((Mutex::new(coordination), Condvar::new())),
+        }
+    }
+
+    /// move from Idle to Pending, return false if
+    /// another compaction is already on its way
+    pub(super) fn try_schedule(&self) -> bool {
+        let mut coordination = self.state.0.lock().unwrap();
+        if coordination.state == CompactionState::Idle {
+            coordination.state = CompactionState::Pending;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// cancel a compaction that has not started yet,
+    /// or wait for a running one to finish
+    pub(super) fn cancel_or_wait(&self) {
+        let (lock, cvar) = &*self.state;
+        let mut coordination = lock.lock().unwrap();
+        if coordination.state == CompactionState::Pending {
+            coordination.state = CompactionState::Cancelled;
+        }
+        while coordination.state == CompactionState::Running {
+            coordination = cvar.wait(coordination).unwrap();
+        }
+    }
+
+    /// wait for a running compaction to finish, and keep the next
+    /// one from starting until the returned pause is dropped. No
+    /// logfile is removed in the meantime
+    pub(super) fn pause(&self) -> CompactionPause {
+        let (lock, cvar) = &*self.state;
+        let mut coordination = lock.lock().unwrap();
+        coordination.pauses += 1;
+        while coordination.state == CompactionState::Running {
+            coordination = cvar.wait(coordination).unwrap();
+        }
+        CompactionPause {
+            handle: self.clone(),
+        }
+    }
+
+    /// when the last successful compaction finished,
+    /// None if none has since the store was opened
+    pub(super) fn last_compaction(&self) -> Option<SystemTime> {
+        self.state.0.lock().unwrap().last_compaction
+    }
+
+    // move from Pending to Running once no pause is in the way,
+    // return false if the compaction has been cancelled meanwhile
+    fn start(&self) -> bool {
+        let (lock, cvar) = &*self.state;
+        let mut coordination = lock.lock().unwrap();
+        while coordination.pauses > 0 && coordination.state == CompactionState::Pending {
+            coordination = cvar.wait(coordination).unwrap();
+        }
+        let started = coordination.state == CompactionState::Pending;
+        coordination.state = if started {
+            CompactionState::Running
+        } else {
+            CompactionState::Idle
+        };
+        started
+    }
+
+    fn finish(&self, compacted: bool) {
+        let (lock, cvar) = &*self.state;
+        let mut coordination = lock.lock().unwrap();
+        coordination.state = CompactionState::Idle;
+        if compacted {
+            coordination.last_compaction = Some(SystemTime::now());
+        }
+        cvar.notify_all();
+    }
+}
+
+impl Drop for CompactionPause {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.handle.state;
+        lock.lock().unwrap().pauses -= 1;
+        cvar.notify_all();
+    }
+}
+
+/// A compaction job that rewrites the live entries of all
+/// generations below `compaction_gen` into `compaction_gen`.
+///
+/// The writer has already moved on to a generation above
+/// `compaction_gen` when the job is created, so the frozen
+/// generations are never appended to while they are copied.
+#[derive(Debug)]
+pub(super) struct Compaction {
+    pub(super) dirpath: Arc<PathBuf>,
+    pub(super) compaction_gen: u64,
+    pub(super) stale_gen: Arc<AtomicU64>,
+    pub(super) database: Arc<Index>,
+    pub(super) pins: Arc<Pins>,
+    pub(super) readers: Arc<LogReaders>,
+    pub(super) cache: Arc<ValueCache>,
+    pub(super) handle: CompactionHandle,
+}
+
+impl Compaction {
+    /// run the compaction, meant to be spawned on the thread pool
+    pub(super) fn run(self) {
+        if !self.handle.start() {
+            info!("Compaction into gen {} cancelled", self.compaction_gen);
+            return;
+        }
+
+        let res = self.compact();
+        if let Err(err) = &res {
+            error!(
+                "Compaction into gen {} failed: {}",
+                self.compaction_gen, err
+            );
+            // the frozen generations are still intact, only
+            // the partial output has to go
+            let _ = remove_hint_file(&self.dirpath, self.compaction_gen);
+            let _ = fs::remove_file(tmp_log_path(&self.dirpath, self.compaction_gen));
+            let _ = fs::remove_file(log_path(&self.dirpath, self.compaction_gen));
+        }
+
+        self.handle.finish(res.is_ok());
+    }
+
+    fn compact(&self) -> Result<()> {
+        let compaction_gen = self.compaction_gen;
+
+        // collect the entries living in frozen generations. Entries
+        // changed after we pass them are caught by the swap below
+        let frozen: Vec<(Vec<u8>, CommandPos)> = self
+            .database
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.gen < compaction_gen)
+            .collect();
+
+        let now = now_millis();
+        // the output only gets the name of a logfile once it is complete,
+        // so that a compaction cut short never leaves a sealed logfile
+        // ending in a torn record
+        let tmp_path = tmp_log_path(&self.dirpath, compaction_gen);
+        let mut compaction_writer = PositionedBufWriter::new(File::create(&tmp_path)?)?;
+        let mut readers_cache = BTreeMap::new();
+        let mut expired = Vec::new();
+        let mut moved = Vec::with_capacity(frozen.len());
+        let mut hints = Vec::with_capacity(frozen.len());
+        let mut last_seq = 0;
+        let mut new_pos: u64 = 0;
+
+        for (key, cmd_pos) in frozen {
+            // expired entries are left behind with the frozen logfiles
+            if cmd_pos.is_expired(now) {
+                expired.push((key, cmd_pos));
+                continue;
+            }
+
+            let reader = readers_cache
+                .entry(cmd_pos.gen)
+                .or_insert(PositionedBufReader::new(File::open(log_path(
+                    &self.dirpath,
+                    cmd_pos.gen,
+                ))?)?);
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+
+            // verify each record before copying it, so that compaction
+            // never carries a corrupted entry into the new logfile
+            let mut buf = vec![0; cmd_pos.len as usize];
+            reader.read_exact(&mut buf)?;
+            last_seq = last_seq.max(record::decode(&buf)?.seq);
+            compaction_writer.write_all(&buf)?;
+
+            let new_cmd: CommandPos =
+                (compaction_gen, new_pos, cmd_pos.len, cmd_pos.expire_at).into();
+            hints.push(HintEntry {
+                is_set: true,
+                key: key.clone(),
+                pos: new_pos,
+                len: cmd_pos.len,
+                expire_at: cmd_pos.expire_at,
+            });
+            moved.push((key, cmd_pos, new_cmd));
+            new_pos += cmd_pos.len;
+        }
+        // the frozen logfiles are removed below, so the new one must
+        // be on disk, under its name, before anything points at it
+        compaction_writer.sync()?;
+        fs::rename(&tmp_path, log_path(&self.dirpath, compaction_gen))?;
+        if let Err(err) = write_hint_file(&self.dirpath, compaction_gen, new_pos, last_seq, &hints)
+        {
+            warn!(
+                "Failed to write hint file for gen {}: {}",
+                compaction_gen, err
+            );
+        }
+        sync_dir(&self.dirpath)?;
+
+        // swap in the new positions. Entries overwritten or removed
+        // while we were copying no longer point at the old position
+        // and are left alone
+        let mut db = self.database.lock();
+        for (key, old_cmd, new_cmd) in moved {
+            if db.get(&key) == Some(old_cmd) {
+                self.cache.moved(&key, old_cmd, new_cmd);
+                db.insert(key, new_cmd);
+            }
+        }
+        for (key, old_cmd) in expired {
+            if db.get(&key) == Some(old_cmd) {
+                db.remove(&key);
+                self.cache.invalidate(&key);
+            }
+        }
+        drop(db);
+
+        // now all the entries in db has been updated, we can update the stale gen
+        // to let readers cleanup
+        self.stale_gen.store(compaction_gen - 1, Ordering::SeqCst);
+        // and unmap the frozen logfiles before they are removed
+        self.readers.close_stale();
+
+        // delete frozen log files, up to this point
+        // these logfiles are replicated and can be safely deleted
+        // without risking losing data. From here on the compacted
+        // logfile is live, so failures must not remove it
+        drop(readers_cache);
+        if let Err(err) = self.remove_frozen() {
+            warn!(
+                "Failed to remove logfiles compacted into gen {}: {}",
+                compaction_gen, err
+            );
+        }
+
+        info!("Compacted {} bytes into gen {}", new_pos, compaction_gen);
+        Ok(())
+    }
+
+    // delete the logfiles below compaction_gen, or retire
+    // those still referenced by an open snapshot
+    fn remove_frozen(&self) -> Result<()> {
+        for gen in sorted_gen_list(&self.dirpath)? {
+            if gen < self.compaction_gen {
+                remove_hint_file(&self.dirpath, gen)?;
+                self.pins.remove_or_retire(&self.dirpath, gen)?;
+            }
+        }
+        Ok(())
+    }
+}
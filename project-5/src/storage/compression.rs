@@ -0,0 +1,98 @@
+//! Compression of the values stored in log records.
+//!
+//! Each record carries the codec its value was written with, so
+//! logfiles freely mix compressed and uncompressed records, and
+//! changing the codec of a store only affects new writes.
+
+use super::options::Compression;
+use crate::{KVErrorKind, Result};
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// codec byte of a value stored as is
+pub(super) const CODEC_NONE: u8 = 0;
+/// codec byte of an LZ4 compressed value
+pub(super) const CODEC_LZ4: u8 = 1;
+/// codec byte of a zstd compressed value
+pub(super) const CODEC_ZSTD: u8 = 2;
+
+// zstd's own default, a good balance of speed and ratio
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compresses the values written to a store, counting
+/// the bytes before and after compression
+#[derive(Debug)]
+pub(super) struct Compressor {
+    compression: Compression,
+    // values shorter than this are stored as is
+    threshold: usize,
+    value_bytes: AtomicU64,
+    stored_value_bytes: AtomicU64,
+}
+
+impl Compressor {
+    pub(super) fn new(compression: Compression, threshold: usize) -> Self {
+        Self {
+            compression,
+            threshold,
+            value_bytes: AtomicU64::new(0),
+            stored_value_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// compress val if it is big enough and compression shrinks it.
+    /// Return the codec byte to record along with the stored bytes
+    pub(super) fn compress<'a>(&self, val: &'a [u8]) -> (u8, Cow<'a, [u8]>) {
+        let (codec, stored) = if val.len() < self.threshold {
+            (CODEC_NONE, Cow::Borrowed(val))
+        } else {
+            let compressed = match self.compression {
+                Compression::None => None,
+                Compression::Lz4 => Some((CODEC_LZ4, lz4_flex::compress_prepend_size(val))),
+                // compressing an in-memory buffer only fails on allocation
+                Compression::Zstd => zstd::bulk::compress(val, ZSTD_LEVEL)
+                    .ok()
+                    .map(|compressed| (CODEC_ZSTD, compressed)),
+            };
+            match compressed {
+                Some((codec, compressed)) if compressed.len() < val.len() => {
+                    (codec, Cow::Owned(compressed))
+                }
+                _ => (CODEC_NONE, Cow::Borrowed(val)),
+            }
+        };
+
+        self.value_bytes
+            .fetch_add(val.len() as u64, Ordering::Relaxed);
+        self.stored_value_bytes
+            .fetch_add(stored.len() as u64, Ordering::Relaxed);
+        (codec, stored)
+    }
+
+    /// bytes of the values compressed so far, before
+    /// and after compression
+    pub(super) fn counts(&self) -> (u64, u64) {
+        (
+            self.value_bytes.load(Ordering::Relaxed),
+            self.stored_value_bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// restore a value stored with codec
+pub(super) fn decompress(codec: u8, stored: &[u8]) -> Result<Vec<u8>> {
+    let val = match codec {
+        CODEC_NONE => Some(stored.to_vec()),
+        CODEC_LZ4 => lz4_flex::decompress_size_prepended(stored).ok(),
+        CODEC_ZSTD => zstd::stream::decode_all(stored).ok(),
+        _ => None,
+    };
+    match val {
+        Some(val) => Ok(val),
+        None => Err(
+            failure::err_msg(format!("value of codec {} fails to decompress", codec))
+                .context(KVErrorKind::Corruption)
+                .into(),
+        ),
+    }
+}
@@ -0,0 +1,72 @@
+//! Advisory locks that keep processes from stepping on each other's
+//! store directory.
+//!
+//! Every open store holds a shared lock on `<dir>/OPEN`, and a store
+//! that writes also holds an exclusive lock on `<dir>/LOCK`. So there
+//! is at most one writer at a time, any number of read-only stores may
+//! run beside it, and whoever needs the directory all to itself locks
+//! `OPEN` exclusively. The locks are released when the process exits,
+//! however it exits, so a crash never leaves a directory locked.
+
+use crate::{KVErrorKind, Result};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+
+// locked exclusively by the writer
+const WRITER_LOCK: &str = "LOCK";
+// locked shared by every open store
+const OPEN_LOCK: &str = "OPEN";
+
+/// The locks a store holds on its directory, until it is dropped
+#[derive(Debug)]
+pub(super) struct DirLock {
+    _open: File,
+    _writer: Option<File>,
+}
+
+impl DirLock {
+    /// lock dirpath for a store, which writes unless read_only.
+    /// Fail with [KVErrorKind::DirectoryLocked] if another
+    /// process holds a conflicting lock
+    pub(super) fn acquire(dirpath: &Path, read_only: bool) -> Result<Self> {
+        let writer = if read_only {
+            None
+        } else {
+            Some(try_lock(dirpath, WRITER_LOCK, true)?)
+        };
+        Ok(Self {
+            _open: try_lock(dirpath, OPEN_LOCK, false)?,
+            _writer: writer,
+        })
+    }
+
+    /// lock dirpath for a process that needs it all to itself, like a
+    /// restore replacing its files. Fail with
+    /// [KVErrorKind::DirectoryLocked] if any store has it open
+    pub(super) fn exclusive(dirpath: &Path) -> Result<Self> {
+        Ok(Self {
+            _writer: Some(try_lock(dirpath, WRITER_LOCK, true)?),
+            _open: try_lock(dirpath, OPEN_LOCK, true)?,
+        })
+    }
+}
+
+// lock the file name in dirpath, creating it if needed
+fn try_lock(dirpath: &Path, name: &str, exclusive: bool) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dirpath.join(name))?;
+    let res = if exclusive {
+        file.try_lock()
+    } else {
+        file.try_lock_shared()
+    };
+    match res {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(KVErrorKind::DirectoryLocked.into()),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
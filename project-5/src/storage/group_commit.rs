@@ -0,0 +1,44 @@
+use super::kvstore::Ops;
+use crate::Result;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+/// A set or remove waiting to be written
+#[derive(Debug)]
+pub(super) struct PendingWrite {
+    pub(super) op: Ops,
+    pub(super) expire_at: u64,
+    pub(super) done: oneshot::Sender<Result<()>>,
+}
+
+/// Queue of the sets and removes waiting for the writer.
+///
+/// A thread that wants to write pushes its write, then takes the
+/// writer lock. Whoever gets the lock becomes the leader: it takes
+/// every write queued so far, its own and those of the threads blocked
+/// behind it, writes them with a single flush and fsync, and hands
+/// each write its result. Since a write is queued before its thread
+/// goes for the lock, the thread finds the result waiting once it
+/// gets the lock, if an earlier leader took the write along.
+#[derive(Debug, Default)]
+pub(super) struct CommitQueue {
+    pending: Mutex<Vec<PendingWrite>>,
+}
+
+impl CommitQueue {
+    /// queue op, returning where its result will arrive
+    pub(super) fn push(&self, op: Ops, expire_at: u64) -> oneshot::Receiver<Result<()>> {
+        let (done, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().push(PendingWrite {
+            op,
+            expire_at,
+            done,
+        });
+        receiver
+    }
+
+    /// take all the queued writes, in the order they were queued
+    pub(super) fn take(&self) -> Vec<PendingWrite> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
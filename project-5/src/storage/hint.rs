@@ -0,0 +1,170 @@
+//! Hint files let [KvStore](crate::KvStore) rebuild its index without
+//! reading every record. `<gen>.hint` lists the position of each record in
+//! `<gen>.log`, in the order the records were written:
+//!
+//! ```text
+//! +-------+---------+-----+---------+----------+-------+---------+
+//! | crc32 | version | gen | log_len | last_seq | count | entries |
+//! +-------+---------+-----+---------+----------+-------+---------+
+//!     4        1       8       8          8        8
+//!
+//! entry:
+//! +----+-----+-----+-----------+---------+-----+
+//! | op | pos | len | expire_at | key_len | key |
+//! +----+-----+-----+-----------+---------+-----+
+//!    1    8     8        8          4
+//! ```
+//!
+//! The crc32 covers every byte after itself. A hint file is only trusted
+//! when its checksum holds and `log_len` matches the logfile on disk.
+
+use super::kv_util::{apply_entry, hint_path, LoadSummary};
+use super::kvstore::CommandPos;
+use crate::{KVErrorKind, Result};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+
+const HINT_VERSION: u8 = 2;
+const HEADER_LEN: usize = 37;
+
+const OP_SET: u8 = 1;
+const OP_RM: u8 = 2;
+
+/// Position of one record in a logfile
+#[derive(Debug, Clone)]
+pub(super) struct HintEntry {
+    pub(super) is_set: bool,
+    pub(super) key: Vec<u8>,
+    pub(super) pos: u64,
+    pub(super) len: u64,
+    pub(super) expire_at: u64,
+}
+
+/// write the hint file of gen, replacing any existing one.
+/// The file is written aside and renamed into place, so a
+/// crash never leaves a partial hint file behind
+pub(super) fn write_hint_file(
+    dirpath: &Path,
+    gen: u64,
+    log_len: u64,
+    last_seq: u64,
+    entries: &[HintEntry],
+) -> Result<()> {
+    let mut buf = vec![0u8; 4];
+    buf.push(HINT_VERSION);
+    buf.write_u64::<LittleEndian>(gen)?;
+    buf.write_u64::<LittleEndian>(log_len)?;
+    buf.write_u64::<LittleEndian>(last_seq)?;
+    buf.write_u64::<LittleEndian>(entries.len() as u64)?;
+    for entry in entries {
+        buf.push(if entry.is_set { OP_SET } else { OP_RM });
+        buf.write_u64::<LittleEndian>(entry.pos)?;
+        buf.write_u64::<LittleEndian>(entry.len)?;
+        buf.write_u64::<LittleEndian>(entry.expire_at)?;
+        buf.write_u32::<LittleEndian>(entry.key.len() as u32)?;
+        buf.extend_from_slice(&entry.key);
+    }
+    let crc = crc32fast::hash(&buf[4..]);
+    LittleEndian::write_u32(&mut buf[0..4], crc);
+
+    let path = hint_path(dirpath, gen);
+    let tmp_path = path.with_extension("hint.tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// remove the hint file of gen if there is one
+pub(super) fn remove_hint_file(dirpath: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(hint_path(dirpath, gen)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Rebuild the part of database stored in gen from its hint file.
+/// Return None, leaving database untouched, if there is no usable
+/// hint file and the logfile has to be replayed
+pub(super) fn load_from_hintfile(
+    dirpath: &Path,
+    gen: u64,
+    log_len: u64,
+    database: &mut BTreeMap<Vec<u8>, CommandPos>,
+) -> Result<Option<LoadSummary>> {
+    let mut buf = Vec::new();
+    match File::open(hint_path(dirpath, gen)) {
+        Ok(mut file) => file.read_to_end(&mut buf)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    if buf.len() < HEADER_LEN || LittleEndian::read_u32(&buf[0..4]) != crc32fast::hash(&buf[4..]) {
+        return Ok(None);
+    }
+
+    let mut cursor = Cursor::new(&buf[4..]);
+    let (entries, last_seq) = match parse_hints(&mut cursor, gen, log_len) {
+        Ok(Some(parsed)) => parsed,
+        _ => return Ok(None),
+    };
+
+    let mut summary = LoadSummary {
+        last_seq,
+        valid_len: log_len,
+        file_len: log_len,
+        ..LoadSummary::default()
+    };
+    for entry in entries {
+        let cmd_pos = (gen, entry.pos, entry.len, entry.expire_at).into();
+        apply_entry(database, &mut summary, entry.key, cmd_pos, entry.is_set);
+    }
+    Ok(Some(summary))
+}
+
+// parse the body of a hint file whose checksum holds, return None
+// if it does not describe the current logfile of gen
+fn parse_hints(
+    cursor: &mut Cursor<&[u8]>,
+    gen: u64,
+    log_len: u64,
+) -> Result<Option<(Vec<HintEntry>, u64)>> {
+    if cursor.read_u8()? != HINT_VERSION
+        || cursor.read_u64::<LittleEndian>()? != gen
+        || cursor.read_u64::<LittleEndian>()? != log_len
+    {
+        return Ok(None);
+    }
+    let last_seq = cursor.read_u64::<LittleEndian>()?;
+    let count = cursor.read_u64::<LittleEndian>()?;
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let is_set = match cursor.read_u8()? {
+            OP_SET => true,
+            OP_RM => false,
+            _ => return Err(KVErrorKind::Corruption.into()),
+        };
+        let pos = cursor.read_u64::<LittleEndian>()?;
+        let len = cursor.read_u64::<LittleEndian>()?;
+        let expire_at = cursor.read_u64::<LittleEndian>()?;
+        let key_len = cursor.read_u32::<LittleEndian>()? as u64;
+        let mut key = Vec::new();
+        cursor.by_ref().take(key_len).read_to_end(&mut key)?;
+        if key.len() as u64 != key_len {
+            return Err(KVErrorKind::Corruption.into());
+        }
+        entries.push(HintEntry {
+            is_set,
+            key,
+            pos,
+            len,
+            expire_at,
+        });
+    }
+    Ok(Some((entries, last_seq)))
+}
@@ -0,0 +1,101 @@
+use super::kvstore::CommandPos;
+use super::scan::KeyRange;
+use crossbeam::atomic::AtomicCell;
+use crossbeam_skiplist::SkipMap;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+
+/// The in-memory index, mapping each key to the position of its
+/// latest record.
+///
+/// Lookups and ordered iteration don't take the lock changes go
+/// through, so readers are not held up by writers or compaction. The
+/// skiplist itself is lock-free, but a [CommandPos] is too large for
+/// an atomic, so [AtomicCell] guards each one with a seqlock: a reader
+/// retries while a writer is storing that entry, which takes only as
+/// long as the store itself. An iteration sees each entry as
+/// it is when the iteration gets to it. Changes go through
+/// [lock](Index::lock), which the writer and compaction take in turn,
+/// so that checking an entry and then changing it is safe, and so
+/// that a copy taken under the lock is consistent.
+///
+/// A key that moves is updated in place: replacing its entry in the
+/// skiplist would leave a moment where readers can't find it.
+#[derive(Debug, Default)]
+pub(super) struct Index {
+    map: SkipMap<Vec<u8>, AtomicCell<CommandPos>>,
+    changes: Mutex<()>,
+}
+
+impl Index {
+    /// the position of key, if it is in the index
+    pub(super) fn get(&self, key: &[u8]) -> Option<CommandPos> {
+        self.map.get(key).map(|entry| entry.value().load())
+    }
+
+    /// the entries with keys in range, in key order
+    pub(super) fn range(
+        &self,
+        range: KeyRange,
+    ) -> impl DoubleEndedIterator<Item = (Vec<u8>, CommandPos)> + '_ {
+        self.map
+            .range(range)
+            .map(|entry| (entry.key().clone(), entry.value().load()))
+    }
+
+    /// all the entries, in key order
+    pub(super) fn iter(&self) -> impl Iterator<Item = (Vec<u8>, CommandPos)> + '_ {
+        self.map
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load()))
+    }
+
+    /// lock the index for changes, which doesn't stop readers
+    pub(super) fn lock(&self) -> IndexGuard<'_> {
+        IndexGuard {
+            map: &self.map,
+            _changes: self.changes.lock().unwrap(),
+        }
+    }
+}
+
+impl From<BTreeMap<Vec<u8>, CommandPos>> for Index {
+    fn from(database: BTreeMap<Vec<u8>, CommandPos>) -> Self {
+        Self {
+            map: database
+                .into_iter()
+                .map(|(key, cmd_pos)| (key, AtomicCell::new(cmd_pos)))
+                .collect(),
+            changes: Mutex::new(()),
+        }
+    }
+}
+
+/// The right to change an [Index]
+pub(super) struct IndexGuard<'a> {
+    map: &'a SkipMap<Vec<u8>, AtomicCell<CommandPos>>,
+    _changes: MutexGuard<'a, ()>,
+}
+
+impl IndexGuard<'_> {
+    /// the position of key, if it is in the index
+    pub(super) fn get(&self, key: &[u8]) -> Option<CommandPos> {
+        self.map.get(key).map(|entry| entry.value().load())
+    }
+
+    /// point key at cmd_pos, returning where it pointed before
+    pub(super) fn insert(&mut self, key: Vec<u8>, cmd_pos: CommandPos) -> Option<CommandPos> {
+        match self.map.get(&key) {
+            Some(entry) => Some(entry.value().swap(cmd_pos)),
+            None => {
+                self.map.insert(key, AtomicCell::new(cmd_pos));
+                None
+            }
+        }
+    }
+
+    /// take key out of the index, returning where it pointed
+    pub(super) fn remove(&mut self, key: &[u8]) -> Option<CommandPos> {
+        self.map.remove(key).map(|entry| entry.value().load())
+    }
+}
@@ -1,11 +1,15 @@
-use super::kvstore::{CommandPos, Ops, PositionedBufReader, PositionedBufWriter};
+use super::hint::remove_hint_file;
+use super::kvstore::{CommandPos, Ops, PositionedBufReader, PositionedBufWriter, RecoveryMode};
+use super::record::{self, RawRecord};
 use crate::Result;
-use serde_json::Deserializer;
+use failure::Fail;
 use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
 use std::io::{Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
 
 /// scan the given director, find "<num>.log" file
 /// and produce a sorted list of such gens
@@ -31,59 +35,171 @@ pub(super) fn log_path(dirpath: &Path, gen: u64) -> PathBuf {
     dirpath.join(format!("{}.log", gen))
 }
 
+/// util to create "{dirpath}/{gen}.log.tmp" as a PargBuf, where
+/// compaction writes a logfile before giving it its name
+pub(super) fn tmp_log_path(dirpath: &Path, gen: u64) -> PathBuf {
+    dirpath.join(format!("{}.log.tmp", gen))
+}
+
+/// util to create "{dirpath}/{gen}.log.retired" as a PargBuf
+pub(super) fn retired_path(dirpath: &Path, gen: u64) -> PathBuf {
+    dirpath.join(format!("{}.log.retired", gen))
+}
+
+/// remove the logfiles retired for snapshots that were still open
+/// when the store closed, and those of compactions it interrupted
+pub(super) fn remove_leftover_logs(dirpath: &Path) -> Result<()> {
+    for entry in fs::read_dir(dirpath)? {
+        let path = entry?.path();
+        let leftover = ["retired", "tmp"]
+            .iter()
+            .any(|ext| path.extension() == Some(ext.as_ref()));
+        if path.is_file() && leftover {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// util to create "{dirpath}/{gen}.hint" as a PargBuf
+pub(super) fn hint_path(dirpath: &Path, gen: u64) -> PathBuf {
+    dirpath.join(format!("{}.hint", gen))
+}
+
+/// milliseconds elapsed since the unix epoch, the unit
+/// expiration times are stored in
+pub(super) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Bookkeeping gathered while replaying a logfile
+#[derive(Debug, Default)]
+pub(super) struct LoadSummary {
+    /// bytes of earlier records made stale by this file
+    pub(super) uncompacted: u64,
+    /// the biggest sequence number found in this file
+    pub(super) last_seq: u64,
+    /// length of the prefix of the file holding intact records,
+    /// anything after it has to be cut off
+    pub(super) valid_len: u64,
+    /// whether a damaged record was found, rather than only
+    /// an incomplete one at the end of the newest logfile
+    pub(super) corrupted: bool,
+    /// total length of the file
+    pub(super) file_len: u64,
+}
+
 /// Scan the given gen file from reader, update in-memory
-/// database based on entries of the file
+/// database based on entries of the file.
+///
+/// An incomplete record at the end of the newest logfile is a write
+/// torn by a crash, and is left out of `valid_len`. Older logfiles were
+/// complete when the writer moved on from them, so there it can only
+/// come from a damaged length, and counts as corrupted. A corrupted
+/// record ends the scan as well under [RecoveryMode::TruncateCorrupted],
+/// and fails it under [RecoveryMode::Strict].
 pub(super) fn load_from_logfile(
     gen: u64,
+    newest: bool,
     reader: &mut PositionedBufReader<File>,
-    database: &mut BTreeMap<String, CommandPos>,
-) -> Result<u64> {
-    let mut uncompacted = 0;
+    database: &mut BTreeMap<Vec<u8>, CommandPos>,
+    mode: RecoveryMode,
+) -> Result<LoadSummary> {
+    let mut summary = LoadSummary {
+        file_len: reader.seek(SeekFrom::End(0))?,
+        ..LoadSummary::default()
+    };
 
     let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Ops>();
-    while let Some(op) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match op? {
-            Ops::Set { key, val: _ } => {
-                if let Some(old_op) = database.insert(key, (gen, pos, new_pos - pos).into()) {
-                    uncompacted += old_op.len;
-                }
+    loop {
+        let res = match record::read_record(reader)? {
+            RawRecord::Complete(buf) => {
+                record::decode_frame(&buf).map(|records| (buf.len() as u64, records))
             }
-            Ops::Rm { key } => {
-                if let Some(old_op) = database.remove(&key) {
-                    uncompacted += old_op.len;
-                }
+            RawRecord::Incomplete if newest => {
+                warn!("{}.log: dropping incomplete record at offset {}", gen, pos);
+                break;
+            }
+            RawRecord::Incomplete => Err(record::corruption(
+                "record runs past the end of a sealed logfile".to_owned(),
+            )),
+            RawRecord::Eof => break,
+        };
+
+        let (len, records) = match res {
+            Ok(res) => res,
+            Err(err) if mode == RecoveryMode::TruncateCorrupted => {
+                summary.corrupted = true;
+                warn!(
+                    "{}.log: dropping corrupted record at offset {} and everything after it: {}",
+                    gen,
+                    pos,
+                    err.cause().map_or(err.to_string(), ToString::to_string)
+                );
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+
+        // the ops of a batch are indexed at their own records
+        for (offset, record_len, record) in records {
+            summary.last_seq = summary.last_seq.max(record.seq);
+            let cmd_pos = (gen, pos + offset, record_len, record.expire_at).into();
+            match record.op {
+                Ops::Set { key, val: _ } => apply_entry(database, &mut summary, key, cmd_pos, true),
+                Ops::Rm { key } => apply_entry(database, &mut summary, key, cmd_pos, false),
             }
         }
-        pos = new_pos;
+        pos += len;
     }
 
-    // println!("In-Memory database after startup: {:?}", database);
+    summary.valid_len = pos;
+    Ok(summary)
+}
 
-    Ok(uncompacted)
+/// update database with a set (or remove) of key found at cmd_pos
+/// while loading a logfile
+pub(super) fn apply_entry(
+    database: &mut BTreeMap<Vec<u8>, CommandPos>,
+    summary: &mut LoadSummary,
+    key: Vec<u8>,
+    cmd_pos: CommandPos,
+    is_set: bool,
+) {
+    let old_op = if is_set {
+        database.insert(key, cmd_pos)
+    } else {
+        database.remove(&key)
+    };
+    if let Some(old_op) = old_op {
+        summary.uncompacted += old_op.len;
+    }
 }
 
-/// create a new logfile
-pub(super) fn new_log_file(
-    dirpath: &Path,
-    gen: u64,
-    readers: &mut BTreeMap<u64, PositionedBufReader<File>>,
-) -> Result<PositionedBufWriter<File>> {
-    let filepath = log_path(dirpath, gen);
+/// cut "{dirpath}/{gen}.log" back to its first len bytes
+pub(super) fn truncate_logfile(dirpath: &Path, gen: u64, len: u64) -> Result<()> {
     let file = OpenOptions::new()
-        .read(true)
         .write(true)
-        .create(true)
-        .open(&filepath)?;
-
-    let writer = PositionedBufWriter::new(file)?;
+        .open(log_path(dirpath, gen))?;
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(())
+}
 
-    readers.insert(gen, PositionedBufReader::new(File::open(&filepath)?)?);
-    Ok(writer)
+/// remove the logfiles of gens and their hint files, which
+/// recovery gives up on
+pub(super) fn remove_generations(dirpath: &Path, gens: &[u64]) -> Result<()> {
+    for &gen in gens {
+        warn!("{}.log: dropped during recovery", gen);
+        remove_hint_file(dirpath, gen)?;
+        fs::remove_file(log_path(dirpath, gen))?;
+    }
+    sync_dir(dirpath)
 }
 
-// create a new logfile without book-keeping a new reader
+/// create a new logfile
 pub(super) fn open_logfile(dirpath: &Path, gen: u64) -> Result<PositionedBufWriter<File>> {
     let filepath = log_path(dirpath, gen);
     let file = OpenOptions::new()
@@ -91,6 +207,23 @@ pub(super) fn open_logfile(dirpath: &Path, gen: u64) -> Result<PositionedBufWrit
         .write(true)
         .create(true)
         .open(&filepath)?;
+    // the file is only there after a crash once its directory entry is
+    sync_dir(dirpath)?;
     let writer = PositionedBufWriter::new(file)?;
     Ok(writer)
 }
+
+/// fsync the directory at dirpath, persisting the files
+/// created, renamed and removed in it
+#[cfg(unix)]
+pub(super) fn sync_dir(dirpath: &Path) -> Result<()> {
+    File::open(dirpath)?.sync_all()?;
+    Ok(())
+}
+
+/// directories can't be opened for fsync outside unix, where
+/// the filesystem persists their entries on its own
+#[cfg(not(unix))]
+pub(super) fn sync_dir(_dirpath: &Path) -> Result<()> {
+    Ok(())
+}
@@ -1,51 +1,980 @@
-use super::KvsEngine;
-use crate::Result;
-use std::path::Path;
+use super::batch::{BatchOp, WriteBatch};
+use super::checkpoint::create_empty_dir;
+use super::kv_util::now_millis;
+use super::merge::{parse_counter, Merge, MergeOperator};
+use super::scan::{is_empty_range, prefix_range, scan_as_of, KeyRange, ScanOrder};
+use super::stats::EngineStats;
+use super::CompareAndSwapError;
+use super::{KvsEngine, KvsSnapshot, KvsTransaction};
+use crate::thread_pool::ThreadPool;
+use crate::{KVError, KVErrorKind, Result};
+use byteorder::{BigEndian, ByteOrder};
+use fs2::FileExt;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+use sled::Transactional;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::error;
 
-/// Wrapper Around sled database,
+/// Controls when a [SledKvsEngine] persists its writes to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// flush the database after every set/remove before
+    /// the operation is acknowledged
+    #[default]
+    EveryWrite,
+    /// leave flushing to sled's background thread, which
+    /// flushes every given number of milliseconds
+    Periodic(u64),
+    /// never flush, leaving the writes to whatever sled
+    /// persists of them on its own
+    Never,
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    /// parse `every-write`, `never` or an interval
+    /// in milliseconds such as `interval=100`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "every-write" => Ok(FlushPolicy::EveryWrite),
+            "never" => Ok(FlushPolicy::Never),
+            _ => match s.strip_prefix("interval=").map(str::parse) {
+                Some(Ok(ms)) if ms > 0 => Ok(FlushPolicy::Periodic(ms)),
+                _ => Err(String::from("Unknown flush policy")),
+            },
+        }
+    }
+}
+
+// tree mapping keys that expire to their expiration
+// time, in milliseconds since the unix epoch
+const EXPIRY_TREE: &str = "expiry";
+
+// how long a checkpoint waits for sled to release the copy it wrote
+const DB_UNLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wrapper Around sled database.
+///
+/// Blocking sled calls are run on a [ThreadPool], the same way
+/// [KvStore](crate::KvStore) runs its disk I/O. Expiration times
+/// of keys set with a ttl live in a tree of their own and are
+/// updated in the same transaction as the values.
+///
+/// sled has no snapshots of its own, yet [snapshot](KvsEngine::snapshot)
+/// and [begin](KvsEngine::begin) copy nothing, see [SledSnapshot].
+///
+/// # Examples
+/// ```rust
+/// use kvs_project_5::{
+///     thread_pool::SharedQueueThreadPool,
+///     SledKvsEngine,
+///     KvsEngine
+/// };
+/// use tempfile::TempDir;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let dir = TempDir::new().unwrap();
+///     let sled = SledKvsEngine::<SharedQueueThreadPool>::open(dir.path(), 5).unwrap();
+///
+///     sled.set(b"Key".to_vec(), b"Value".to_vec()).await.unwrap();
+///     assert_eq!(Some(b"Value".to_vec()), sled.get(b"Key".to_vec()).await.unwrap());
+/// }
+///
 #[derive(Clone)]
-pub struct SledKvsEngine {
-    _db: sled::Db,
+pub struct SledKvsEngine<P: ThreadPool> {
+    db: sled::Db,
+    pool: P,
+    flush: FlushPolicy,
+    // writes share it, snapshots take it exclusively
+    snapshot_lock: Arc<RwLock<()>>,
+    overlays: Arc<Overlays>,
+    merge_operator: Option<MergeOperator>,
 }
 
-impl SledKvsEngine {
+impl<P: ThreadPool> SledKvsEngine<P> {
     /// open a new instance binded with
-    /// path
-    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let _db = sled::Config::new().path(path).open()?;
+    /// path, flushing on every write
+    pub fn open(path: impl AsRef<Path>, capacity: i32) -> Result<Self> {
+        Self::open_with_flush(path, capacity, FlushPolicy::default())
+    }
+
+    /// open a new instance binded with path, using
+    /// the given flush policy
+    pub fn open_with_flush(
+        path: impl AsRef<Path>,
+        capacity: i32,
+        flush: FlushPolicy,
+    ) -> Result<Self> {
+        let mut config = sled::Config::new().path(path);
+        match flush {
+            FlushPolicy::EveryWrite => {}
+            FlushPolicy::Periodic(ms) => config = config.flush_every_ms(Some(ms)),
+            FlushPolicy::Never => config = config.flush_every_ms(None),
+        }
+        let db = config.open()?;
+        let pool = P::new(capacity)?;
 
-        Ok(Self { _db })
+        Ok(Self {
+            db,
+            pool,
+            flush,
+            snapshot_lock: Arc::default(),
+            overlays: Arc::default(),
+            merge_operator: None,
+        })
     }
 
     /// create a new instance based on given sled database instance
-    pub fn new(sled: sled::Db) -> Self {
-        Self { _db: sled }
+    /// and thread pool, flushing on every write
+    pub fn new(sled: sled::Db, pool: P) -> Self {
+        Self {
+            db: sled,
+            pool,
+            flush: FlushPolicy::default(),
+            snapshot_lock: Arc::default(),
+            overlays: Arc::default(),
+            merge_operator: None,
+        }
+    }
+
+    /// register the function [merge](KvsEngine::merge) merges operands
+    /// with, see [KvStoreOptions::merge_operator](crate::KvStoreOptions::merge_operator)
+    pub fn with_merge_operator(
+        mut self,
+        operator: impl Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    ) -> Self {
+        self.merge_operator = Some(MergeOperator::new(operator));
+        self
+    }
+
+    // merge into the value of key, returning the result
+    async fn merge_value(&self, key: Vec<u8>, merge: Merge) -> Result<Option<Vec<u8>>> {
+        let operator = self.merge_operator.clone();
+        self.run_write(vec![key.clone()], move |db, flush| {
+            let merged = merge_in_transaction(&db, &key, &merge, operator.as_ref())?;
+            maybe_flush(&db, flush)?;
+            Ok(merged)
+        })
+        .await
+    }
+
+    // run the blocking sled operation on the thread pool
+    // and wait for its result through a channel
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(sled::Db, FlushPolicy) -> Result<T> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let db = self.db.clone();
+        let flush = self.flush;
+
+        self.pool.spawn(move || {
+            let res = f(db, flush);
+            if sender.send(res).is_err() {
+                error!("Receiving End is dropped");
+            }
+        });
+
+        match receiver.await {
+            Ok(r) => r,
+            Err(err) => Err(KVError::from(err)),
+        }
+    }
+
+    // run a sled operation that writes keys, which must not overlap
+    // with taking a snapshot or beginning a transaction, after saving
+    // the values keys hold for the open snapshots and transactions
+    async fn run_write<T, F>(&self, keys: Vec<Vec<u8>>, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(sled::Db, FlushPolicy) -> Result<T> + Send + 'static,
+    {
+        let snapshot_lock = Arc::clone(&self.snapshot_lock);
+        let overlays = Arc::clone(&self.overlays);
+        self.run(move |db, flush| {
+            let _guard = snapshot_lock.read().unwrap();
+            overlays.capture(&db, &keys)?;
+            f(db, flush)
+        })
+        .await
+    }
+
+    // open an overlay for a snapshot or transaction, once the writes
+    // under way are done, since they have not captured anything for it
+    async fn open_overlay(&self) -> Result<u64> {
+        let snapshot_lock = Arc::clone(&self.snapshot_lock);
+        let overlays = Arc::clone(&self.overlays);
+        self.run(move |_, _| {
+            let _guard = snapshot_lock.write().unwrap();
+            Ok(overlays.begin())
+        })
+        .await
+    }
+}
+
+/// The values keys had when each open [SledSnapshot] or
+/// [SledTransaction] started, kept for the keys written since, which
+/// is where it reads them. Every write captures the values of its keys
+/// first, for the snapshots and transactions that don't have them yet
+#[derive(Debug, Default)]
+struct Overlays {
+    state: Mutex<OverlayState>,
+}
+
+#[derive(Debug, Default)]
+struct OverlayState {
+    next_id: u64,
+    // the captured values of each open snapshot and transaction,
+    // with their expiration times. None stands for no value
+    open: HashMap<u64, BTreeMap<Vec<u8>, Captured>>,
+}
+
+// a value and its expiration time, None if there is no value
+type Captured = Option<(Vec<u8>, Option<u64>)>;
+
+impl Overlays {
+    // register a snapshot or transaction, returning its id
+    fn begin(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(id, BTreeMap::new());
+        id
+    }
+
+    fn end(&self, id: u64) {
+        self.state.lock().unwrap().open.remove(&id);
+    }
+
+    // capture the current values of keys for the open snapshots
+    // and transactions that lack them. Done under the lock, so that no write captures
+    // a key in between and goes on to change it
+    fn capture(&self, db: &sled::Db, keys: &[Vec<u8>]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.open.is_empty() {
+            return Ok(());
+        }
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        for key in keys {
+            if state.open.values().all(|overlay| overlay.contains_key(key)) {
+                continue;
+            }
+            // the expiration time first, since an expired
+            // value may be purged without being captured
+            let expire_at = expiry
+                .get(key)?
+                .map(|expire_at| BigEndian::read_u64(&expire_at));
+            let captured = db.get(key)?.map(|val| (val.to_vec(), expire_at));
+            for overlay in state.open.values_mut() {
+                overlay
+                    .entry(key.clone())
+                    .or_insert_with(|| captured.clone());
+            }
+        }
+        Ok(())
+    }
+
+    // the value key had when overlay id was opened, as of now,
+    // provided key has been written since
+    fn get(&self, id: u64, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        let state = self.state.lock().unwrap();
+        let captured = state.open.get(&id)?.get(key)?;
+        Some(unexpired(captured, now_millis()))
+    }
+
+    // the values the keys in range written since overlay id
+    // was opened had then, as of now
+    fn range(&self, id: u64, range: &KeyRange) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        let state = self.state.lock().unwrap();
+        let now = now_millis();
+        match state.open.get(&id) {
+            Some(overlay) => overlay
+                .range(range.clone())
+                .map(|(key, captured)| (key.clone(), unexpired(captured, now)))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// the captured value, unless it has expired by now
+fn unexpired(captured: &Captured, now: u64) -> Option<Vec<u8>> {
+    match captured {
+        Some((_, Some(expire_at))) if *expire_at <= now => None,
+        Some((val, _)) => Some(val.clone()),
+        None => None,
+    }
+}
+
+// the value key had when overlay id was opened. The live value is
+// only that one if the key has not been captured by the time it is
+// read, so the overlay is looked at again afterwards
+fn get_overlaid(
+    db: &sled::Db,
+    overlays: &Overlays,
+    id: u64,
+    key: &[u8],
+) -> Result<Option<Vec<u8>>> {
+    if let Some(val) = overlays.get(id, key) {
+        return Ok(val);
+    }
+    let live = get_live(db, key)?;
+    Ok(overlays.get(id, key).unwrap_or(live))
+}
+
+// the first limit entries of range when overlay id was opened
+fn scan_overlaid(
+    db: &sled::Db,
+    overlays: &Overlays,
+    id: u64,
+    range: KeyRange,
+    limit: usize,
+    order: ScanOrder,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if is_empty_range(&range) {
+        return Ok(Vec::new());
+    }
+
+    let expiry = db.open_tree(EXPIRY_TREE)?;
+    let now = now_millis();
+    let iter = db.range::<Vec<u8>, _>(range.clone());
+    let iter: Box<dyn Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>> = match order {
+        ScanOrder::Forward => Box::new(iter),
+        ScanOrder::Reverse => Box::new(iter.rev()),
+    };
+    let live = iter.map(|entry| {
+        let (key, val) = entry?;
+        let expired = match expiry.get(&key)? {
+            Some(expire_at) => is_expired(&expire_at, now),
+            None => false,
+        };
+        Ok((key.to_vec(), Some(val.to_vec()).filter(|_| !expired)))
+    });
+    let written = |covered: &KeyRange| Ok(overlays.range(id, covered));
+    scan_as_of(live, written, &range, limit, order)
+}
+
+/// A read-only view of a [SledKvsEngine] frozen at the time it was
+/// taken with [snapshot](KvsEngine::snapshot).
+///
+/// Taking one copies nothing. Instead, each write to the database first
+/// saves the values its keys had for the open snapshots, which read
+/// those keys there and the others from the database, so they see the
+/// data as it was when they were taken. Keys expire as of the time they
+/// are read.
+#[derive(Clone)]
+pub struct SledSnapshot<P: ThreadPool> {
+    inner: Arc<SledSnapshotInner<P>>,
+}
+
+struct SledSnapshotInner<P: ThreadPool> {
+    engine: SledKvsEngine<P>,
+    // the id of the snapshot's overlay
+    id: u64,
+}
+
+impl<P: ThreadPool> Drop for SledSnapshotInner<P> {
+    fn drop(&mut self) {
+        self.engine.overlays.end(self.id);
+    }
+}
+
+/// A read-write transaction on a [SledKvsEngine], started
+/// with [begin](KvsEngine::begin).
+///
+/// Like a [SledSnapshot], it reads the data as it was when it began,
+/// without copying anything. The writes of the transaction are kept in
+/// memory until it commits. Without versions to go by, the commit
+/// detects a conflict by comparing the current value of each written
+/// key with the one it had at the start, so a key changed and then
+/// changed back in the meantime does not conflict.
+pub struct SledTransaction<P: ThreadPool> {
+    engine: SledKvsEngine<P>,
+    // the id of the transaction's overlay
+    id: u64,
+    // buffered writes, None standing for a remove
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<P: ThreadPool> Drop for SledTransaction<P> {
+    fn drop(&mut self) {
+        self.engine.overlays.end(self.id);
+    }
+}
+
+// the value of key, unless it has expired
+fn get_live(db: &sled::Db, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    if purge_expired(db, key)? {
+        return Ok(None);
+    }
+    Ok(db.get(key)?.map(|ivec| ivec.to_vec()))
+}
+
+// apply the writes of transaction id unless a key no longer has the
+// value it had when the transaction began, in a single sled transaction
+fn commit_writes(
+    db: &sled::Db,
+    overlays: &Overlays,
+    id: u64,
+    writes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+) -> Result<()> {
+    let expiry = db.open_tree(EXPIRY_TREE)?;
+    let now = now_millis();
+    let res: std::result::Result<(), TransactionError<KVErrorKind>> =
+        (&**db, &expiry).transaction(|(values, expiry)| {
+            for key in writes.keys() {
+                let current = match (values.get(key.as_slice())?, expiry.get(key.as_slice())?) {
+                    (Some(_), Some(expire_at)) if is_expired(&expire_at, now) => None,
+                    (current, _) => current,
+                };
+                // a key not written since the transaction
+                // began still has the value it had then
+                let expected = match overlays.get(id, key) {
+                    Some(expected) => expected,
+                    None => continue,
+                };
+                if current.as_deref() != expected.as_deref() {
+                    return Err(ConflictableTransactionError::Abort(
+                        KVErrorKind::TransactionConflict,
+                    ));
+                }
+            }
+            for (key, val) in writes {
+                match val {
+                    Some(val) => {
+                        values.insert(key.as_slice(), val.as_slice())?;
+                    }
+                    None => {
+                        values.remove(key.as_slice())?;
+                    }
+                }
+                expiry.remove(key.as_slice())?;
+            }
+            Ok(())
+        });
+    res.map_err(from_transaction_error)
+}
+
+// copy every tree of db, values and expiry times alike, into a new
+// database at dest, returning once the copy is closed and can be opened
+fn copy_db(db: &sled::Db, dest: &Path) -> Result<()> {
+    let copy = sled::open(dest)?;
+    for name in db.tree_names() {
+        let (tree, copy_tree) = (db.open_tree(&name)?, copy.open_tree(&name)?);
+        for entry in tree.iter() {
+            let (key, val) = entry?;
+            copy_tree.insert(key, val)?;
+        }
+    }
+    copy.flush()?;
+    drop(copy);
+    wait_unlocked(dest)
+}
+
+// wait for the lock sled holds on the database at dirpath to be
+// released, which its background threads may delay for a moment
+// after the last handle is dropped
+fn wait_unlocked(dirpath: &Path) -> Result<()> {
+    let lock_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dirpath.join("db"))?;
+    let deadline = Instant::now() + DB_UNLOCK_TIMEOUT;
+    loop {
+        if lock_file.try_lock_exclusive().is_ok() {
+            lock_file.unlock()?;
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("database at {} is still locked", dirpath.display()),
+            )
+            .into());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// flush the database if required by the policy
+fn maybe_flush(db: &sled::Db, flush: FlushPolicy) -> Result<()> {
+    if flush == FlushPolicy::EveryWrite {
+        db.flush()?;
+    }
+    Ok(())
+}
+
+// whether an entry of the expiry tree has passed
+fn is_expired(expire_at: &[u8], now: u64) -> bool {
+    BigEndian::read_u64(expire_at) <= now
+}
+
+// drop key from both trees if it has expired, unless
+// a concurrent write has replaced it in the meantime.
+// Return whether key had expired
+fn purge_expired(db: &sled::Db, key: &[u8]) -> Result<bool> {
+    let expiry = db.open_tree(EXPIRY_TREE)?;
+    let now = now_millis();
+    match expiry.get(key)? {
+        Some(expire_at) if is_expired(&expire_at, now) => {}
+        _ => return Ok(false),
+    }
+
+    let res: std::result::Result<bool, TransactionError<KVErrorKind>> = (&**db, &expiry)
+        .transaction(|(values, expiry)| match expiry.get(key)? {
+            Some(expire_at) if is_expired(&expire_at, now) => {
+                values.remove(key)?;
+                expiry.remove(key)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        });
+    res.map_err(from_transaction_error)
+}
+
+// set key to val in the default tree, recording its
+// expiration time if there is one and clearing it otherwise
+fn insert_expiring(
+    db: &sled::Db,
+    key: Vec<u8>,
+    val: Vec<u8>,
+    expire_at: Option<u64>,
+) -> Result<()> {
+    let expiry = db.open_tree(EXPIRY_TREE)?;
+    let res: std::result::Result<(), TransactionError<KVErrorKind>> =
+        (&**db, &expiry).transaction(|(values, expiry)| {
+            values.insert(key.as_slice(), val.as_slice())?;
+            match expire_at {
+                Some(expire_at) => {
+                    let mut buf = [0u8; 8];
+                    BigEndian::write_u64(&mut buf, expire_at);
+                    expiry.insert(key.as_slice(), &buf)?;
+                }
+                None => {
+                    expiry.remove(key.as_slice())?;
+                }
+            }
+            Ok(())
+        });
+    res.map_err(from_transaction_error)
+}
+
+// merge into the value of key in a single sled transaction, keeping
+// its expiration time, and return the result
+fn merge_in_transaction(
+    db: &sled::Db,
+    key: &[u8],
+    merge: &Merge,
+    operator: Option<&MergeOperator>,
+) -> Result<Option<Vec<u8>>> {
+    let expiry = db.open_tree(EXPIRY_TREE)?;
+    let now = now_millis();
+    let res: std::result::Result<Option<Vec<u8>>, TransactionError<KVErrorKind>> = (&**db, &expiry)
+        .transaction(|(values, expiry)| {
+            let current = match (values.get(key)?, expiry.get(key)?) {
+                // the result must not inherit the ttl of an expired value
+                (Some(_), Some(expire_at)) if is_expired(&expire_at, now) => {
+                    expiry.remove(key)?;
+                    None
+                }
+                (current, _) => current,
+            };
+            let merged = merge
+                .apply(key, current.as_deref(), operator)
+                .map_err(|err| ConflictableTransactionError::Abort(err.kind()))?;
+            match &merged {
+                Some(val) => {
+                    values.insert(key, val.as_slice())?;
+                }
+                None => {
+                    values.remove(key)?;
+                    expiry.remove(key)?;
+                }
+            }
+            Ok(merged)
+        });
+    res.map_err(from_transaction_error)
+}
+
+// set key to new, or remove it if new is None, in a single sled
+// transaction provided its value is expected, where an expired
+// value compares as no value
+fn compare_and_swap_in_transaction(
+    db: &sled::Db,
+    key: &[u8],
+    expected: Option<&[u8]>,
+    new: Option<&[u8]>,
+) -> Result<std::result::Result<(), CompareAndSwapError>> {
+    let expiry = db.open_tree(EXPIRY_TREE)?;
+    let now = now_millis();
+    let res: std::result::Result<_, TransactionError<KVErrorKind>> =
+        (&**db, &expiry).transaction(|(values, expiry)| {
+            let current = match (values.get(key)?, expiry.get(key)?) {
+                (Some(_), Some(expire_at)) if is_expired(&expire_at, now) => {
+                    values.remove(key)?;
+                    expiry.remove(key)?;
+                    None
+                }
+                (current, _) => current,
+            };
+            if current.as_deref() != expected {
+                let current = current.map(|ivec| ivec.to_vec());
+                return Ok(Err(CompareAndSwapError { current }));
+            }
+            match new {
+                Some(new) => {
+                    values.insert(key, new)?;
+                }
+                None => {
+                    values.remove(key)?;
+                }
+            }
+            // the new value does not inherit the ttl of the old one
+            expiry.remove(key)?;
+            Ok(Ok(()))
+        });
+    res.map_err(from_transaction_error)
+}
+
+// remove key from both trees, returning whether it
+// held a value that had not expired yet
+fn remove_expiring(db: &sled::Db, key: &[u8]) -> Result<bool> {
+    let expiry = db.open_tree(EXPIRY_TREE)?;
+    let now = now_millis();
+    let res: std::result::Result<bool, TransactionError<KVErrorKind>> = (&**db, &expiry)
+        .transaction(|(values, expiry)| remove_in_transaction(values, expiry, key, now));
+    res.map_err(from_transaction_error)
+}
+
+// apply the ops of a batch in a single transaction, aborting
+// it if one of the removes finds no live value
+fn apply_batch(db: &sled::Db, ops: &[BatchOp]) -> Result<()> {
+    let expiry = db.open_tree(EXPIRY_TREE)?;
+    let now = now_millis();
+    let res: std::result::Result<(), TransactionError<KVErrorKind>> =
+        (&**db, &expiry).transaction(|(values, expiry)| {
+            for op in ops {
+                match op {
+                    BatchOp::Set { key, val } => {
+                        values.insert(key.as_slice(), val.as_slice())?;
+                        expiry.remove(key.as_slice())?;
+                    }
+                    BatchOp::Remove { key } => {
+                        if !remove_in_transaction(values, expiry, key.as_slice(), now)? {
+                            return Err(ConflictableTransactionError::Abort(
+                                KVErrorKind::KeyNotFound,
+                            ));
+                        }
+                    }
+                }
+            }
+            Ok(())
+        });
+    res.map_err(from_transaction_error)
+}
+
+// remove key from both trees, returning whether it
+// held a value that had not expired by now
+fn remove_in_transaction(
+    values: &TransactionalTree,
+    expiry: &TransactionalTree,
+    key: &[u8],
+    now: u64,
+) -> ConflictableTransactionResult<bool, KVErrorKind> {
+    let old_val = values.remove(key)?;
+    let old_expire_at = expiry.remove(key)?;
+    let expired = old_expire_at.is_some_and(|expire_at| is_expired(&expire_at, now));
+    Ok(old_val.is_some() && !expired)
+}
+
+fn from_transaction_error(err: TransactionError<KVErrorKind>) -> KVError {
+    match err {
+        TransactionError::Storage(err) => err.into(),
+        TransactionError::Abort(kind) => kind.into(),
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.run(move |db, _| get_live(&db, &key)).await
+    }
+
+    async fn set(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.run_write(vec![key.clone()], move |db, flush| {
+            insert_expiring(&db, key, val, None)?;
+            maybe_flush(&db, flush)
+        })
+        .await
+    }
+
+    async fn set_with_ttl(&self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expire_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.run_write(vec![key.clone()], move |db, flush| {
+            insert_expiring(&db, key, val, Some(expire_at))?;
+            maybe_flush(&db, flush)
+        })
+        .await
+    }
+
+    async fn remove(&self, key: Vec<u8>) -> Result<()> {
+        self.run_write(vec![key.clone()], move |db, flush| {
+            if !remove_expiring(&db, key.as_slice())? {
+                return Err(KVErrorKind::KeyNotFound.into());
+            }
+            maybe_flush(&db, flush)
+        })
+        .await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        self.run_write(vec![key.clone()], move |db, flush| {
+            let res =
+                compare_and_swap_in_transaction(&db, &key, expected.as_deref(), new.as_deref())?;
+            if res.is_ok() {
+                maybe_flush(&db, flush)?;
+            }
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn incr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let merged = self.merge_value(key, Merge::IncrBy(delta)).await?;
+        parse_counter(merged.as_deref())
+    }
+
+    async fn append(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<()> {
+        self.merge_value(key, Merge::Append(suffix)).await?;
+        Ok(())
+    }
+
+    async fn merge(&self, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        self.merge_value(key, Merge::Operand(operand)).await?;
+        Ok(())
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let keys = batch
+            .ops()
+            .iter()
+            .map(|op| match op {
+                BatchOp::Set { key, val: _ } => key.clone(),
+                BatchOp::Remove { key } => key.clone(),
+            })
+            .collect();
+        self.run_write(keys, move |db, flush| {
+            apply_batch(&db, batch.ops())?;
+            maybe_flush(&db, flush)
+        })
+        .await
+    }
+
+    async fn scan(
+        &self,
+        range: KeyRange,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.run(move |db, _| {
+            if is_empty_range(&range) {
+                return Ok(Vec::new());
+            }
+
+            let expiry = db.open_tree(EXPIRY_TREE)?;
+            let now = now_millis();
+            let iter = db.range::<Vec<u8>, _>(range);
+            let iter: Box<dyn Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>>> = match order
+            {
+                ScanOrder::Forward => Box::new(iter),
+                ScanOrder::Reverse => Box::new(iter.rev()),
+            };
+
+            let mut pairs = Vec::new();
+            for entry in iter {
+                if pairs.len() >= limit {
+                    break;
+                }
+                let (key, val) = entry?;
+                // expired entries are skipped here and
+                // dropped by the next get or remove
+                if let Some(expire_at) = expiry.get(&key)? {
+                    if is_expired(&expire_at, now) {
+                        continue;
+                    }
+                }
+                pairs.push((key.to_vec(), val.to_vec()));
+            }
+            Ok(pairs)
+        })
+        .await
+    }
+
+    async fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan(prefix_range(prefix), limit, order).await
+    }
+
+    type Snapshot = SledSnapshot<P>;
+
+    async fn snapshot(&self) -> Result<Self::Snapshot> {
+        let id = self.open_overlay().await?;
+        Ok(SledSnapshot {
+            inner: Arc::new(SledSnapshotInner {
+                engine: self.clone(),
+                id,
+            }),
+        })
+    }
+
+    type Transaction = SledTransaction<P>;
+
+    async fn begin(&self) -> Result<Self::Transaction> {
+        let id = self.open_overlay().await?;
+        Ok(SledTransaction {
+            engine: self.clone(),
+            id,
+            writes: BTreeMap::new(),
+        })
+    }
+
+    async fn checkpoint(&self, dest: PathBuf) -> Result<()> {
+        let snapshot_lock = Arc::clone(&self.snapshot_lock);
+        self.run(move |db, _| {
+            create_empty_dir(&dest)?;
+            // holding off writes keeps the copy consistent
+            let _guard = snapshot_lock.write().unwrap();
+            copy_db(&db, &dest)
+        })
+        .await
+    }
+
+    async fn stats(&self) -> Result<EngineStats> {
+        self.run(|db, _| {
+            let expiry = db.open_tree(EXPIRY_TREE)?;
+            let now = now_millis();
+            let mut stats = EngineStats {
+                disk_bytes: db.size_on_disk()?,
+                ..EngineStats::default()
+            };
+            for entry in db.iter() {
+                let (key, val) = entry?;
+                if let Some(expire_at) = expiry.get(&key)? {
+                    if is_expired(&expire_at, now) {
+                        continue;
+                    }
+                }
+                stats.key_count += 1;
+                stats.live_bytes += (key.len() + val.len()) as u64;
+            }
+            Ok(stats)
+        })
+        .await
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: ThreadPool> KvsSnapshot for SledSnapshot<P> {
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let inner = Arc::clone(&self.inner);
+        self.inner
+            .engine
+            .run(move |db, _| get_overlaid(&db, &inner.engine.overlays, inner.id, &key))
+            .await
+    }
+
+    async fn scan(
+        &self,
+        range: KeyRange,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let inner = Arc::clone(&self.inner);
+        self.inner
+            .engine
+            .run(move |db, _| {
+                scan_overlaid(&db, &inner.engine.overlays, inner.id, range, limit, order)
+            })
+            .await
+    }
+
+    async fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan(prefix_range(prefix), limit, order).await
     }
 }
 
 #[async_trait::async_trait]
-impl KvsEngine for SledKvsEngine {
-    async fn get(&self, _key: String) -> Result<Option<String>> {
-        // let res = self.db.get(key)?;
-        // Ok(res.map(|ivec| String::from_utf8(ivec.to_vec()).expect("Utf8 Error")))
-        unimplemented!()
-    }
-
-    async fn set(&self, _key: String, _val: String) -> Result<()> {
-        // self.db.insert(key, val.as_bytes())?;
-        // self.db.flush()?;
-        // Ok(())
-        unimplemented!()
-    }
-
-    async fn remove(&self, _key: String) -> Result<()> {
-        // let res = self.db.remove(key.clone())?;
-        // self.db.flush()?;
-        // if res.is_none() {
-        //     Err(KVErrorKind::KeyNotFound(key).into())
-        // } else {
-        //     Ok(())
-        // }
-        unimplemented!()
+impl<P: ThreadPool> KvsTransaction for SledTransaction<P> {
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(val) = self.writes.get(&key) {
+            return Ok(val.clone());
+        }
+        let overlays = Arc::clone(&self.engine.overlays);
+        let id = self.id;
+        self.engine
+            .run(move |db, _| get_overlaid(&db, &overlays, id, &key))
+            .await
+    }
+
+    async fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.writes.insert(key, Some(val));
+        Ok(())
+    }
+
+    async fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get(key.clone()).await?.is_none() {
+            return Err(KVErrorKind::KeyNotFound.into());
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    async fn commit(mut self) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+
+        let overlays = Arc::clone(&self.engine.overlays);
+        let id = self.id;
+        let writes = std::mem::take(&mut self.writes);
+        let keys = writes.keys().cloned().collect();
+        self.engine
+            .run_write(keys, move |db, flush| {
+                commit_writes(&db, &overlays, id, &writes)?;
+                maybe_flush(&db, flush)
+            })
+            .await
+    }
+
+    async fn rollback(self) -> Result<()> {
+        Ok(())
     }
 }
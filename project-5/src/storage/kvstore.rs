@@ -1,18 +1,50 @@
-use super::{kv_util::*, KvsEngine};
+use super::archive::{replay_archive, Archiver, RestorePoint};
+use super::batch::{BatchOp, WriteBatch};
+use super::checkpoint::{create_empty_dir, restore_checkpoint, write_checkpoint};
+use super::compaction::{Compaction, CompactionHandle};
+use super::compression::Compressor;
+use super::dir_lock::DirLock;
+use super::group_commit::{CommitQueue, PendingWrite};
+use super::hint::{load_from_hintfile, write_hint_file, HintEntry};
+use super::index::{Index, IndexGuard};
+use super::log_readers::LogReaders;
+use super::merge::{parse_counter, Merge, MergeOperator};
+use super::options::{KvStoreOptions, SyncPolicy};
+use super::scan::{is_empty_range, prefix_range, KeyRange, ScanOrder};
+use super::snapshot::{KvStoreSnapshot, Pins};
+use super::stats::{EngineStats, KvStoreStats};
+use super::transaction::{KvStoreTransaction, Versions};
+use super::value_cache::ValueCache;
+use super::{kv_util::*, record, CompareAndSwapError, KvsEngine};
 use crate::thread_pool::ThreadPool;
 use crate::{KVError, KVErrorKind, Result};
-use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{self, File};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
 use tokio::sync::oneshot;
-use tracing::error;
+use tracing::{error, warn};
 
-// try to compact log under 2MB threshold
-const COMPACTION_THRESHOLD: u64 = 2 * 1024 * 1024;
+/// How [KvStore::open_with_recovery] deals with damaged logfiles.
+///
+/// An incomplete record at the end of the newest logfile is what a
+/// crash in the middle of a write leaves behind, so it is always cut
+/// off. The modes differ in how they treat a damaged record: one that
+/// fails its checksum, or runs past the end of an older logfile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecoveryMode {
+    /// cut the logfile back to the last intact record before the
+    /// damaged one, dropping everything after it, newer logfiles
+    /// included
+    #[default]
+    TruncateCorrupted,
+    /// refuse to open the store
+    Strict,
+}
 
 /// Data Structure handling the storage and retrieval
 /// of key-value data
@@ -31,28 +63,34 @@ const COMPACTION_THRESHOLD: u64 = 2 * 1024 * 1024;
 ///     let temp_dir = TempDir::new().unwrap();
 ///     let mut store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 5).unwrap();
 ///
-///     store.set(String::from("key"), String::from("value")).await.unwrap();
-///     assert_eq!(Some(String::from("value")), store.get(String::from("key")).await.unwrap());
+///     store.set(b"key".to_vec(), b"value".to_vec()).await.unwrap();
+///     assert_eq!(Some(b"value".to_vec()), store.get(b"key".to_vec()).await.unwrap());
 ///
-///     store.remove(String::from("key")).await.unwrap();
-///     assert_eq!(None, store.get(String::from("key")).await.unwrap());
+///     store.remove(b"key".to_vec()).await.unwrap();
+///     assert_eq!(None, store.get(b"key".to_vec()).await.unwrap());
 /// }
 ///
 #[derive(Debug, Clone)]
 pub struct KvStore<P: ThreadPool> {
     // referenced by all readers and the writer
-    // access of database and cur_read_gen is critical section
-    // protected for all kinds of tasks, but it should
-    // remain short and in-memory only. Split the disk
+    // the database is an index read without its lock, that only the writer
+    // and compaction change. Split the disk
     // work through read_half and write_half
     // dirpath: Arc<PathBuf>,
-    // database: Arc<Mutex<BTreeMap<String, CommandPos>>>,
+    // database: Arc<Index>,
 
     // reader local structures
     read_half: KvStoreReadHalf,
 
-    // writer local structures
-    write_half: Arc<Mutex<KvStoreWriteHalf>>,
+    // writer local structures, None if the store is read-only
+    write_half: Option<Arc<Mutex<KvStoreWriteHalf>>>,
+    // versions of the keys written while snapshots
+    // and transactions are open
+    versions: Arc<Versions>,
+    // shared with the writer, which counts the bytes it compresses
+    compressor: Arc<Compressor>,
+    // sets and removes waiting to be committed together
+    commits: Arc<CommitQueue>,
     pool: P,
 }
 
@@ -60,59 +98,256 @@ impl<P: ThreadPool> KvStore<P> {
     /// create a new KvStore instance binded to
     /// given path as its log-file location
     pub fn open(path: impl Into<PathBuf>, capacity: i32) -> Result<Self> {
+        KvStoreOptions::new().pool_capacity(capacity).open(path)
+    }
+
+    /// create a new KvStore instance binded to given path,
+    /// recovering damaged logfiles according to mode
+    pub fn open_with_recovery(
+        path: impl Into<PathBuf>,
+        capacity: i32,
+        mode: RecoveryMode,
+    ) -> Result<Self> {
+        KvStoreOptions::new()
+            .pool_capacity(capacity)
+            .recovery_mode(mode)
+            .open(path)
+    }
+
+    /// create a new KvStore instance binded to given path,
+    /// configured by options
+    pub fn open_with_options(path: impl Into<PathBuf>, options: &KvStoreOptions) -> Result<Self> {
+        let mode = options.recovery_mode;
+        let read_only = options.read_only;
         let dirpath = Arc::new(path.into());
         // ensure that the log directory exists before proceeding
         fs::create_dir_all(&*dirpath)?;
+        let lock = Arc::new(DirLock::acquire(&dirpath, read_only)?);
+        // snapshots don't outlive the store that took them. A read-only
+        // store leaves the files alone, they may be the writer's
+        if !read_only {
+            remove_leftover_logs(&dirpath)?;
+        }
 
         let mut database = BTreeMap::new();
-        let mut readers = BTreeMap::new();
         let mut uncompacted = 0;
-        let gen_list = sorted_gen_list(&dirpath)?;
-
-        for &gen in &gen_list {
-            let mut reader = PositionedBufReader::new(File::open(&log_path(&dirpath, gen))?)?;
-            let new_uncompacted = load_from_logfile(gen, &mut reader, &mut database)?;
-            readers.insert(gen, reader);
-            uncompacted += new_uncompacted;
+        let mut last_seq = 0;
+        let mut gen_list = sorted_gen_list(&dirpath)?;
+        let newest_gen = gen_list.last().copied();
+
+        for (replayed, &gen) in gen_list.iter().enumerate() {
+            // a generation with a hint file doesn't need its log replayed
+            let log_len = fs::metadata(log_path(&dirpath, gen))?.len();
+            let summary = match load_from_hintfile(&dirpath, gen, log_len, &mut database)? {
+                Some(summary) => summary,
+                None => {
+                    let mut reader =
+                        PositionedBufReader::new(File::open(&log_path(&dirpath, gen))?)?;
+                    let newest = Some(gen) == newest_gen;
+                    let summary = load_from_logfile(gen, newest, &mut reader, &mut database, mode)?;
+                    // the writer may be in the middle of appending to the
+                    // logfile a read-only store reads, so it only skips
+                    // what comes after the last intact record
+                    if summary.valid_len < summary.file_len && !read_only {
+                        warn!(
+                            "{}.log: truncated from {} to {} bytes during recovery",
+                            gen, summary.file_len, summary.valid_len
+                        );
+                        truncate_logfile(&dirpath, gen, summary.valid_len)?;
+                    }
+                    summary
+                }
+            };
+            uncompacted += summary.uncompacted;
+            last_seq = last_seq.max(summary.last_seq);
+
+            // the records lost with the damaged one came before those of
+            // the newer generations, so replaying these on top would give
+            // data the store never held. Recovery stops here instead
+            if summary.corrupted {
+                let dropped = gen_list.split_off(replayed + 1);
+                if !read_only {
+                    remove_generations(&dirpath, &dropped)?;
+                }
+                break;
+            }
         }
 
         let cur_gen = gen_list.iter().last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&dirpath, cur_gen, &mut readers)?;
-        let database = Arc::new(Mutex::new(database));
+        let database = Arc::new(Index::from(database));
 
         // stale gen is initialized to 0 and updated every compaction
         let stale_gen = Arc::new(AtomicU64::new(0));
+        let pins = Arc::new(Pins::default());
+        let versions = Arc::new(Versions::new(Arc::clone(&dirpath), Arc::clone(&pins)));
 
         let kv_reader = KvStoreReadHalf::new(
             Arc::clone(&dirpath),
             Arc::clone(&database),
             Arc::clone(&stale_gen),
+            lock,
+            options,
         );
 
-        let kv_writer = KvStoreWriteHalf::new(
-            Arc::clone(&dirpath),
-            cur_gen,
-            Arc::clone(&stale_gen),
-            Arc::clone(&database),
-            writer,
-            uncompacted,
-        );
-
-        let pool = P::new(capacity)?;
+        let (write_half, compressor) = if read_only {
+            // a writer in another process may be appending to the
+            // newest logfile, which so can't count as sealed
+            kv_reader.readers.activate(newest_gen.unwrap_or(cur_gen));
+            kv_reader.readers.hold(&gen_list)?;
+            let compressor = Compressor::new(options.compression, options.compression_threshold);
+            (None, Arc::new(compressor))
+        } else {
+            // the writer reads current values through the same file handles
+            let kv_writer = KvStoreWriteHalf::new(
+                kv_reader.clone(),
+                cur_gen,
+                Arc::clone(&pins),
+                Arc::clone(&versions),
+                uncompacted,
+                last_seq,
+                options,
+            )?;
+            let compressor = Arc::clone(&kv_writer.compressor);
+            (Some(Arc::new(Mutex::new(kv_writer))), compressor)
+        };
+        let pool = P::new(options.pool_capacity)?;
 
         Ok(Self {
             // dirpath,
             // database,
             read_half: kv_reader,
-            write_half: Arc::new(Mutex::new(kv_writer)),
+            write_half,
+            versions,
+            compressor,
+            commits: Arc::new(CommitQueue::default()),
             pool,
         })
     }
+
+    /// replace the store at path with a copy of checkpoint, taken
+    /// with [checkpoint](KvsEngine::checkpoint), and open it with
+    /// options. Fail with [KVErrorKind::DirectoryLocked] if a store
+    /// has path open
+    pub fn restore(
+        checkpoint: impl AsRef<Path>,
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+    ) -> Result<Self> {
+        let dirpath = path.into();
+        fs::create_dir_all(&dirpath)?;
+        {
+            let _lock = DirLock::exclusive(&dirpath)?;
+            restore_checkpoint(checkpoint.as_ref(), &dirpath)?;
+        }
+        Self::open_with_options(dirpath, options)
+    }
+
+    /// replay the records archived in archive up to point into a new
+    /// store at path, which must be empty, and open it with options.
+    /// Fail if the archive starts after point. See
+    /// [KvStoreOptions::archive_dir]
+    pub fn restore_archive(
+        archive: impl AsRef<Path>,
+        path: impl Into<PathBuf>,
+        point: RestorePoint,
+        options: &KvStoreOptions,
+    ) -> Result<Self> {
+        let dirpath = path.into();
+        create_empty_dir(&dirpath)?;
+        {
+            let _lock = DirLock::exclusive(&dirpath)?;
+            replay_archive(archive.as_ref(), &dirpath, point)?;
+        }
+        Self::open_with_options(dirpath, options)
+    }
+
+    /// statistics of the values written and read
+    /// since the store was opened
+    pub fn value_stats(&self) -> KvStoreStats {
+        let (value_bytes, stored_value_bytes) = self.compressor.counts();
+        let (cache_hits, cache_misses) = self.read_half.cache.counts();
+        KvStoreStats {
+            value_bytes,
+            stored_value_bytes,
+            cache_hits,
+            cache_misses,
+        }
+    }
+
+    // the writer, unless the store is read-only
+    fn write_half(&self) -> Result<Arc<Mutex<KvStoreWriteHalf>>> {
+        match &self.write_half {
+            Some(write_half) => Ok(Arc::clone(write_half)),
+            None => Err(KVErrorKind::ReadOnly.into()),
+        }
+    }
+
+    // queue a set or remove for the next group commit, and
+    // lead that commit unless another thread gets to it first
+    async fn queue_write(&self, op: Ops, expire_at: u64) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        let write_half = self.write_half()?;
+        let commits = Arc::clone(&self.commits);
+        let pool = self.pool.clone();
+        self.pool.spawn(move || {
+            let mut done = commits.push(op, expire_at);
+            let compaction = match write_half.lock() {
+                Ok(mut writer) => writer.commit_group(commits.take()),
+                // an earlier commit panicked halfway, so fail the
+                // queued writes rather than commit them after it
+                Err(_) => {
+                    drop(commits.take());
+                    None
+                }
+            };
+            // release our handle on the writer before answering, so that
+            // dropping the store afterwards always happens on the caller side
+            drop(write_half);
+            spawn_compaction(&pool, compaction);
+            // whichever commit took the write has answered by the time
+            // the writer is released, unless it panicked on the way
+            let res = done
+                .try_recv()
+                .unwrap_or_else(|_| Err(KVErrorKind::ThreadPanic.into()));
+            if sender.send(res).is_err() {
+                error!("Receiving End is dropped");
+            }
+        });
+
+        match receiver.await {
+            Ok(r) => r,
+            Err(err) => Err(KVError::from(err)),
+        }
+    }
+
+    // merge into the value of key under the writer, returning the result
+    async fn merge_value(&self, key: Vec<u8>, merge: Merge) -> Result<Option<Vec<u8>>> {
+        let (sender, receiver) = oneshot::channel();
+        let write_half = self.write_half()?;
+        let pool = self.pool.clone();
+
+        self.pool.spawn(move || {
+            let res = write_half.lock().unwrap().merge(key, &merge);
+            drop(write_half);
+            let res = res.map(|(merged, compaction)| {
+                spawn_compaction(&pool, compaction);
+                merged
+            });
+            if sender.send(res).is_err() {
+                error!("Receiving End is dropped");
+            }
+        });
+
+        match receiver.await {
+            Ok(r) => r,
+            Err(err) => Err(KVError::from(err)),
+        }
+    }
 }
 
 #[async_trait::async_trait]
 impl<P: ThreadPool> KvsEngine for KvStore<P> {
-    async fn get(&self, key: String) -> Result<Option<String>> {
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         let (sender, receiver) = oneshot::channel();
         let read_half = self.read_half.clone();
 
@@ -122,6 +357,111 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         // end to know whether it has received result from the working thread
         self.pool.spawn(move || {
             let res = read_half.get(key);
+            // read_half holds the directory lock, which must be
+            // released by the time the last handle is dropped
+            drop(read_half);
+            if sender.send(res).is_err() {
+                error!("Receiving End is dropped");
+            }
+        });
+
+        match receiver.await {
+            Ok(r) => r,
+            Err(err) => Err(KVError::from(err)),
+        }
+    }
+
+    async fn set(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.queue_write(Ops::set(key, val), 0).await
+    }
+
+    async fn set_with_ttl(&self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expire_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.queue_write(Ops::set(key, val), expire_at).await
+    }
+
+    async fn remove(&self, key: Vec<u8>) -> Result<()> {
+        self.queue_write(Ops::rm(key), 0).await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        let (sender, receiver) = oneshot::channel();
+        let write_half = self.write_half()?;
+        let pool = self.pool.clone();
+
+        self.pool.spawn(move || {
+            let res = write_half
+                .lock()
+                .unwrap()
+                .compare_and_swap(key, expected, new);
+            drop(write_half);
+            let res = res.map(|(swapped, compaction)| {
+                spawn_compaction(&pool, compaction);
+                swapped
+            });
+            if sender.send(res).is_err() {
+                error!("Receiving End is dropped");
+            }
+        });
+
+        match receiver.await {
+            Ok(r) => r,
+            Err(err) => Err(KVError::from(err)),
+        }
+    }
+
+    async fn incr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let merged = self.merge_value(key, Merge::IncrBy(delta)).await?;
+        parse_counter(merged.as_deref())
+    }
+
+    async fn append(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<()> {
+        self.merge_value(key, Merge::Append(suffix)).await?;
+        Ok(())
+    }
+
+    async fn merge(&self, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        self.merge_value(key, Merge::Operand(operand)).await?;
+        Ok(())
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        let write_half = self.write_half()?;
+        let pool = self.pool.clone();
+
+        self.pool.spawn(move || {
+            let res = write_half.lock().unwrap().write_batch(batch);
+            drop(write_half);
+            let res = res.map(|compaction| spawn_compaction(&pool, compaction));
+            if sender.send(res).is_err() {
+                error!("Receiving End is dropped");
+            }
+        });
+
+        match receiver.await {
+            Ok(r) => r,
+            Err(err) => Err(KVError::from(err)),
+        }
+    }
+
+    async fn scan(
+        &self,
+        range: KeyRange,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let (sender, receiver) = oneshot::channel();
+        let read_half = self.read_half.clone();
+
+        self.pool.spawn(move || {
+            let res = read_half.scan(range, limit, order);
+            drop(read_half);
             if sender.send(res).is_err() {
                 error!("Receiving End is dropped");
             }
@@ -133,11 +473,87 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         }
     }
 
-    async fn set(&self, key: String, val: String) -> Result<()> {
+    async fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan(prefix_range(prefix), limit, order).await
+    }
+
+    type Snapshot = KvStoreSnapshot<P>;
+
+    async fn snapshot(&self) -> Result<Self::Snapshot> {
         let (sender, receiver) = oneshot::channel();
         let write_half = self.write_half.clone();
+        let reader = self.read_half.clone();
+        let versions = Arc::clone(&self.versions);
+        let pool = self.pool.clone();
+
+        self.pool.spawn(move || {
+            // a read-only store never writes, so
+            // any version sees the same data
+            let version = match &write_half {
+                Some(write_half) => {
+                    let writer = write_half.lock().unwrap();
+                    versions.begin(writer.seq);
+                    writer.seq
+                }
+                None => {
+                    versions.begin(0);
+                    0
+                }
+            };
+            drop(write_half);
+            let snapshot = KvStoreSnapshot::new(reader, version, versions, pool);
+            if sender.send(Ok(snapshot)).is_err() {
+                error!("Receiving End is dropped");
+            }
+        });
+
+        match receiver.await {
+            Ok(r) => r,
+            Err(err) => Err(KVError::from(err)),
+        }
+    }
+
+    type Transaction = KvStoreTransaction<P>;
+
+    async fn begin(&self) -> Result<Self::Transaction> {
+        let (sender, receiver) = oneshot::channel();
+        let write_half = self.write_half()?;
+        let reader = self.read_half.clone();
+        let versions = Arc::clone(&self.versions);
+        let pool = self.pool.clone();
+
+        self.pool.spawn(move || {
+            // holding the writer, the last record written is also the
+            // last applied to the index, and any later write is recorded
+            let version = {
+                let writer = write_half.lock().unwrap();
+                versions.begin(writer.seq);
+                writer.seq
+            };
+            let txn = KvStoreTransaction::new(reader, version, versions, write_half, pool);
+            if sender.send(Ok(txn)).is_err() {
+                error!("Receiving End is dropped");
+            }
+        });
+
+        match receiver.await {
+            Ok(r) => r,
+            Err(err) => Err(KVError::from(err)),
+        }
+    }
+
+    async fn checkpoint(&self, dest: PathBuf) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        let write_half = self.write_half()?;
+
         self.pool.spawn(move || {
-            let res = write_half.lock().unwrap().set(key, val);
+            let res = checkpoint(&write_half, &dest);
+            drop(write_half);
             if sender.send(res).is_err() {
                 error!("Receiving End is dropped");
             }
@@ -149,12 +565,14 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         }
     }
 
-    async fn remove(&self, key: String) -> Result<()> {
+    async fn stats(&self) -> Result<EngineStats> {
         let (sender, receiver) = oneshot::channel();
+        let read_half = self.read_half.clone();
         let write_half = self.write_half.clone();
 
         self.pool.spawn(move || {
-            let res = write_half.lock().unwrap().remove(key);
+            let res = engine_stats(&read_half, write_half.as_deref());
+            drop((read_half, write_half));
             if sender.send(res).is_err() {
                 error!("Receiving End is dropped");
             }
@@ -166,258 +584,881 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         }
     }
 }
+
+// report on the store. A read-only store has no writer,
+// and doesn't know what it could compact
+fn engine_stats(
+    read_half: &KvStoreReadHalf,
+    write_half: Option<&Mutex<KvStoreWriteHalf>>,
+) -> Result<EngineStats> {
+    let mut stats = EngineStats::default();
+    if let Some(write_half) = write_half {
+        let writer = write_half.lock().unwrap();
+        stats.uncompacted_bytes = Some(writer.uncompacted);
+        stats.last_compaction = writer.compaction.last_compaction();
+    }
+
+    let now = now_millis();
+    for (_, cmd_pos) in read_half.database.iter() {
+        if !cmd_pos.is_expired(now) {
+            stats.key_count += 1;
+            stats.live_bytes += cmd_pos.len;
+        }
+    }
+
+    let gen_list = sorted_gen_list(&read_half.dirpath)?;
+    stats.generations = Some(gen_list.len() as u64);
+    for gen in gen_list {
+        // a compaction may have removed the files since they were listed
+        for path in [
+            log_path(&read_half.dirpath, gen),
+            hint_path(&read_half.dirpath, gen),
+        ] {
+            stats.disk_bytes += fs::metadata(path).map_or(0, |metadata| metadata.len());
+        }
+    }
+    Ok(stats)
+}
+
+// copy the store into dest while writes go on. Compactions are
+// paused throughout, so no logfile goes away before it is copied
+fn checkpoint(write_half: &Mutex<KvStoreWriteHalf>, dest: &Path) -> Result<()> {
+    // pausing waits for a running compaction to
+    // finish, which the writer must not be held for
+    let compaction = write_half.lock().unwrap().compaction.clone();
+    let _pause = compaction.pause();
+    let (dirpath, active_gen, active_len) = {
+        let mut writer = write_half.lock().unwrap();
+        writer.writer.flush()?;
+        (
+            Arc::clone(&writer.dirpath),
+            writer.cur_gen,
+            writer.writer.pos,
+        )
+    };
+    write_checkpoint(&dirpath, dest, active_gen, active_len)
+}
+
+// run a compaction started by the writer in the background
+pub(super) fn spawn_compaction<P: ThreadPool>(pool: &P, compaction: Option<Compaction>) {
+    if let Some(compaction) = compaction {
+        pool.spawn(move || compaction.run());
+    }
+}
+
+// the logfile the periodic sync fsyncs, kept apart from the
+// writer so that the sync thread never holds on to the writer,
+// and in particular never drops it
 #[derive(Debug)]
-struct KvStoreReadHalf {
+struct SyncTarget {
+    file: Mutex<File>,
+    // whether file has writes not fsynced yet
+    unsynced: AtomicBool,
+}
+
+// fsync the writer's logfile every interval on a thread of its
+// own, until the writer is dropped
+fn spawn_periodic_sync(target: Weak<SyncTarget>, interval: Duration) -> Result<()> {
+    thread::Builder::new()
+        .name(String::from("kvs-sync"))
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let target = match target.upgrade() {
+                Some(target) => target,
+                None => return,
+            };
+            if target.unsynced.swap(false, Ordering::SeqCst) {
+                if let Err(err) = target.file.lock().unwrap().sync_data() {
+                    error!("Periodic sync failed: {}", err);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct KvStoreReadHalf {
     // the biggest stale generation number
     // readers that reads generation less than this number
     // can be safely dropped
     stale_gen: Arc<AtomicU64>,
     // working directory
     dirpath: Arc<PathBuf>,
-    // this is strange... Mutex is needed to make KvStoreReadHalf a Sync Type,
-    // which is needed because async functions capture a reference to it.
-    // However, we don't really share a KvStoreReadHalf across threads
-    readers: Mutex<BTreeMap<u64, PositionedBufReader<File>>>,
-    database: Arc<Mutex<BTreeMap<String, CommandPos>>>,
-}
-
-impl Clone for KvStoreReadHalf {
-    fn clone(&self) -> KvStoreReadHalf {
-        KvStoreReadHalf {
-            stale_gen: Arc::clone(&self.stale_gen),
-            dirpath: Arc::clone(&self.dirpath),
-            // readers are not cloned
-            readers: Mutex::new(BTreeMap::new()),
-            database: Arc::clone(&self.database),
-        }
-    }
+    // logfile handles, shared by all the clones
+    readers: Arc<LogReaders>,
+    // values of hot keys, shared by all the clones
+    cache: Arc<ValueCache>,
+    database: Arc<Index>,
+    // held by the store, the writer and whatever works on their
+    // behalf, so the directory stays locked until they are all done
+    _lock: Arc<DirLock>,
 }
 
 impl KvStoreReadHalf {
     fn new(
         dirpath: Arc<PathBuf>,
-        database: Arc<Mutex<BTreeMap<String, CommandPos>>>,
+        database: Arc<Index>,
         stale_gen: Arc<AtomicU64>,
+        lock: Arc<DirLock>,
+        options: &KvStoreOptions,
     ) -> Self {
         Self {
+            readers: Arc::new(LogReaders::new(
+                Arc::clone(&dirpath),
+                Arc::clone(&stale_gen),
+                options.reader_cache_size,
+                options.mmap_reads,
+            )),
+            cache: Arc::new(ValueCache::new(options.value_cache_size)),
             stale_gen,
             dirpath,
-            readers: Mutex::new(BTreeMap::new()),
             database,
+            _lock: lock,
         }
     }
 
-    // delete all handles to logfiles with stale generation number
-    fn clean_stale_handle(&self) {
-        let stale_gen = self.stale_gen.load(Ordering::SeqCst);
-        let gens_to_delete: Vec<u64> = self
-            .readers
-            .lock()
-            .unwrap()
-            .iter()
-            .map(|(key, _)| *key)
-            .filter(|&gen| gen <= stale_gen)
-            .collect();
+    // read the op at cmd and verify its checksum. If the logfile is
+    // gone, a compaction retired it after cmd was looked up, and the
+    // caller may look the key up again
+    fn read_op_at_pos(&self, cmd: CommandPos) -> Result<Ops> {
+        let buf = self.readers.read(cmd.gen, cmd.pos, cmd.len)?;
+        Ok(record::decode(&buf)?.op)
+    }
+
+    /// the working directory
+    pub(super) fn dirpath(&self) -> &Path {
+        &self.dirpath
+    }
+
+    /// the index, read without taking its lock
+    pub(super) fn database(&self) -> &Index {
+        &self.database
+    }
+
+    /// the live value of key
+    pub(super) fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        loop {
+            // the index is read without taking its lock
+            let cmd = self.database.get(&key);
 
-        let mut readers = self.readers.lock().unwrap();
+            let cmd_pos = match cmd {
+                Some(cmd_pos) if !cmd_pos.is_expired(now_millis()) => cmd_pos,
+                _ => return Ok(None),
+            };
 
-        for gen in gens_to_delete {
-            readers.remove(&gen);
+            if let Some(val) = self.cache.get(&key, cmd_pos) {
+                return Ok(Some(val));
+            }
+            match self.read_op_at_pos(cmd_pos) {
+                Ok(Ops::Set { key: _, val }) => {
+                    self.cache.insert(&key, cmd_pos, &val);
+                    return Ok(Some(val));
+                }
+                Ok(_) => return Err(KVErrorKind::UnexpectedCommandType.into()),
+                // a background compaction retired the logfile after we looked
+                // the key up, by now the database points at its new location
+                Err(_) if cmd_pos.gen <= self.stale_gen.load(Ordering::SeqCst) => continue,
+                Err(err) => return Err(err),
+            }
         }
     }
 
-    fn read_op_at_pos(&self, cmd: CommandPos) -> Result<Ops> {
-        self.clean_stale_handle();
-
-        let mut readers = self.readers.lock().unwrap();
-        let gen = cmd.gen;
-        // we now lazily initialize readers
-        // so we will create a reader to a logfile
-        // if none exists for now
-        // a subtle issue here is: if the current gen we get is stale and
-        // corresponding logfile deleted, the File::open will generate an
-        // error and get propogated upward, and the user may retry it
-        let gen_reader = readers
-            .entry(gen)
-            .or_insert(PositionedBufReader::new(File::open(log_path(
-                &self.dirpath,
-                gen,
-            ))?)?);
-
-        // read and deserialize Ops
-        gen_reader.seek(SeekFrom::Start(cmd.pos))?;
-        let entry_reader = gen_reader.take(cmd.len);
-        let ops: Ops = serde_json::from_reader(entry_reader)?;
-        Ok(ops)
-    }
-
-    fn get(&self, key: String) -> Result<Option<String>> {
-        // the critical section ends here:
-        let cmd = self.database.lock().unwrap().get(&key).copied();
-
-        if let Some(cmd_pos) = cmd {
-            let ops = self.read_op_at_pos(cmd_pos)?;
-            if let Ops::Set { key: _, val } = ops {
-                Ok(Some(val))
-            } else {
-                Err(KVErrorKind::UnexpectedCommandType.into())
+    fn scan(
+        &self,
+        range: KeyRange,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+
+        // the positions are collected first and the values read
+        // afterwards. A key written in the meantime is read at its
+        // old position, or at its new one if compaction moved it
+        let now = now_millis();
+        let entries: Vec<(Vec<u8>, CommandPos)> = {
+            let live = self
+                .database
+                .range(range)
+                .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now));
+            match order {
+                ScanOrder::Forward => live.take(limit).collect(),
+                ScanOrder::Reverse => live.rev().take(limit).collect(),
+            }
+        };
+
+        let mut pairs = Vec::with_capacity(entries.len());
+        for (key, cmd_pos) in entries {
+            let val = match self.read_op_at_pos(cmd_pos) {
+                Ok(Ops::Set { key: _, val }) => Some(val),
+                Ok(_) => return Err(KVErrorKind::UnexpectedCommandType.into()),
+                // compacted away since we looked, go through
+                // get, which finds the key at its new position
+                Err(_) if cmd_pos.gen <= self.stale_gen.load(Ordering::SeqCst) => {
+                    self.get(key.clone())?
+                }
+                Err(err) => return Err(err),
+            };
+            if let Some(val) = val {
+                pairs.push((key, val));
             }
-        } else {
-            Ok(None)
         }
+        Ok(pairs)
     }
 }
 
 #[derive(Debug)]
-struct KvStoreWriteHalf {
+pub(super) struct KvStoreWriteHalf {
     dirpath: Arc<PathBuf>,
     cur_gen: u64,
     // the writer updates stale_gen to let the reader clean
     // stale file handles
     stale_gen: Arc<AtomicU64>,
     writer: PositionedBufWriter<File>,
-    database: Arc<Mutex<BTreeMap<String, CommandPos>>>,
+    // whether writer has writes not fsynced yet
+    unsynced: bool,
+    // shared with the sync thread under SyncPolicy::Interval
+    sync_target: Option<Arc<SyncTarget>>,
+    // copies the records written into the archive, if there is one
+    archiver: Option<Archiver>,
+    // reads current values for compare_and_swap
+    reader: KvStoreReadHalf,
+    database: Arc<Index>,
+    pins: Arc<Pins>,
+    versions: Arc<Versions>,
+    compressor: Arc<Compressor>,
     uncompacted: u64,
+    // sequence number of the last record written
+    seq: u64,
+    compaction: CompactionHandle,
+    // positions of the records written to cur_gen, saved
+    // as its hint file once the generation is finished
+    hints: Vec<HintEntry>,
+    compaction_threshold: u64,
+    max_file_size: u64,
+    sync_policy: SyncPolicy,
+    merge_operator: Option<MergeOperator>,
+    // set when a failed write could not be rolled back, after
+    // which the logfile may hold records nobody acknowledged
+    poisoned: bool,
 }
 
 impl KvStoreWriteHalf {
     fn new(
-        dirpath: Arc<PathBuf>,
+        reader: KvStoreReadHalf,
         cur_gen: u64,
-        stale_gen: Arc<AtomicU64>,
-        database: Arc<Mutex<BTreeMap<String, CommandPos>>>,
-        writer: PositionedBufWriter<File>,
+        pins: Arc<Pins>,
+        versions: Arc<Versions>,
         uncompacted: u64,
-    ) -> Self {
-        Self {
-            dirpath,
+        seq: u64,
+        options: &KvStoreOptions,
+    ) -> Result<Self> {
+        let writer = open_logfile(&reader.dirpath, cur_gen)?;
+        reader.readers.activate(cur_gen);
+        let sync_target = match options.sync_policy {
+            SyncPolicy::Interval(interval) => {
+                let target = Arc::new(SyncTarget {
+                    file: Mutex::new(writer.get_ref().try_clone()?),
+                    unsynced: AtomicBool::new(false),
+                });
+                spawn_periodic_sync(Arc::downgrade(&target), interval)?;
+                Some(target)
+            }
+            _ => None,
+        };
+        let archiver = match &options.archive_dir {
+            Some(archive_dir) => Some(Archiver::open(archive_dir, &reader.dirpath, cur_gen, seq)?),
+            None => None,
+        };
+        Ok(Self {
+            dirpath: Arc::clone(&reader.dirpath),
             cur_gen,
-            stale_gen,
+            stale_gen: Arc::clone(&reader.stale_gen),
             writer,
-            database,
+            unsynced: false,
+            sync_target,
+            archiver,
+            database: Arc::clone(&reader.database),
+            reader,
+            pins,
+            versions,
+            compressor: Arc::new(Compressor::new(
+                options.compression,
+                options.compression_threshold,
+            )),
             uncompacted,
-        }
+            seq,
+            compaction: CompactionHandle::new(),
+            hints: Vec::new(),
+            compaction_threshold: options.compaction_threshold,
+            max_file_size: options.max_file_size,
+            sync_policy: options.sync_policy,
+            merge_operator: options.merge_operator.clone(),
+            poisoned: false,
+        })
     }
 
-    fn write_ops(&mut self, op: &Ops) -> Result<CommandPos> {
+    fn write_ops(&mut self, op: &Ops, expire_at: u64) -> Result<CommandPos> {
         // this is the position of the current op
+        let (pos, buf_len) = self.undo_on_error(|this| {
+            this.seq += 1;
+            let buf = record::encode(this.seq, op, expire_at, &this.compressor);
+            Ok((this.append(&buf)?, buf.len()))
+        })?;
+
+        let cmd_pos = (self.cur_gen, pos, buf_len as u64, expire_at).into();
+        self.push_hint(op, cmd_pos);
+        self.maybe_roll_over()?;
+
+        Ok(cmd_pos)
+    }
+
+    // append buf to the current logfile and
+    // return the position it was written at
+    fn append(&mut self, buf: &[u8]) -> Result<u64> {
+        let pos = self.write_record(buf)?;
+        self.flush()?;
+        Ok(pos)
+    }
+
+    // write buf to the current logfile and the archive, without
+    // flushing, and return the position it was written at
+    fn write_record(&mut self, buf: &[u8]) -> Result<u64> {
+        if self.poisoned {
+            return Err(
+                io::Error::other("logfile left in an unknown state by a failed write").into(),
+            );
+        }
         let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, op)?;
-        self.writer.flush()?;
+        self.writer.write_all(buf)?;
+        if let Some(archiver) = &mut self.archiver {
+            archiver.append(buf)?;
+        }
+        Ok(pos)
+    }
 
-        let new_pos = self.writer.pos;
-        Ok((self.cur_gen, pos, new_pos - pos).into())
+    // run write, which appends records to the current logfile and the
+    // archive, and should it fail, take back the records and sequence
+    // numbers it got to from both, so that no later flush persists
+    // writes already answered with an error
+    fn undo_on_error<T>(&mut self, write: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let (pos, seq) = (self.writer.pos, self.seq);
+        let archived_len = self.archiver.as_ref().map(Archiver::len);
+        let res = write(self);
+        if res.is_err() {
+            self.seq = seq;
+            let rolled_back =
+                self.writer
+                    .truncate(pos)
+                    .map_err(KVError::from)
+                    .and_then(|_| match (&mut self.archiver, archived_len) {
+                        (Some(archiver), Some(len)) => archiver.truncate(len),
+                        _ => Ok(()),
+                    });
+            if let Err(err) = rolled_back {
+                error!(
+                    "Failed to roll back gen {} to {}, refusing further writes: {}",
+                    self.cur_gen, pos, err
+                );
+                self.poisoned = true;
+            }
+        }
+        res
     }
 
-    fn set(&mut self, key: String, val: String) -> Result<()> {
-        let op = Ops::set(key, val);
-        let cmd_pos = self.write_ops(&op)?;
+    // hand the buffered writes to the OS, and
+    // fsync them under SyncPolicy::Always
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if let Some(archiver) = &mut self.archiver {
+            archiver.flush(self.seq)?;
+        }
+        self.unsynced = true;
+        if let Some(target) = &self.sync_target {
+            target.unsynced.store(true, Ordering::SeqCst);
+        }
+        if self.sync_policy == SyncPolicy::Always {
+            self.sync()?;
+        }
+        Ok(())
+    }
 
-        if let Ops::Set { key, val: _ } = op {
-            if let Some(old_cmd) = self.database.lock().unwrap().insert(key, cmd_pos) {
-                self.uncompacted += old_cmd.len;
+    // fsync the current logfile if it has writes that aren't yet
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced {
+            self.writer.sync()?;
+            if let Some(archiver) = &mut self.archiver {
+                archiver.sync()?;
             }
+            self.unsynced = false;
         }
+        Ok(())
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+    // seal the current logfile and move on to gen. Under a policy
+    // that syncs, the sealed logfile is synced first, since the
+    // periodic sync only ever looks at the current one
+    fn switch_logfile(&mut self, gen: u64) -> Result<()> {
+        if self.sync_policy != SyncPolicy::OsManaged {
+            self.sync()?;
+        }
+        self.seal_gen();
+        self.cur_gen = gen;
+        self.writer = open_logfile(&self.dirpath, gen)?;
+        if let Some(archiver) = &mut self.archiver {
+            archiver.switch(gen)?;
         }
+        self.unsynced = false;
+        if let Some(target) = &self.sync_target {
+            *target.file.lock().unwrap() = self.writer.get_ref().try_clone()?;
+            target.unsynced.store(false, Ordering::SeqCst);
+        }
+        self.reader.readers.activate(gen);
+        Ok(())
+    }
 
+    // remember where op was written for the hint file of cur_gen
+    fn push_hint(&mut self, op: &Ops, cmd_pos: CommandPos) {
+        let (is_set, key) = match op {
+            Ops::Set { key, val: _ } => (true, key),
+            Ops::Rm { key } => (false, key),
+        };
+        self.hints.push(HintEntry {
+            is_set,
+            key: key.clone(),
+            pos: cmd_pos.pos,
+            len: cmd_pos.len,
+            expire_at: cmd_pos.expire_at,
+        });
+    }
+
+    // move on to a new logfile once the current one is full
+    fn maybe_roll_over(&mut self) -> Result<()> {
+        if self.writer.pos >= self.max_file_size {
+            self.switch_logfile(self.cur_gen + 1)?;
+        }
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let old_cmd = self.database.lock().unwrap().remove(&key);
+    // write the hint file of cur_gen, which receives no more writes
+    fn seal_gen(&mut self) {
+        let hints = std::mem::take(&mut self.hints);
+        if hints.is_empty() {
+            return;
+        }
+        // hint files only speed up opening the store,
+        // failing to write one loses nothing
+        if let Err(err) = self.writer.flush().map_err(KVError::from).and_then(|_| {
+            write_hint_file(
+                &self.dirpath,
+                self.cur_gen,
+                self.writer.pos,
+                self.seq,
+                &hints,
+            )
+        }) {
+            warn!(
+                "Failed to write hint file for gen {}: {}",
+                self.cur_gen, err
+            );
+        }
+    }
 
-        if let Some(old_cmd) = old_cmd {
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>, expire_at: u64) -> Result<Option<Compaction>> {
+        let op = Ops::set(key, val);
+        let cmd_pos = self.write_ops(&op, expire_at)?;
+
+        self.reader.cache.invalidate(op.key());
+        let mut database = self.database.lock();
+        if let Some(old_cmd) = apply_write(
+            &mut database,
+            &self.versions,
+            op.key(),
+            self.seq,
+            Some(cmd_pos),
+        ) {
             self.uncompacted += old_cmd.len;
-            let op = Ops::rm(key);
-            let _ = self.write_ops(&op)?;
+        }
+        drop(database);
+
+        self.maybe_compact()
+    }
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
+    fn remove(&mut self, key: Vec<u8>) -> Result<Option<Compaction>> {
+        {
+            let mut database = self.database.lock();
+            match database.get(&key) {
+                // an expired entry is already gone as far as readers
+                // are concerned, it only has to leave the index
+                Some(old_cmd) if old_cmd.is_expired(now_millis()) => {
+                    database.remove(&key);
+                    self.reader.cache.invalidate(&key);
+                    self.uncompacted += old_cmd.len;
+                    return Err(KVErrorKind::KeyNotFound.into());
+                }
+                Some(_) => {}
+                None => return Err(KVErrorKind::KeyNotFound.into()),
             }
+        }
 
-            Ok(())
-        } else {
-            Err(KVErrorKind::KeyNotFound.into())
-        }
-    }
-
-    fn compact(&mut self) -> Result<()> {
-        self.cur_gen += 1;
-        let compaction_gen = self.cur_gen;
-        let mut compaction_writer = open_logfile(&self.dirpath, compaction_gen)?;
-
-        let mut readers_cache = BTreeMap::new();
-
-        // copy all the data stored in the in-memory database
-        // to a new logfile, this ensures the new logfile contains
-        // all the up-to-date data and old logfiles can be deleted
-        let mut new_pos: u64 = 0;
-
-        let mut db = self.database.lock().unwrap();
-        for cmd_pos in db.values_mut() {
-            let reader = readers_cache
-                .entry(cmd_pos.gen)
-                .or_insert(PositionedBufReader::new(File::open(log_path(
-                    &self.dirpath,
-                    cmd_pos.gen,
-                ))?)?);
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-
-            let mut reader = reader.take(cmd_pos.len);
-            let length = io::copy(&mut reader, &mut compaction_writer)?;
-
-            // update in-memory database to relfect new log entry
-            *cmd_pos = (compaction_gen, new_pos, length).into();
-            new_pos += length;
-        }
-        compaction_writer.flush()?;
-        // release the lock,
-        // access of database from this point on by readers is safe
-        // because all entries now points to the new location
-        drop(db);
-
-        // now all the entries in db has been updated, we can update the stale gen
-        // to let readers cleanup
-        self.stale_gen.store(compaction_gen - 1, Ordering::SeqCst);
-
-        // delete current log files, up to this point
-        // these logfiles are replicated and can be safely deleted
-        // without risking losing data
-        let gens_to_remove: Vec<u64> = readers_cache
-            .keys()
-            .filter(|&&key| key < compaction_gen)
-            .cloned()
+        let op = Ops::rm(key);
+        let _ = self.write_ops(&op, 0)?;
+        let mut database = self.database.lock();
+        if let Some(old_cmd) = apply_write(&mut database, &self.versions, op.key(), self.seq, None)
+        {
+            self.uncompacted += old_cmd.len;
+        }
+        self.reader.cache.invalidate(op.key());
+        drop(database);
+
+        self.maybe_compact()
+    }
+
+    // write a group of queued sets and removes with a single flush, and
+    // fsync under SyncPolicy::Always, then answer each of them
+    pub(super) fn commit_group(&mut self, writes: Vec<PendingWrite>) -> Option<Compaction> {
+        if writes.is_empty() {
+            return None;
+        }
+
+        // check every remove against the index and the earlier writes
+        // of the group. A failed remove doesn't hold up the others
+        let mut accepted = Vec::with_capacity(writes.len());
+        {
+            let now = now_millis();
+            let mut database = self.database.lock();
+            let mut live_in_group: HashMap<Vec<u8>, bool> = HashMap::new();
+            for write in writes {
+                let live = match &write.op {
+                    Ops::Set { key, val: _ } => {
+                        live_in_group.insert(key.clone(), true);
+                        true
+                    }
+                    Ops::Rm { key } => {
+                        let live = match live_in_group.get(key) {
+                            Some(&live) => live,
+                            None => match database.get(key) {
+                                // an expired entry is already gone as far as
+                                // readers are concerned, it only has to leave
+                                // the index
+                                Some(cmd_pos) if cmd_pos.is_expired(now) => {
+                                    self.uncompacted += cmd_pos.len;
+                                    database.remove(key);
+                                    self.reader.cache.invalidate(key);
+                                    false
+                                }
+                                Some(_) => true,
+                                None => false,
+                            },
+                        };
+                        live_in_group.insert(key.clone(), false);
+                        live
+                    }
+                };
+                if live {
+                    accepted.push(write);
+                } else {
+                    let _ = write.done.send(Err(KVErrorKind::KeyNotFound.into()));
+                }
+            }
+        }
+
+        let cmd_positions = match self.write_group(&accepted) {
+            Ok(cmd_positions) => cmd_positions,
+            Err(err) => {
+                error!("Failed to commit {} writes: {}", accepted.len(), err);
+                for write in accepted {
+                    let _ = write.done.send(Err(err.kind().into()));
+                }
+                return None;
+            }
+        };
+
+        // readers only find the records once they are all written
+        {
+            let mut database = self.database.lock();
+            for (write, &(seq, cmd_pos)) in accepted.iter().zip(&cmd_positions) {
+                let cmd_pos = match &write.op {
+                    Ops::Set { key: _, val: _ } => Some(cmd_pos),
+                    Ops::Rm { key: _ } => None,
+                };
+                let old_cmd =
+                    apply_write(&mut database, &self.versions, write.op.key(), seq, cmd_pos);
+                if let Some(old_cmd) = old_cmd {
+                    self.uncompacted += old_cmd.len;
+                }
+                self.reader.cache.invalidate(write.op.key());
+            }
+        }
+        for (write, (_, cmd_pos)) in accepted.into_iter().zip(cmd_positions) {
+            self.push_hint(&write.op, cmd_pos);
+            let _ = write.done.send(Ok(()));
+        }
+
+        // the writes are already acknowledged, a failure to move on to
+        // another logfile is left for the next write to run into
+        match self.maybe_roll_over().and_then(|_| self.maybe_compact()) {
+            Ok(compaction) => compaction,
+            Err(err) => {
+                error!("Failed to switch logfiles: {}", err);
+                None
+            }
+        }
+    }
+
+    // write the records of a group to the current logfile, returning
+    // the sequence number and position of each of them
+    fn write_group(&mut self, writes: &[PendingWrite]) -> Result<Vec<(u64, CommandPos)>> {
+        self.undo_on_error(|this| {
+            let mut cmd_positions = Vec::with_capacity(writes.len());
+            for write in writes {
+                this.seq += 1;
+                let buf = record::encode(this.seq, &write.op, write.expire_at, &this.compressor);
+                let pos = this.write_record(&buf)?;
+                let cmd_pos = (this.cur_gen, pos, buf.len() as u64, write.expire_at).into();
+                cmd_positions.push((this.seq, cmd_pos));
+            }
+            this.flush()?;
+            Ok(cmd_positions)
+        })
+    }
+
+    // compare and swap while holding the writer, so that
+    // no other write can slip in between the two
+    fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<(
+        std::result::Result<(), CompareAndSwapError>,
+        Option<Compaction>,
+    )> {
+        let current = self.reader.get(key.clone())?;
+        if current != expected {
+            return Ok((Err(CompareAndSwapError { current }), None));
+        }
+
+        let compaction = match (current, new) {
+            (_, Some(val)) => self.set(key, val, 0)?,
+            (Some(_), None) => self.remove(key)?,
+            (None, None) => None,
+        };
+        Ok((Ok(()), compaction))
+    }
+
+    // merge while holding the writer, so that no other write can slip
+    // in between reading the value and writing the result. The result
+    // keeps the expiration time of the value it replaces
+    fn merge(
+        &mut self,
+        key: Vec<u8>,
+        merge: &Merge,
+    ) -> Result<(Option<Vec<u8>>, Option<Compaction>)> {
+        let current = self.reader.get(key.clone())?;
+        let expire_at = match current {
+            Some(_) => self
+                .database
+                .get(&key)
+                .map_or(0, |cmd_pos| cmd_pos.expire_at),
+            None => 0,
+        };
+        let merged = merge.apply(&key, current.as_deref(), self.merge_operator.as_ref())?;
+
+        let compaction = match (current, &merged) {
+            (_, Some(val)) => self.set(key, val.clone(), expire_at)?,
+            (Some(_), None) => self.remove(key)?,
+            (None, None) => None,
+        };
+        Ok((merged, compaction))
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<Option<Compaction>> {
+        if batch.is_empty() {
+            return Ok(None);
+        }
+        let ops: Vec<Ops> = batch
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, val } => Ops::set(key, val),
+                BatchOp::Remove { key } => Ops::rm(key),
+            })
+            .collect();
+
+        // check every remove before writing anything, taking
+        // the earlier ops of the batch into account
+        {
+            let now = now_millis();
+            let database = &self.database;
+            let mut live_in_batch: HashMap<&[u8], bool> = HashMap::new();
+            for op in &ops {
+                match op {
+                    Ops::Set { key, val: _ } => {
+                        live_in_batch.insert(key, true);
+                    }
+                    Ops::Rm { key } => {
+                        let live =
+                            live_in_batch
+                                .get(key.as_slice())
+                                .copied()
+                                .unwrap_or_else(|| {
+                                    database
+                                        .get(key)
+                                        .is_some_and(|cmd_pos| !cmd_pos.is_expired(now))
+                                });
+                        if !live {
+                            return Err(KVErrorKind::KeyNotFound.into());
+                        }
+                        live_in_batch.insert(key, false);
+                    }
+                }
+            }
+        }
+
+        self.append_batch(ops)
+    }
+
+    // commit the writes of a transaction started at version, unless
+    // one of its keys has been written since then
+    pub(super) fn commit(
+        &mut self,
+        version: u64,
+        writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ) -> Result<Option<Compaction>> {
+        if self.versions.conflicts(version, writes.keys()) {
+            return Err(KVErrorKind::TransactionConflict.into());
+        }
+
+        let ops = writes
+            .into_iter()
+            .map(|(key, val)| match val {
+                Some(val) => Ops::set(key, val),
+                None => Ops::rm(key),
+            })
+            .collect();
+        self.append_batch(ops)
+    }
+
+    // write ops as a single batch record and apply them to the index
+    fn append_batch(&mut self, ops: Vec<Ops>) -> Result<Option<Compaction>> {
+        let (pos, places) = self.undo_on_error(|this| {
+            this.seq += 1;
+            let (buf, places) = record::encode_batch(this.seq, &ops, &this.compressor);
+            Ok((this.append(&buf)?, places))
+        })?;
+
+        // snapshots see either none or all of the batch, since they
+        // copy the index under the lock held while applying it
+        let cmd_positions: Vec<CommandPos> = places
+            .into_iter()
+            .map(|(offset, len)| (self.cur_gen, pos + offset, len, 0).into())
             .collect();
-        for gen in gens_to_remove {
-            let logfile_path = log_path(&self.dirpath, gen);
-            fs::remove_file(logfile_path)?;
+        {
+            let mut database = self.database.lock();
+            for (op, &cmd_pos) in ops.iter().zip(&cmd_positions) {
+                let cmd_pos = match op {
+                    Ops::Set { key: _, val: _ } => Some(cmd_pos),
+                    Ops::Rm { key: _ } => None,
+                };
+                let old_cmd =
+                    apply_write(&mut database, &self.versions, op.key(), self.seq, cmd_pos);
+                if let Some(old_cmd) = old_cmd {
+                    self.uncompacted += old_cmd.len;
+                }
+                self.reader.cache.invalidate(op.key());
+            }
+        }
+
+        for (op, cmd_pos) in ops.iter().zip(cmd_positions) {
+            self.push_hint(op, cmd_pos);
         }
+        self.maybe_roll_over()?;
 
-        self.writer = compaction_writer;
+        self.maybe_compact()
+    }
+
+    // start a compaction if there are enough stale bytes and
+    // no compaction is running already. The current logfile is
+    // frozen and new writes go to a fresh one, which leaves a gap
+    // of one generation for the compaction to write into
+    fn maybe_compact(&mut self) -> Result<Option<Compaction>> {
+        if self.uncompacted <= self.compaction_threshold || !self.compaction.try_schedule() {
+            return Ok(None);
+        }
+
+        let compaction_gen = self.cur_gen + 1;
+        self.switch_logfile(self.cur_gen + 2)?;
         self.uncompacted = 0;
 
-        Ok(())
+        Ok(Some(Compaction {
+            dirpath: Arc::clone(&self.dirpath),
+            compaction_gen,
+            stale_gen: Arc::clone(&self.stale_gen),
+            database: Arc::clone(&self.database),
+            pins: Arc::clone(&self.pins),
+            readers: Arc::clone(&self.reader.readers),
+            cache: Arc::clone(&self.reader.cache),
+            handle: self.compaction.clone(),
+        }))
+    }
+}
+
+// point key at cmd_pos, or take it out of the index if it is None,
+// and return where it pointed. The open transactions learn of the
+// write at seq first, see Versions::record
+fn apply_write(
+    database: &mut IndexGuard<'_>,
+    versions: &Versions,
+    key: &[u8],
+    seq: u64,
+    cmd_pos: Option<CommandPos>,
+) -> Option<CommandPos> {
+    versions.record(key, seq, database.get(key));
+    match cmd_pos {
+        Some(cmd_pos) => database.insert(key.to_vec(), cmd_pos),
+        None => database.remove(key),
+    }
+}
+
+impl Drop for KvStoreWriteHalf {
+    fn drop(&mut self) {
+        // never leave a compaction deleting files behind the back
+        // of whoever opens the directory next
+        self.compaction.cancel_or_wait();
+        // don't leave the writes of the last interval to the OS
+        if self.sync_policy != SyncPolicy::OsManaged {
+            if let Err(err) = self.sync() {
+                warn!("Failed to sync gen {}: {}", self.cur_gen, err);
+            }
+        }
+        self.seal_gen();
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug)]
 pub(super) enum Ops {
-    Set { key: String, val: String },
+    Set { key: Vec<u8>, val: Vec<u8> },
 
-    Rm { key: String },
+    Rm { key: Vec<u8> },
 }
 
 impl Ops {
-    pub(super) fn set(key: String, val: String) -> Self {
+    pub(super) fn set(key: Vec<u8>, val: Vec<u8>) -> Self {
         Self::Set { key, val }
     }
 
-    pub(super) fn rm(key: String) -> Self {
+    pub(super) fn rm(key: Vec<u8>) -> Self {
         Self::Rm { key }
     }
+
+    pub(super) fn key(&self) -> &[u8] {
+        match self {
+            Self::Set { key, val: _ } => key,
+            Self::Rm { key } => key,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -469,6 +1510,31 @@ impl<W: Write + Seek> PositionedBufWriter<W> {
     }
 }
 
+impl PositionedBufWriter<File> {
+    /// the underlying file
+    pub fn get_ref(&self) -> &File {
+        self.writer.get_ref()
+    }
+
+    /// drop the buffered data without writing it,
+    /// and cut the file back to pos
+    pub fn truncate(&mut self, pos: u64) -> io::Result<()> {
+        let file = self.writer.get_ref().try_clone()?;
+        let discarded = std::mem::replace(&mut self.writer, BufWriter::new(file));
+        // unlike dropping it, taking the writer apart doesn't flush it
+        let _ = discarded.into_parts();
+        self.writer.get_ref().set_len(pos)?;
+        self.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+
+    /// flush buffered data and fsync the underlying file
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
 impl<W: Write + Seek> Write for PositionedBufWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
@@ -488,15 +1554,28 @@ impl<W: Write + Seek> Seek for PositionedBufWriter<W> {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(super) struct CommandPos {
     pub(super) gen: u64,
     pub(super) pos: u64,
     pub(super) len: u64,
+    // milliseconds since the unix epoch, 0 if the entry never expires
+    pub(super) expire_at: u64,
+}
+
+impl CommandPos {
+    pub(super) fn is_expired(&self, now: u64) -> bool {
+        self.expire_at != 0 && self.expire_at <= now
+    }
 }
 
-impl From<(u64, u64, u64)> for CommandPos {
-    fn from((gen, pos, len): (u64, u64, u64)) -> Self {
-        Self { gen, pos, len }
+impl From<(u64, u64, u64, u64)> for CommandPos {
+    fn from((gen, pos, len, expire_at): (u64, u64, u64, u64)) -> Self {
+        Self {
+            gen,
+            pos,
+            len,
+            expire_at,
+        }
     }
 }
@@ -0,0 +1,190 @@
+use super::kv_util::log_path;
+use crate::Result;
+use lru::LruCache;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Logfile handles shared by all the readers of a store.
+///
+/// Records are read with positional reads, which leave the file offset
+/// alone, so one handle serves any number of readers at once without
+/// seeking or locking. At most `capacity` handles are kept open, the
+/// least recently used one being closed to make room, and handles to
+/// generations up to `stale_gen` are closed once compaction has
+/// retired them.
+///
+/// With `mmap` set, sealed generations, those the writer has moved on
+/// from, are memory-mapped instead and read without a syscall. A sealed
+/// logfile never changes, so its mapping stays valid until compaction
+/// retires it. Readers hold on to the mapping they read from, which is
+/// only unmapped once the last of them is done with it. A read-only
+/// store can't tell which logfile the writer appends to, and may even
+/// cut back after a failed write, so it treats the newest one it found
+/// on open as active and keeps reading it with positional reads.
+#[derive(Debug)]
+pub(super) struct LogReaders {
+    dirpath: Arc<PathBuf>,
+    stale_gen: Arc<AtomicU64>,
+    // the generation the writer appends to
+    active_gen: AtomicU64,
+    mmap: bool,
+    state: Mutex<ReaderState>,
+}
+
+#[derive(Debug)]
+struct ReaderState {
+    handles: LruCache<u64, LogHandle>,
+    // the stale_gen handles were last closed for
+    closed_up_to: u64,
+}
+
+#[derive(Debug, Clone)]
+enum LogHandle {
+    File(Arc<File>),
+    Mapped(Arc<Mmap>),
+}
+
+impl LogReaders {
+    pub(super) fn new(
+        dirpath: Arc<PathBuf>,
+        stale_gen: Arc<AtomicU64>,
+        capacity: usize,
+        mmap: bool,
+    ) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            dirpath,
+            stale_gen,
+            active_gen: AtomicU64::new(0),
+            mmap,
+            state: Mutex::new(ReaderState {
+                handles: LruCache::new(capacity),
+                closed_up_to: 0,
+            }),
+        }
+    }
+
+    /// tell readers the writer now appends to gen,
+    /// which seals all the generations before it
+    pub(super) fn activate(&self, gen: u64) {
+        self.active_gen.store(gen, Ordering::SeqCst);
+    }
+
+    /// open the logfiles of gens now and keep them open for as long
+    /// as the readers live, however many there are. A read-only store
+    /// holds its logfiles like this, so that a writer in another process
+    /// compacting them away doesn't pull them from under its readers
+    pub(super) fn hold(&self, gens: &[u64]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(capacity) = NonZeroUsize::new(gens.len()) {
+            if capacity > state.handles.cap() {
+                state.handles.resize(capacity);
+            }
+        }
+        for &gen in gens {
+            let file = File::open(log_path(&self.dirpath, gen))?;
+            state.handles.put(gen, LogHandle::File(Arc::new(file)));
+        }
+        Ok(())
+    }
+
+    /// read the len bytes at pos in the logfile of gen
+    pub(super) fn read(&self, gen: u64, pos: u64, len: u64) -> Result<Vec<u8>> {
+        match self.handle(gen)? {
+            LogHandle::File(file) => {
+                let mut buf = vec![0; len as usize];
+                read_exact_at(&file, &mut buf, pos)?;
+                Ok(buf)
+            }
+            LogHandle::Mapped(map) => {
+                let end = pos.checked_add(len).filter(|&end| end <= map.len() as u64);
+                match end {
+                    Some(end) => Ok(map[pos as usize..end as usize].to_vec()),
+                    None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                }
+            }
+        }
+    }
+
+    /// close the handles to generations up to stale_gen, which
+    /// compaction has retired. Readers still using one keep it
+    /// until they are done
+    pub(super) fn close_stale(&self) {
+        let stale_gen = self.stale_gen.load(Ordering::SeqCst);
+        let mut state = self.state.lock().unwrap();
+        if state.closed_up_to >= stale_gen {
+            return;
+        }
+        let stale: Vec<u64> = state
+            .handles
+            .iter()
+            .map(|(&gen, _)| gen)
+            .filter(|&gen| gen <= stale_gen)
+            .collect();
+        for gen in stale {
+            state.handles.pop(&gen);
+        }
+        state.closed_up_to = stale_gen;
+    }
+
+    // a handle to the logfile of gen, opened if there is none.
+    // If the logfile is gone, compaction retired gen after the
+    // caller looked its position up, and opening it fails
+    fn handle(&self, gen: u64) -> Result<LogHandle> {
+        self.close_stale();
+        let stale_gen = self.stale_gen.load(Ordering::SeqCst);
+        let sealed = gen < self.active_gen.load(Ordering::SeqCst);
+        let mut state = self.state.lock().unwrap();
+
+        let file = match state.handles.get(&gen) {
+            Some(LogHandle::File(file)) if self.mmap && sealed => Arc::clone(file),
+            Some(handle) => return Ok(handle.clone()),
+            None => Arc::new(File::open(log_path(&self.dirpath, gen))?),
+        };
+        let handle = if self.mmap && sealed {
+            // SAFETY: sealed logfiles are never written to again, and
+            // only truncated by recovery, which cuts off the bytes past
+            // the last record any reader looks up, so the mapped bytes
+            // we read can't change under us. The logfile a writer in
+            // another process appends to is never sealed here, since a
+            // read-only store keeps the newest one active
+            let map = unsafe { Mmap::map(&*file)? };
+            LogHandle::Mapped(Arc::new(map))
+        } else {
+            LogHandle::File(file)
+        };
+        // a stale generation is read one last time, not kept around
+        if gen > stale_gen {
+            state.handles.put(gen, handle.clone());
+        }
+        Ok(handle)
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, pos)
+}
+
+// seek_read moves the offset of the handle, which
+// doesn't matter since every read gives its position
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, pos) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => {
+                buf = &mut buf[len..];
+                pos += len as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,81 @@
+//! Read-modify-writes of a single value, run by the engine itself so
+//! that no other write can come in between reading the value and
+//! writing the result.
+
+use crate::{KVErrorKind, Result};
+use std::fmt;
+use std::sync::Arc;
+
+// gets the key, its current value if it has one, and the operand
+// given to merge, and returns the new value, None to remove the key
+type MergeFn = dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>> + Send + Sync;
+
+/// A merge function registered by the user, see
+/// [KvStoreOptions::merge_operator](crate::KvStoreOptions::merge_operator)
+#[derive(Clone)]
+pub(super) struct MergeOperator(Arc<MergeFn>);
+
+impl MergeOperator {
+    pub(super) fn new(
+        operator: impl Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(operator))
+    }
+}
+
+impl fmt::Debug for MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
+
+/// A read-modify-write of the value of a key
+#[derive(Debug)]
+pub(super) enum Merge {
+    /// add to the integer the value holds as decimal text
+    IncrBy(i64),
+    /// append the bytes to the value
+    Append(Vec<u8>),
+    /// merge the operand in with the registered merge operator
+    Operand(Vec<u8>),
+}
+
+impl Merge {
+    /// the value key has once merged, given its current value,
+    /// None if it is to be removed
+    pub(super) fn apply(
+        &self,
+        key: &[u8],
+        current: Option<&[u8]>,
+        operator: Option<&MergeOperator>,
+    ) -> Result<Option<Vec<u8>>> {
+        match self {
+            Merge::IncrBy(delta) => {
+                let sum = parse_counter(current)?
+                    .checked_add(*delta)
+                    .ok_or(KVErrorKind::InvalidInteger)?;
+                Ok(Some(sum.to_string().into_bytes()))
+            }
+            Merge::Append(suffix) => {
+                let mut val = current.map_or_else(Vec::new, <[u8]>::to_vec);
+                val.extend_from_slice(suffix);
+                Ok(Some(val))
+            }
+            Merge::Operand(operand) => match operator {
+                Some(operator) => Ok((operator.0)(key, current, operand)),
+                None => Err(KVErrorKind::NoMergeOperator.into()),
+            },
+        }
+    }
+}
+
+/// the integer a counter holds, 0 if it has no value
+pub(super) fn parse_counter(val: Option<&[u8]>) -> Result<i64> {
+    match val {
+        None => Ok(0),
+        Some(val) => std::str::from_utf8(val)
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .ok_or_else(|| KVErrorKind::InvalidInteger.into()),
+    }
+}
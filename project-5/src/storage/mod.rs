@@ -1,22 +1,185 @@
+mod archive;
+mod batch;
+mod checkpoint;
+mod compaction;
+mod compression;
+mod dir_lock;
+mod group_commit;
+mod hint;
+mod index;
 pub(self) mod kv_util;
 mod kvsled;
 pub(self) mod kvstore;
+mod log_readers;
+mod merge;
+mod options;
+mod record;
+mod scan;
+mod snapshot;
+mod stats;
+mod transaction;
+mod value_cache;
 
-pub use kvsled::SledKvsEngine;
-pub use kvstore::KvStore;
+pub use archive::RestorePoint;
+pub use batch::{BatchOp, WriteBatch};
+pub use kvsled::{FlushPolicy, SledKvsEngine, SledSnapshot, SledTransaction};
+pub use kvstore::{KvStore, RecoveryMode};
+pub use options::{Compression, KvStoreOptions, SyncPolicy};
+pub(crate) use scan::prefix_range;
+pub use scan::{KeyRange, ScanOrder};
+pub use snapshot::KvStoreSnapshot;
+pub use stats::{EngineStats, KvStoreStats};
+pub use transaction::KvStoreTransaction;
 
 use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The outcome of a [compare_and_swap](KvsEngine::compare_and_swap)
+/// whose comparison failed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompareAndSwapError {
+    /// the value the key actually has, None if it has none
+    pub current: Option<Vec<u8>>,
+}
 
 /// Trait that describe the behavior
 /// of a key-value storage engine
 #[async_trait::async_trait]
 pub trait KvsEngine: Clone + Send + 'static {
-    /// get the value of the given string key
-    async fn get(&self, key: String) -> Result<Option<String>>;
+    /// read-only view returned by [snapshot](KvsEngine::snapshot)
+    type Snapshot: KvsSnapshot;
+
+    /// transaction returned by [begin](KvsEngine::begin)
+    type Transaction: KvsTransaction;
+
+    /// get the value of the given key
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
 
-    /// set the value of the string key
-    async fn set(&self, key: String, val: String) -> Result<()>;
+    /// set the value of the key
+    async fn set(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()>;
+
+    /// set the value of the key, which
+    /// expires once ttl has elapsed
+    async fn set_with_ttl(&self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<()>;
 
     /// remove the value of the key
-    async fn remove(&self, key: String) -> Result<()>;
+    async fn remove(&self, key: Vec<u8>) -> Result<()>;
+
+    /// set the value of key to new, or remove it if new is None,
+    /// provided its current value is expected, None meaning no value.
+    /// Nothing is written if the comparison fails
+    async fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>>;
+
+    /// apply all the ops of batch atomically
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// get at most limit key-value pairs whose
+    /// keys fall in range, visited in order
+    async fn scan(
+        &self,
+        range: KeyRange,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// get at most limit key-value pairs whose keys
+    /// start with prefix, visited in order
+    async fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// take a read-only view of the data as it is now,
+    /// unaffected by the writes that follow
+    async fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// start a transaction reading the data as it is now,
+    /// whose writes are applied together when it commits
+    async fn begin(&self) -> Result<Self::Transaction>;
+
+    /// copy the data as it is now into dest, a directory that is
+    /// created if needed and must be empty, while writes go on.
+    /// The copy opens as a store of the same engine
+    async fn checkpoint(&self, dest: PathBuf) -> Result<()>;
+
+    /// add delta to the integer the value of key holds as decimal
+    /// text, no value counting as 0, and return the sum. Fail with
+    /// [InvalidInteger](crate::KVErrorKind::InvalidInteger) if the
+    /// value is not an integer or the sum overflows
+    async fn incr_by(&self, key: Vec<u8>, delta: i64) -> Result<i64>;
+
+    /// append suffix to the value of key, no value counting as empty
+    async fn append(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<()>;
+
+    /// merge operand into the value of key with the merge operator
+    /// the engine was opened with, failing with
+    /// [NoMergeOperator](crate::KVErrorKind::NoMergeOperator) if there
+    /// is none. Like the other merges, it reads the value and writes
+    /// the result with no other write in between, and keeps the
+    /// expiration time of the key
+    async fn merge(&self, key: Vec<u8>, operand: Vec<u8>) -> Result<()>;
+
+    /// report how many keys the store holds, how much
+    /// space they take up and how it compacts
+    async fn stats(&self) -> Result<EngineStats>;
+}
+
+/// Trait that describe the behavior of a read-only,
+/// point-in-time view of a key-value storage engine
+#[async_trait::async_trait]
+pub trait KvsSnapshot: Clone + Send + 'static {
+    /// get the value of the given key
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// get at most limit key-value pairs whose
+    /// keys fall in range, visited in order
+    async fn scan(
+        &self,
+        range: KeyRange,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// get at most limit key-value pairs whose keys
+    /// start with prefix, visited in order
+    async fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+}
+
+/// Trait that describe the behavior of a read-write transaction
+/// with snapshot isolation on a key-value storage engine.
+///
+/// Writes are not visible outside the transaction until it commits.
+/// Dropping a transaction without committing rolls it back.
+#[async_trait::async_trait]
+pub trait KvsTransaction: Send + 'static {
+    /// get the value of the given key, as seen by the transaction
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// set the value of the key
+    async fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()>;
+
+    /// remove the value of the key
+    async fn remove(&mut self, key: Vec<u8>) -> Result<()>;
+
+    /// apply the writes of the transaction atomically, failing with
+    /// [TransactionConflict](crate::KVErrorKind::TransactionConflict)
+    /// if another write changed one of the written keys since it began
+    async fn commit(self) -> Result<()>;
+
+    /// discard the writes of the transaction
+    async fn rollback(self) -> Result<()>;
 }
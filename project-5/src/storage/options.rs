@@ -0,0 +1,238 @@
+use super::kvstore::{KvStore, RecoveryMode};
+use super::merge::MergeOperator;
+use crate::thread_pool::ThreadPool;
+use crate::Result;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+// try to compact log under 2MB threshold
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 2 * 1024 * 1024;
+const DEFAULT_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_READER_CACHE_SIZE: usize = 32;
+const DEFAULT_POOL_CAPACITY: i32 = 5;
+// values smaller than this rarely shrink enough to be worth it
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+
+/// Controls when a [KvStore] asks the OS to persist its writes.
+///
+/// Whatever the policy, a compaction persists the logfile it writes
+/// before removing the ones it replaces, so it never loses data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// fsync the logfile after every write before it is acknowledged
+    Always,
+    /// fsync the logfile from a background thread at this interval,
+    /// so a crash loses at most the writes of the last interval
+    Interval(Duration),
+    /// hand every write to the OS and let it decide when
+    /// to persist it
+    #[default]
+    OsManaged,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    /// parse `always`, `os` or an interval such as `100ms`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "os" => Ok(SyncPolicy::OsManaged),
+            _ => match humantime::parse_duration(s) {
+                Ok(interval) if !interval.is_zero() => Ok(SyncPolicy::Interval(interval)),
+                _ => Err(String::from("Unknown sync policy")),
+            },
+        }
+    }
+}
+
+/// How a [KvStore] compresses the values it writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// store values as they are
+    #[default]
+    None,
+    /// LZ4, fast with a moderate ratio
+    Lz4,
+    /// zstd, slower with a better ratio
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(String::from("Unknown compression")),
+        }
+    }
+}
+
+/// Options used to open a [KvStore].
+///
+/// # Examples
+/// ```rust
+/// use kvs_project_5::{
+///     thread_pool::SharedQueueThreadPool,
+///     Compression,
+///     KvStore,
+///     KvStoreOptions,
+///     SyncPolicy,
+/// };
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let store: KvStore<SharedQueueThreadPool> = KvStoreOptions::new()
+///     .compaction_threshold(64 * 1024)
+///     .sync_policy(SyncPolicy::Always)
+///     .compression(Compression::Lz4)
+///     .pool_capacity(2)
+///     .open(temp_dir.path())
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) compaction_threshold: u64,
+    pub(super) max_file_size: u64,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) reader_cache_size: usize,
+    pub(super) value_cache_size: usize,
+    pub(super) mmap_reads: bool,
+    pub(super) pool_capacity: i32,
+    pub(super) recovery_mode: RecoveryMode,
+    pub(super) compression: Compression,
+    pub(super) compression_threshold: usize,
+    pub(super) read_only: bool,
+    pub(super) archive_dir: Option<PathBuf>,
+    pub(super) merge_operator: Option<MergeOperator>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            sync_policy: SyncPolicy::default(),
+            reader_cache_size: DEFAULT_READER_CACHE_SIZE,
+            value_cache_size: 0,
+            mmap_reads: false,
+            pool_capacity: DEFAULT_POOL_CAPACITY,
+            recovery_mode: RecoveryMode::default(),
+            compression: Compression::default(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            read_only: false,
+            archive_dir: None,
+            merge_operator: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// create options with default values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// number of stale bytes that triggers a background compaction
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// size after which the writer moves on to a new logfile
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// when writes are persisted to disk
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// maximum number of logfile handles the store keeps open for reads
+    pub fn reader_cache_size(mut self, size: usize) -> Self {
+        self.reader_cache_size = size.max(1);
+        self
+    }
+
+    /// bytes of recently read keys and values kept in memory,
+    /// 0 (the default) not caching any
+    pub fn value_cache_size(mut self, bytes: usize) -> Self {
+        self.value_cache_size = bytes;
+        self
+    }
+
+    /// whether to memory-map the logfiles the writer is done with
+    /// and read them without syscalls, instead of with positional reads
+    pub fn mmap_reads(mut self, enabled: bool) -> Self {
+        self.mmap_reads = enabled;
+        self
+    }
+
+    /// number of threads in the store's thread pool
+    pub fn pool_capacity(mut self, capacity: i32) -> Self {
+        self.pool_capacity = capacity;
+        self
+    }
+
+    /// how damaged logfiles are recovered on open
+    pub fn recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.recovery_mode = mode;
+        self
+    }
+
+    /// codec new values are compressed with. Existing records
+    /// keep the codec they were written with
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// size in bytes below which values are stored uncompressed
+    pub fn compression_threshold(mut self, bytes: usize) -> Self {
+        self.compression_threshold = bytes;
+        self
+    }
+
+    /// open the store for reads only, which may be done while a writer
+    /// has it open in another process. The store sees the data as of
+    /// opening it and keeps its logfiles open, so the writer compacting
+    /// them away doesn't affect it, but snapshots taken from it may
+    /// still fail to read. Writes fail with
+    /// [KVErrorKind::ReadOnly](crate::KVErrorKind::ReadOnly)
+    pub fn read_only(mut self, enabled: bool) -> Self {
+        self.read_only = enabled;
+        self
+    }
+
+    /// archive every record written into dir, a directory apart from
+    /// the store's, for [KvStore::restore_archive] to replay. The
+    /// archive is synced under [SyncPolicy::Always], and whenever
+    /// the writer moves on to another logfile
+    pub fn archive_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.archive_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// register the function [merge](crate::KvsEngine::merge) merges
+    /// operands with. It gets the key, its current value if it has one
+    /// and the operand, and returns the new value, None to remove the key
+    pub fn merge_operator(
+        mut self,
+        operator: impl Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    ) -> Self {
+        self.merge_operator = Some(MergeOperator::new(operator));
+        self
+    }
+
+    /// open a KvStore at path with these options
+    pub fn open<P: ThreadPool>(&self, path: impl Into<PathBuf>) -> Result<KvStore<P>> {
+        KvStore::open_with_options(path, self)
+    }
+}
@@ -0,0 +1,262 @@
+//! Binary on-disk format of a single log record.
+//!
+//! ```text
+//! +-------+---------+----+-----+---------+---------+-----------+-------+-----+-----+
+//! | crc32 | version | op | seq | key_len | val_len | expire_at | codec | key | val |
+//! +-------+---------+----+-----+---------+---------+-----------+-------+-----+-----+
+//!     4        1      1     8       4         4          8         1
+//! ```
+//!
+//! All integers are little-endian. The crc32 covers every byte
+//! after itself, so a flipped bit anywhere in the record is detected
+//! when it is decoded. `expire_at` is in milliseconds since the unix
+//! epoch, 0 meaning never. `codec` tells how `val` is compressed, and
+//! `val_len` is its compressed length. Version 1 records, written
+//! before keys could expire, lack `expire_at` and `codec`, and
+//! version 2 records lack `codec`. Both are still readable.
+//!
+//! A write batch is a single record with no key, whose value is the
+//! records of its ops one after another. Its checksum covers them all,
+//! so a batch torn by a crash is dropped as a whole.
+
+use super::compression::{decompress, Compressor, CODEC_NONE};
+use super::kvstore::Ops;
+use crate::{KVError, KVErrorKind, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::Read;
+
+/// version of the record layout written by this crate
+pub(super) const RECORD_VERSION: u8 = 3;
+/// length of the record header written by this crate
+pub(super) const HEADER_LEN: usize = 31;
+// length of a version 2 header, the first to have expire_at
+const V2_HEADER_LEN: usize = 30;
+// length of a version 1 header, which is also the
+// prefix shared by all versions
+const V1_HEADER_LEN: usize = 22;
+
+const OP_SET: u8 = 1;
+const OP_RM: u8 = 2;
+const OP_BATCH: u8 = 3;
+
+/// A decoded log record
+#[derive(Debug)]
+pub(super) struct Record {
+    pub(super) seq: u64,
+    pub(super) expire_at: u64,
+    pub(super) op: Ops,
+}
+
+// length of the header of a record of given version
+fn header_len_of(version: u8) -> Option<usize> {
+    match version {
+        1 => Some(V1_HEADER_LEN),
+        2 => Some(V2_HEADER_LEN),
+        RECORD_VERSION => Some(HEADER_LEN),
+        _ => None,
+    }
+}
+
+/// serialize an op with the given sequence number and expiration
+/// time into a record, its value compressed by compressor
+pub(super) fn encode(seq: u64, op: &Ops, expire_at: u64, compressor: &Compressor) -> Vec<u8> {
+    match op {
+        Ops::Set { key, val } => {
+            let (codec, val) = compressor.compress(val);
+            frame(seq, OP_SET, codec, key, &val, expire_at)
+        }
+        Ops::Rm { key } => frame(seq, OP_RM, CODEC_NONE, key, &[], expire_at),
+    }
+}
+
+/// serialize ops into a single batch record. Return it along with
+/// the offset and length of the record of each op inside it
+pub(super) fn encode_batch(
+    seq: u64,
+    ops: &[Ops],
+    compressor: &Compressor,
+) -> (Vec<u8>, Vec<(u64, u64)>) {
+    let mut body = Vec::new();
+    let mut places = Vec::with_capacity(ops.len());
+    for op in ops {
+        let record = encode(seq, op, 0, compressor);
+        places.push(((HEADER_LEN + body.len()) as u64, record.len() as u64));
+        body.extend_from_slice(&record);
+    }
+    // the ops are compressed one by one, so each
+    // of them can still be read on its own
+    (frame(seq, OP_BATCH, CODEC_NONE, &[], &body, 0), places)
+}
+
+fn frame(seq: u64, op_type: u8, codec: u8, key: &[u8], val: &[u8], expire_at: u64) -> Vec<u8> {
+    let mut header = [0u8; HEADER_LEN];
+    header[4] = RECORD_VERSION;
+    header[5] = op_type;
+    LittleEndian::write_u64(&mut header[6..14], seq);
+    LittleEndian::write_u32(&mut header[14..18], key.len() as u32);
+    LittleEndian::write_u32(&mut header[18..22], val.len() as u32);
+    LittleEndian::write_u64(&mut header[22..30], expire_at);
+    header[30] = codec;
+
+    let mut buf = Vec::with_capacity(HEADER_LEN + key.len() + val.len());
+    buf.extend_from_slice(&header);
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(val);
+
+    let crc = crc32fast::hash(&buf[4..]);
+    LittleEndian::write_u32(&mut buf[0..4], crc);
+    buf
+}
+
+/// decode a whole record, verifying its checksum
+pub(super) fn decode(buf: &[u8]) -> Result<Record> {
+    let header_len = check(buf)?;
+
+    let seq = LittleEndian::read_u64(&buf[6..14]);
+    let key_len = LittleEndian::read_u32(&buf[14..18]) as usize;
+    let expire_at = if header_len >= V2_HEADER_LEN {
+        LittleEndian::read_u64(&buf[22..30])
+    } else {
+        0
+    };
+    let codec = if header_len >= HEADER_LEN {
+        buf[30]
+    } else {
+        CODEC_NONE
+    };
+
+    let key = buf[header_len..header_len + key_len].to_vec();
+    let op = match buf[5] {
+        OP_SET => {
+            let val = decompress(codec, &buf[header_len + key_len..])?;
+            Ops::set(key, val)
+        }
+        OP_RM => Ops::rm(key),
+        op_type => return Err(corruption(format!("unknown op type {}", op_type))),
+    };
+
+    Ok(Record { seq, expire_at, op })
+}
+
+/// decode a record read from a logfile, which may be a batch.
+/// Return the record of each op with its offset and length in buf
+pub(super) fn decode_frame(buf: &[u8]) -> Result<Vec<(u64, u64, Record)>> {
+    let header_len = check(buf)?;
+    if buf[5] != OP_BATCH {
+        return Ok(vec![(0, buf.len() as u64, decode(buf)?)]);
+    }
+
+    let mut records = Vec::new();
+    let mut offset = header_len;
+    while offset < buf.len() {
+        let rest = &buf[offset..];
+        let len = match rest.get(4).copied().and_then(header_len_of) {
+            Some(inner_header_len) if rest.len() >= inner_header_len => {
+                inner_header_len
+                    + LittleEndian::read_u32(&rest[14..18]) as usize
+                    + LittleEndian::read_u32(&rest[18..22]) as usize
+            }
+            _ => return Err(corruption(format!("malformed batch at offset {}", offset))),
+        };
+        if len > rest.len() {
+            return Err(corruption(format!("malformed batch at offset {}", offset)));
+        }
+        records.push((offset as u64, len as u64, decode(&rest[..len])?));
+        offset += len;
+    }
+    Ok(records)
+}
+
+/// the sequence number of a record read from a logfile, which
+/// may be a batch, verifying its checksum
+pub(super) fn frame_seq(buf: &[u8]) -> Result<u64> {
+    check(buf)?;
+    Ok(LittleEndian::read_u64(&buf[6..14]))
+}
+
+// verify length, checksum and version of a whole
+// record, returning the length of its header
+fn check(buf: &[u8]) -> Result<usize> {
+    if buf.len() < V1_HEADER_LEN {
+        return Err(corruption(format!(
+            "record of {} bytes is shorter than its header",
+            buf.len()
+        )));
+    }
+
+    let crc = LittleEndian::read_u32(&buf[0..4]);
+    let actual_crc = crc32fast::hash(&buf[4..]);
+    if crc != actual_crc {
+        return Err(corruption(format!(
+            "checksum mismatch: expected {:#010x}, found {:#010x}",
+            crc, actual_crc
+        )));
+    }
+
+    let version = buf[4];
+    let header_len = match header_len_of(version) {
+        Some(len) => len,
+        None => return Err(corruption(format!("unknown record version {}", version))),
+    };
+
+    let key_len = LittleEndian::read_u32(&buf[14..18]) as usize;
+    let val_len = LittleEndian::read_u32(&buf[18..22]) as usize;
+    if buf.len() != header_len + key_len + val_len {
+        return Err(corruption(format!(
+            "record length {} does not match header",
+            buf.len()
+        )));
+    }
+    Ok(header_len)
+}
+
+/// Raw bytes of a record read sequentially from a logfile
+#[derive(Debug)]
+pub(super) enum RawRecord {
+    /// all the bytes the record header claims
+    Complete(Vec<u8>),
+    /// the file ends before the record does, which is
+    /// what a write torn by a crash looks like
+    Incomplete,
+    /// the reader is at end of file
+    Eof,
+}
+
+/// read the raw bytes of the next record from reader
+pub(super) fn read_record<R: Read>(reader: &mut R) -> Result<RawRecord> {
+    let mut buf = Vec::with_capacity(HEADER_LEN);
+    reader
+        .by_ref()
+        .take(V1_HEADER_LEN as u64)
+        .read_to_end(&mut buf)?;
+    if buf.is_empty() {
+        return Ok(RawRecord::Eof);
+    }
+    if buf.len() < V1_HEADER_LEN {
+        return Ok(RawRecord::Incomplete);
+    }
+    // a record of unknown version is left for decode to reject
+    let extra_len = match header_len_of(buf[4]) {
+        Some(len) => (len - V1_HEADER_LEN) as u64,
+        None => return Ok(RawRecord::Complete(buf)),
+    };
+
+    let key_len = LittleEndian::read_u32(&buf[14..18]) as u64;
+    let val_len = LittleEndian::read_u32(&buf[18..22]) as u64;
+    let rest_len = extra_len + key_len + val_len;
+    // read through take() so a corrupted length does not
+    // make us allocate a huge buffer upfront
+    let body_len = reader.by_ref().take(rest_len).read_to_end(&mut buf)?;
+    if (body_len as u64) < rest_len {
+        return Ok(RawRecord::Incomplete);
+    }
+
+    Ok(RawRecord::Complete(buf))
+}
+
+/// an error of kind [KVErrorKind::Corruption] telling what is damaged
+pub(super) fn corruption(msg: String) -> KVError {
+    failure::err_msg(msg)
+        .context(KVErrorKind::Corruption)
+        .into()
+}
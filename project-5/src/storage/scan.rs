@@ -0,0 +1,108 @@
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// Range of keys visited by [scan](super::KvsEngine::scan)
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Order in which a scan visits keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ScanOrder {
+    /// from the smallest key up
+    #[default]
+    Forward,
+    /// from the biggest key down
+    Reverse,
+}
+
+/// range holding exactly the keys that start with prefix
+pub(crate) fn prefix_range(prefix: Vec<u8>) -> KeyRange {
+    // the first key bigger than all keys starting with prefix
+    // is prefix with its last byte bumped, dropping bytes
+    // that cannot be bumped
+    let mut end = prefix.clone();
+    let mut upper = Bound::Unbounded;
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            upper = Bound::Excluded(end);
+            break;
+        }
+    }
+    (Bound::Included(prefix), upper)
+}
+
+/// whether range holds no key at all. std's BTreeMap
+/// panics when asked for such a range
+pub(crate) fn is_empty_range(range: &KeyRange) -> bool {
+    match range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// The first limit entries of range in order, as of an earlier point.
+///
+/// live walks the current entries of range in order, and written gives
+/// what the keys in a range written since that point held then. None
+/// stands for no entry, or an expired one. written is asked once the
+/// live entries are walked, so that it also covers the keys written in
+/// the meantime, and live is walked on for as long as it takes to find
+/// limit entries.
+pub(super) fn scan_as_of<V, L, W>(
+    mut live: L,
+    written: W,
+    range: &KeyRange,
+    limit: usize,
+    order: ScanOrder,
+) -> Result<Vec<(Vec<u8>, V)>>
+where
+    L: Iterator<Item = Result<(Vec<u8>, Option<V>)>>,
+    W: Fn(&KeyRange) -> Result<Vec<(Vec<u8>, Option<V>)>>,
+{
+    let mut view = BTreeMap::new();
+    let mut found = 0;
+    while found < limit {
+        let wanted = limit - found;
+        let mut walked = 0;
+        let mut last = None;
+        for entry in live.by_ref().take(wanted) {
+            let (key, val) = entry?;
+            last = Some(key.clone());
+            view.insert(key, val);
+            walked += 1;
+        }
+
+        // the part of range walked so far
+        let walked_all = walked < wanted;
+        let covered = match (last, order) {
+            (Some(last), ScanOrder::Forward) if !walked_all => {
+                (range.0.clone(), Bound::Included(last))
+            }
+            (Some(last), ScanOrder::Reverse) if !walked_all => {
+                (Bound::Included(last), range.1.clone())
+            }
+            _ => range.clone(),
+        };
+        for (key, val) in written(&covered)? {
+            view.insert(key, val);
+        }
+
+        found = view.values().filter(|val| val.is_some()).count();
+        if walked_all {
+            break;
+        }
+    }
+
+    let entries = view
+        .into_iter()
+        .filter_map(|(key, val)| val.map(|val| (key, val)));
+    Ok(match order {
+        ScanOrder::Forward => entries.take(limit).collect(),
+        ScanOrder::Reverse => entries.rev().take(limit).collect(),
+    })
+}
@@ -0,0 +1,231 @@
+use super::kv_util::{log_path, retired_path};
+use super::kvstore::{CommandPos, KvStoreReadHalf, Ops, PositionedBufReader};
+use super::scan::{prefix_range, KeyRange, ScanOrder};
+use super::transaction::{read_at, scan_at, Versions};
+use super::{record, KvsSnapshot};
+use crate::thread_pool::ThreadPool;
+use crate::{KVError, KVErrorKind, Result};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tracing::{error, warn};
+
+/// Generations referenced by open snapshots and transactions.
+///
+/// `stale_gen` tells live readers which generations have left the
+/// index. Snapshots and transactions keep reading the positions keys
+/// had when they started, see [Versions], so a compaction asks `Pins`
+/// before deleting a stale generation: one that is still pinned is
+/// renamed to `<gen>.log.retired` instead, out of the way of the next
+/// open, and deleted once its last reader is gone.
+#[derive(Debug, Default)]
+pub(super) struct Pins {
+    state: Mutex<PinState>,
+}
+
+#[derive(Debug, Default)]
+struct PinState {
+    // number of pins on each generation
+    counts: BTreeMap<u64, usize>,
+    // pinned generations that compaction has retired
+    retired: BTreeSet<u64>,
+}
+
+impl Pins {
+    /// pin a single generation, which the caller
+    /// knows not to be deleted yet
+    pub(super) fn pin_gen(&self, gen: u64) {
+        *self.state.lock().unwrap().counts.entry(gen).or_insert(0) += 1;
+    }
+
+    /// unpin gens, deleting those retired in the
+    /// meantime that nothing else pins
+    pub(super) fn release(&self, dirpath: &Path, gens: &[u64]) {
+        let mut state = self.state.lock().unwrap();
+        for gen in gens {
+            let count = state
+                .counts
+                .get_mut(gen)
+                .expect("releasing an unpinned gen");
+            *count -= 1;
+            if *count > 0 {
+                continue;
+            }
+            state.counts.remove(gen);
+            if state.retired.remove(gen) {
+                if let Err(err) = fs::remove_file(retired_path(dirpath, *gen)) {
+                    warn!("Failed to remove retired gen {}: {}", gen, err);
+                }
+            }
+        }
+    }
+
+    /// delete the logfile of a generation compaction has made stale,
+    /// or retire it if it is still pinned
+    pub(super) fn remove_or_retire(&self, dirpath: &Path, gen: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.counts.contains_key(&gen) {
+            fs::rename(log_path(dirpath, gen), retired_path(dirpath, gen))?;
+            state.retired.insert(gen);
+        } else {
+            fs::remove_file(log_path(dirpath, gen))?;
+        }
+        Ok(())
+    }
+}
+
+/// A read-only view of a [KvStore](crate::KvStore) frozen at
+/// the time it was taken with [snapshot](super::KvsEngine::snapshot).
+///
+/// Taking a snapshot copies nothing. Like a transaction, it reads the
+/// store at the version of the last record written then, through the
+/// positions the writer keeps in [Versions] for the keys written since,
+/// whose logfiles stay alive until the snapshot is dropped. Keys expire
+/// as of the time they are read.
+#[derive(Debug, Clone)]
+pub struct KvStoreSnapshot<P: ThreadPool> {
+    inner: Arc<SnapshotInner>,
+    pool: P,
+}
+
+#[derive(Debug)]
+struct SnapshotInner {
+    reader: KvStoreReadHalf,
+    // version of the store the snapshot reads
+    version: u64,
+    versions: Arc<Versions>,
+}
+
+impl Drop for SnapshotInner {
+    fn drop(&mut self) {
+        self.versions.end(self.version);
+    }
+}
+
+impl<P: ThreadPool> KvStoreSnapshot<P> {
+    pub(super) fn new(
+        reader: KvStoreReadHalf,
+        version: u64,
+        versions: Arc<Versions>,
+        pool: P,
+    ) -> Self {
+        Self {
+            inner: Arc::new(SnapshotInner {
+                reader,
+                version,
+                versions,
+            }),
+            pool,
+        }
+    }
+
+    // run f against the snapshot on the thread pool
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&SnapshotInner) -> Result<T> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let inner = Arc::clone(&self.inner);
+
+        self.pool.spawn(move || {
+            let res = f(&inner);
+            // let go before answering, so that the caller dropping the
+            // last handle afterwards always releases the logfiles
+            drop(inner);
+            if sender.send(res).is_err() {
+                error!("Receiving End is dropped");
+            }
+        });
+
+        match receiver.await {
+            Ok(r) => r,
+            Err(err) => Err(KVError::from(err)),
+        }
+    }
+}
+
+impl SnapshotInner {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        read_at(&self.reader, &self.versions, self.version, key)
+    }
+
+    fn scan(
+        &self,
+        range: KeyRange,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        scan_at(
+            &self.reader,
+            &self.versions,
+            self.version,
+            range,
+            limit,
+            order,
+        )
+    }
+}
+
+/// read the value at cmd_pos, in a pinned generation of the store at
+/// dirpath, opening the logfile into readers unless it is there already
+pub(super) fn read_pinned(
+    dirpath: &Path,
+    readers: &mut BTreeMap<u64, PositionedBufReader<File>>,
+    cmd_pos: CommandPos,
+) -> Result<Vec<u8>> {
+    let reader = match readers.entry(cmd_pos.gen) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(PositionedBufReader::new(open_pinned_log(
+            dirpath,
+            cmd_pos.gen,
+        )?)?),
+    };
+    reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    let mut buf = vec![0; cmd_pos.len as usize];
+    reader.read_exact(&mut buf)?;
+    match record::decode(&buf)?.op {
+        Ops::Set { key: _, val } => Ok(val),
+        Ops::Rm { key: _ } => Err(KVErrorKind::UnexpectedCommandType.into()),
+    }
+}
+
+// open the logfile of a pinned gen, which a
+// compaction may have retired in the meantime
+fn open_pinned_log(dirpath: &Path, gen: u64) -> Result<File> {
+    match File::open(log_path(dirpath, gen)) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            Ok(File::open(retired_path(dirpath, gen))?)
+        }
+        res => Ok(res?),
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: ThreadPool> KvsSnapshot for KvStoreSnapshot<P> {
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.run(move |inner| inner.get(&key)).await
+    }
+
+    async fn scan(
+        &self,
+        range: KeyRange,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.run(move |inner| inner.scan(range, limit, order)).await
+    }
+
+    async fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: usize,
+        order: ScanOrder,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan(prefix_range(prefix), limit, order).await
+    }
+}
@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+/// The health of a store, returned by [stats](crate::KvsEngine::stats).
+///
+/// Fields an engine has no equivalent for are None. Sled neither keeps
+/// generations of logfiles nor says how much of its data is stale.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// keys that have a value, expired ones left out
+    pub key_count: u64,
+    /// bytes taken up by the entries of those keys, as the engine stores them
+    pub live_bytes: u64,
+    /// bytes taken up by overwritten and removed entries
+    /// that no compaction has reclaimed yet
+    pub uncompacted_bytes: Option<u64>,
+    /// bytes of the files the engine keeps its data in
+    pub disk_bytes: u64,
+    /// number of logfiles
+    pub generations: Option<u64>,
+    /// when the last compaction since the store was opened finished
+    pub last_compaction: Option<SystemTime>,
+}
+
+/// Statistics of the values of a [KvStore](crate::KvStore),
+/// returned by [KvStore::value_stats](crate::KvStore::value_stats)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KvStoreStats {
+    /// bytes of the values written since the store was opened
+    pub value_bytes: u64,
+    /// bytes those values take up in the logfiles, after compression
+    pub stored_value_bytes: u64,
+    /// gets answered from the value cache
+    pub cache_hits: u64,
+    /// gets that had to read their value from a logfile
+    /// while the value cache was enabled
+    pub cache_misses: u64,
+}
+
+impl KvStoreStats {
+    /// how many times smaller compression made the values written
+    /// since the store was opened, 1.0 if nothing was written
+    pub fn compression_ratio(&self) -> f64 {
+        if self.stored_value_bytes == 0 {
+            1.0
+        } else {
+            self.value_bytes as f64 / self.stored_value_bytes as f64
+        }
+    }
+}
@@ -0,0 +1,380 @@
+use super::kv_util::now_millis;
+use super::kvstore::{spawn_compaction, CommandPos, KvStoreReadHalf, KvStoreWriteHalf};
+use super::scan::{is_empty_range, scan_as_of, KeyRange, ScanOrder};
+use super::snapshot::{read_pinned, Pins};
+use super::KvsTransaction;
+use crate::thread_pool::ThreadPool;
+use crate::{KVError, KVErrorKind, Result};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tracing::error;
+
+/// Versions of the keys written while snapshots and transactions
+/// are open.
+///
+/// The version of a write is the sequence number of its log record.
+/// Snapshots and transactions start at the version of the last record
+/// written, and a transaction conflicts with every write to one of its
+/// keys at a later version.
+///
+/// Starting either copies nothing. Instead, the writer records where a
+/// key pointed before each write, and a key written since a snapshot or
+/// transaction started is read at that older position. Its generation
+/// is pinned, until nothing open can read it anymore. Only writes that
+/// happen while something is open matter, so nothing is recorded when
+/// there is none.
+#[derive(Debug)]
+pub(super) struct Versions {
+    dirpath: Arc<PathBuf>,
+    pins: Arc<Pins>,
+    state: Mutex<VersionState>,
+}
+
+#[derive(Debug, Default)]
+struct VersionState {
+    // number of open snapshots and transactions started at each version
+    active: BTreeMap<u64, usize>,
+    // the writes to each key, oldest first: their version, and where
+    // the key pointed until then, None if it had no value
+    history: BTreeMap<Vec<u8>, Vec<(u64, Option<CommandPos>)>>,
+}
+
+impl Versions {
+    pub(super) fn new(dirpath: Arc<PathBuf>, pins: Arc<Pins>) -> Self {
+        Self {
+            dirpath,
+            pins,
+            state: Mutex::default(),
+        }
+    }
+
+    /// register a snapshot or transaction started at version
+    pub(super) fn begin(&self, version: u64) {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .active
+            .entry(version)
+            .or_insert(0) += 1;
+    }
+
+    /// unregister a snapshot or transaction started at version, forgetting
+    /// the writes nothing open reads or conflicts with anymore
+    pub(super) fn end(&self, version: u64) {
+        let mut state = self.state.lock().unwrap();
+        let count = state
+            .active
+            .get_mut(&version)
+            .expect("ending an unknown version");
+        *count -= 1;
+        if *count > 0 {
+            return;
+        }
+        state.active.remove(&version);
+
+        // everything open sees the writes up to the oldest of them
+        let oldest = state.active.keys().next().copied();
+        let mut released = Vec::new();
+        state.history.retain(|_, writes| {
+            let seen = match oldest {
+                Some(oldest) => writes.partition_point(|&(written, _)| written <= oldest),
+                None => writes.len(),
+            };
+            released.extend(
+                writes
+                    .drain(..seen)
+                    .filter_map(|(_, previous)| previous.map(|cmd_pos| cmd_pos.gen)),
+            );
+            !writes.is_empty()
+        });
+        self.pins.release(&self.dirpath, &released);
+    }
+
+    /// record a write of key at version, key having pointed at previous
+    /// until then. The writer calls it under the index lock before
+    /// changing the index, so a transaction that finds the new position
+    /// there finds the old one here
+    pub(super) fn record(&self, key: &[u8], version: u64, previous: Option<CommandPos>) {
+        let mut state = self.state.lock().unwrap();
+        let newest = match state.active.keys().next_back() {
+            Some(&newest) => newest,
+            None => return,
+        };
+        let writes = state.history.entry(key.to_owned()).or_default();
+        // an earlier write after everything open started is
+        // already what they all read and conflict with
+        if writes.last().is_some_and(|&(written, _)| written > newest) {
+            return;
+        }
+        if let Some(previous) = previous {
+            self.pins.pin_gen(previous.gen);
+        }
+        writes.push((version, previous));
+    }
+
+    /// where key pointed at version, provided it has been written since
+    pub(super) fn before(&self, key: &[u8], version: u64) -> Option<Option<CommandPos>> {
+        let state = self.state.lock().unwrap();
+        first_after(state.history.get(key)?, version)
+    }
+
+    /// where the keys in range written since version pointed at version
+    pub(super) fn before_in(
+        &self,
+        range: &KeyRange,
+        version: u64,
+    ) -> Vec<(Vec<u8>, Option<CommandPos>)> {
+        let state = self.state.lock().unwrap();
+        state
+            .history
+            .range(range.clone())
+            .filter_map(|(key, writes)| Some((key.clone(), first_after(writes, version)?)))
+            .collect()
+    }
+
+    /// whether one of keys was written after version
+    pub(super) fn conflicts<'a>(
+        &self,
+        version: u64,
+        mut keys: impl Iterator<Item = &'a Vec<u8>>,
+    ) -> bool {
+        let state = self.state.lock().unwrap();
+        keys.any(|key| {
+            state
+                .history
+                .get(key)
+                .and_then(|writes| writes.last())
+                .is_some_and(|&(written, _)| written > version)
+        })
+    }
+}
+
+// where the key with writes pointed at version, provided
+// one of them came later
+fn first_after(writes: &[(u64, Option<CommandPos>)], version: u64) -> Option<Option<CommandPos>> {
+    writes
+        .iter()
+        .find(|&&(written, _)| written > version)
+        .map(|&(_, previous)| previous)
+}
+
+/// A read-write transaction on a [KvStore](crate::KvStore), started
+/// with [begin](super::KvsEngine::begin).
+///
+/// Reads see the store as it was when the transaction began, plus the
+/// transaction's own writes, which are kept in memory until
+/// [commit](KvsTransaction::commit). Keys expire as of the time they
+/// are read. The commit fails with
+/// [TransactionConflict](crate::KVErrorKind::TransactionConflict) if
+/// another write touched one of the written keys in the meantime.
+/// Otherwise the writes are appended as a single batch record, so a
+/// transaction is never recovered half way, and one that never committed
+/// leaves no trace in the log. Dropping the transaction rolls it back.
+///
+/// # Examples
+/// ```rust
+/// use kvs_project_5::{
+///     thread_pool::SharedQueueThreadPool,
+///     KvStore,
+///     KvsEngine,
+///     KvsTransaction,
+/// };
+/// use tempfile::TempDir;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let temp_dir = TempDir::new().unwrap();
+///     let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 2).unwrap();
+///
+///     let mut txn = store.begin().await.unwrap();
+///     txn.set(b"key".to_vec(), b"value".to_vec()).await.unwrap();
+///     assert_eq!(None, store.get(b"key".to_vec()).await.unwrap());
+///
+///     txn.commit().await.unwrap();
+///     assert_eq!(Some(b"value".to_vec()), store.get(b"key".to_vec()).await.unwrap());
+/// }
+/// ```
+#[derive(Debug)]
+pub struct KvStoreTransaction<P: ThreadPool> {
+    reader: KvStoreReadHalf,
+    // version of the store the transaction reads
+    version: u64,
+    // buffered writes, None standing for a remove
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    versions: Arc<Versions>,
+    write_half: Arc<Mutex<KvStoreWriteHalf>>,
+    pool: P,
+}
+
+impl<P: ThreadPool> KvStoreTransaction<P> {
+    pub(super) fn new(
+        reader: KvStoreReadHalf,
+        version: u64,
+        versions: Arc<Versions>,
+        write_half: Arc<Mutex<KvStoreWriteHalf>>,
+        pool: P,
+    ) -> Self {
+        Self {
+            reader,
+            version,
+            writes: BTreeMap::new(),
+            versions,
+            write_half,
+            pool,
+        }
+    }
+
+    // read key as of the version on the thread pool
+    async fn read(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let (sender, receiver) = oneshot::channel();
+        let reader = self.reader.clone();
+        let versions = Arc::clone(&self.versions);
+        let version = self.version;
+
+        self.pool.spawn(move || {
+            let res = read_at(&reader, &versions, version, &key);
+            drop((reader, versions));
+            if sender.send(res).is_err() {
+                error!("Receiving End is dropped");
+            }
+        });
+
+        match receiver.await {
+            Ok(r) => r,
+            Err(err) => Err(KVError::from(err)),
+        }
+    }
+}
+
+/// the value key had at version: the one it had before the first
+/// write since, if there is one, and its live value otherwise. The
+/// history is looked at again after reading the live value, which
+/// a write coming in between may have replaced
+pub(super) fn read_at(
+    reader: &KvStoreReadHalf,
+    versions: &Versions,
+    version: u64,
+    key: &[u8],
+) -> Result<Option<Vec<u8>>> {
+    let previous = match versions.before(key, version) {
+        Some(previous) => previous,
+        None => {
+            let live = reader.get(key.to_vec())?;
+            match versions.before(key, version) {
+                Some(previous) => previous,
+                None => return Ok(live),
+            }
+        }
+    };
+    match previous {
+        Some(cmd_pos) if !cmd_pos.is_expired(now_millis()) => {
+            read_pinned(reader.dirpath(), &mut BTreeMap::new(), cmd_pos).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// the first limit entries of range at version, in order
+pub(super) fn scan_at(
+    reader: &KvStoreReadHalf,
+    versions: &Versions,
+    version: u64,
+    range: KeyRange,
+    limit: usize,
+    order: ScanOrder,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if is_empty_range(&range) {
+        return Ok(Vec::new());
+    }
+
+    // only which keys there were is taken from the positions, the
+    // values are read like a get, which finds a key at its new
+    // position if a compaction has moved it meanwhile
+    let now = now_millis();
+    let live = reader.database().range(range.clone());
+    let live: Box<dyn Iterator<Item = (Vec<u8>, CommandPos)>> = match order {
+        ScanOrder::Forward => Box::new(live),
+        ScanOrder::Reverse => Box::new(live.rev()),
+    };
+    let unexpired =
+        |cmd_pos: Option<CommandPos>| cmd_pos.filter(|cmd_pos| !cmd_pos.is_expired(now));
+    let live = live.map(|(key, cmd_pos)| Ok((key, unexpired(Some(cmd_pos)))));
+    let written = |covered: &KeyRange| {
+        Ok(versions
+            .before_in(covered, version)
+            .into_iter()
+            .map(|(key, cmd_pos)| (key, unexpired(cmd_pos)))
+            .collect())
+    };
+    let entries = scan_as_of(live, written, &range, limit, order)?;
+
+    let mut pairs = Vec::with_capacity(entries.len());
+    for (key, _) in entries {
+        if let Some(val) = read_at(reader, versions, version, &key)? {
+            pairs.push((key, val));
+        }
+    }
+    Ok(pairs)
+}
+
+impl<P: ThreadPool> Drop for KvStoreTransaction<P> {
+    fn drop(&mut self) {
+        self.versions.end(self.version);
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: ThreadPool> KvsTransaction for KvStoreTransaction<P> {
+    async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.writes.get(&key) {
+            Some(val) => Ok(val.clone()),
+            None => self.read(key).await,
+        }
+    }
+
+    async fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.writes.insert(key, Some(val));
+        Ok(())
+    }
+
+    async fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get(key.clone()).await?.is_none() {
+            return Err(KVErrorKind::KeyNotFound.into());
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    async fn commit(mut self) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let write_half = Arc::clone(&self.write_half);
+        let pool = self.pool.clone();
+        let version = self.version;
+        let writes = std::mem::take(&mut self.writes);
+
+        self.pool.spawn(move || {
+            let res = write_half.lock().unwrap().commit(version, writes);
+            drop(write_half);
+            let res = res.map(|compaction| spawn_compaction(&pool, compaction));
+            if sender.send(res).is_err() {
+                error!("Receiving End is dropped");
+            }
+        });
+
+        match receiver.await {
+            Ok(r) => r,
+            Err(err) => Err(KVError::from(err)),
+        }
+    }
+
+    async fn rollback(self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use super::kvstore::CommandPos;
+use lru::LruCache;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Cache of recently read values, bounded by their total size.
+///
+/// A value is cached with the position of the record it was read from,
+/// and only served to a lookup that finds its key at that position.
+/// So a cached value never outlives its record, whatever order readers,
+/// the writer and compaction get to the cache in. Writes still drop the
+/// values they replace, and compaction moves the ones it copies, so that
+/// the cache doesn't fill up with values no lookup can get.
+#[derive(Debug)]
+pub(super) struct ValueCache {
+    // total bytes of keys and values cached, 0 disabling the cache
+    capacity: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    values: LruCache<Vec<u8>, (CommandPos, Vec<u8>)>,
+    size: usize,
+}
+
+impl ValueCache {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(CacheState {
+                values: LruCache::unbounded(),
+                size: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// the value of key read from the record at cmd_pos, if cached
+    pub(super) fn get(&self, key: &[u8], cmd_pos: CommandPos) -> Option<Vec<u8>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let val = match self.state.lock().unwrap().values.get(key) {
+            Some((cached_pos, val)) if *cached_pos == cmd_pos => Some(val.clone()),
+            _ => None,
+        };
+        let counter = if val.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        val
+    }
+
+    /// cache the value of key read from the record at cmd_pos,
+    /// evicting the least recently used values to make room
+    pub(super) fn insert(&self, key: &[u8], cmd_pos: CommandPos, val: &[u8]) {
+        let size = key.len() + val.len();
+        if size > self.capacity {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let entry = (cmd_pos, val.to_vec());
+        if let Some((key, (_, val))) = state.values.push(key.to_vec(), entry) {
+            state.size -= key.len() + val.len();
+        }
+        state.size += size;
+        while state.size > self.capacity {
+            match state.values.pop_lru() {
+                Some((key, (_, val))) => state.size -= key.len() + val.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// drop the value of key, which a write replaced
+    pub(super) fn invalidate(&self, key: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some((key, (_, val))) = state.values.pop_entry(key) {
+            state.size -= key.len() + val.len();
+        }
+    }
+
+    /// follow the record of key that compaction moved
+    /// from old_cmd to new_cmd
+    pub(super) fn moved(&self, key: &[u8], old_cmd: CommandPos, new_cmd: CommandPos) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some((cached_pos, _)) = self.state.lock().unwrap().values.peek_mut(key) {
+            if *cached_pos == old_cmd {
+                *cached_pos = new_cmd;
+            }
+        }
+    }
+
+    /// number of lookups that found their value cached, and
+    /// number of those that didn't
+    pub(super) fn counts(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
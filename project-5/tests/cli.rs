@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use kvs_project_5::{thread_pool::SharedQueueThreadPool, KvStore, KvStoreOptions, KvsEngine};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -6,6 +7,7 @@ use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
+use tokio::runtime::Runtime;
 
 // `kvs-client5` with no args should exit with a non-zero code.
 #[test]
@@ -78,6 +80,20 @@ fn client_cli_invalid_set() {
         .assert()
         .failure();
 
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["set", "key", "value", "--file", "value"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["set", "key", "value", "--ttl", "forever"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
     Command::cargo_bin("kvs-client5")
         .unwrap()
         .args(&["get", "key", "--unknown-flag"])
@@ -151,6 +167,32 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-server5` should reject an unknown sync policy
+#[test]
+fn server_cli_invalid_sync() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server5")
+        .unwrap()
+        .args(["--sync", "sometimes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-server5` should reject an unknown sled flush policy
+#[test]
+fn server_cli_invalid_sled_flush() {
+    let temp_dir = TempDir::new().unwrap();
+    for policy in ["sometimes", "interval=", "interval=0"] {
+        Command::cargo_bin("kvs-server5")
+            .unwrap()
+            .args(["--engine", "sled", "--sled-flush", policy])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -184,6 +226,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server did not exit");
 
         let mut cmd = Command::cargo_bin("kvs-server5").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -203,6 +246,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server did not exit");
 
         let mut cmd = Command::cargo_bin("kvs-server5").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -212,6 +256,68 @@ fn cli_wrong_engine() {
     }
 }
 
+// `kvs-server5` should refuse a directory another server is using
+#[test]
+fn cli_locked_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server5").unwrap();
+    let mut child = cmd
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4014"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-server5")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("locked"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server did not exit");
+}
+
+// `kvs-restore5` should rebuild a store from its archive up to the given point
+#[test]
+fn cli_restore_archive() {
+    let temp_dir = TempDir::new().unwrap();
+    let archive_dir = TempDir::new().unwrap();
+    let restore_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions::new().archive_dir(archive_dir.path());
+    Runtime::new().unwrap().block_on(async {
+        let store: KvStore<SharedQueueThreadPool> = options.open(temp_dir.path()).unwrap();
+        store.set(b"key".to_vec(), b"1".to_vec()).await.unwrap();
+        store.set(b"key".to_vec(), b"2".to_vec()).await.unwrap();
+    });
+
+    Command::cargo_bin("kvs-restore5")
+        .unwrap()
+        .arg(archive_dir.path())
+        .arg(restore_dir.path().join("store"))
+        .args(["--seq", "1"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-restore5")
+        .unwrap()
+        .arg(archive_dir.path())
+        .arg(restore_dir.path().join("store"))
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+
+    Runtime::new().unwrap().block_on(async {
+        let store =
+            KvStore::<SharedQueueThreadPool>::open(restore_dir.path().join("store"), 1).unwrap();
+        assert_eq!(
+            store.get(b"key".to_vec()).await.unwrap(),
+            Some(b"1".to_vec())
+        );
+    });
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -224,6 +330,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server did not exit");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -291,6 +398,165 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["--addr", addr, "scan", "--prefix", "key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2 value3\n");
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "cas",
+            "key2",
+            "--expected",
+            "value1",
+            "--new",
+            "value4",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout("value3\n")
+        .stderr(is_empty());
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "cas",
+            "key1",
+            "--expected",
+            "value1",
+            "--new",
+            "value4",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout("Key not found\n");
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "cas",
+            "key2",
+            "--expected",
+            "value3",
+            "--new",
+            "value4",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "cas",
+            "key2",
+            "--expected",
+            "value4",
+            "--new",
+            "value3",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["--addr", addr, "--hex", "set", "ff00", "c328"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["--addr", addr, "get", "ff00", "--hex"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("c328\n");
+
+    let value_path = temp_dir.path().join("value");
+    fs::write(&value_path, [0x00, 0xff, 0x0a]).unwrap();
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["--addr", addr, "set", "file-key", "--file"])
+        .arg(&value_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["--addr", addr, "get", "file-key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(&[0x00, 0xff, 0x0a, b'\n'][..]);
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["--addr", addr, "--hex", "get", "not-hex"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["--addr", addr, "incr", "counter"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["--addr", addr, "incr", "counter", "--by", "-3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-2\n");
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["--addr", addr, "incr", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not an integer"));
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["--addr", addr, "append", "counter", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client5")
+        .unwrap()
+        .args(["--addr", addr, "get", "counter"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-20\n");
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -305,6 +571,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server did not exit");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -332,7 +599,52 @@ fn cli_access_server_kvs_engine() {
 }
 
 #[test]
-#[ignore]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// A sled server flushing on an interval should keep
+// the writes flushed before it is killed
+#[test]
+fn cli_access_server_sled_flush_interval() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    for run in 0..2 {
+        let mut child = Command::cargo_bin("kvs-server5")
+            .unwrap()
+            .args([
+                "--engine",
+                "sled",
+                "--sled-flush",
+                "interval=100",
+                "--addr",
+                addr,
+            ])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        if run == 0 {
+            Command::cargo_bin("kvs-client5")
+                .unwrap()
+                .args(["--addr", addr, "set", "key1", "value1"])
+                .current_dir(&temp_dir)
+                .assert()
+                .success();
+            // leave the background thread time to flush
+            thread::sleep(Duration::from_millis(500));
+        } else {
+            Command::cargo_bin("kvs-client5")
+                .unwrap()
+                .args(["--addr", addr, "get", "key1"])
+                .current_dir(&temp_dir)
+                .assert()
+                .success()
+                .stdout("value1\n");
+        }
+
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server did not exit");
+    }
+}
@@ -1,7 +1,14 @@
 use futures::future::join_all;
 use kvs_project_5::{
-    thread_pool::RayonThreadPool, KVError as KvsError, KvStore, KvsEngine, Result,
+    thread_pool::RayonThreadPool, Compression, FlushPolicy, KVError as KvsError, KVErrorKind,
+    KvStore, KvStoreOptions, KvsEngine, KvsSnapshot, KvsTransaction, RecoveryMode, RestorePoint,
+    Result, ScanOrder, SledKvsEngine, SyncPolicy, WriteBatch,
 };
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 use tempfile::TempDir;
 use tokio::runtime::Runtime;
 use walkdir::WalkDir;
@@ -12,29 +19,17 @@ async fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    let res = store.set("key1".to_owned(), "value1".to_owned()).await?;
-    let res2 = store.set("key2".to_owned(), "value2".to_owned()).await?;
+    let res = store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    let res2 = store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
 
-    assert_eq!(
-        store.get("key1".to_owned()).await?,
-        Some("value1".to_owned())
-    );
-    assert_eq!(
-        store.get("key2".to_owned()).await?,
-        Some("value2".to_owned())
-    );
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert_eq!(
-        store.get("key1".to_owned()).await?,
-        Some("value1".to_owned())
-    );
-    assert_eq!(
-        store.get("key2".to_owned()).await?,
-        Some("value2".to_owned())
-    );
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
 
     Ok(())
 }
@@ -45,29 +40,17 @@ async fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    store.set("key1".to_owned(), "value1".to_owned()).await?;
-    assert_eq!(
-        store.get("key1".to_owned()).await?,
-        Some("value1".to_owned())
-    );
-    store.set("key1".to_owned(), "value2".to_owned()).await?;
-    assert_eq!(
-        store.get("key1".to_owned()).await?,
-        Some("value2".to_owned())
-    );
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    store.set(b"key1".to_vec(), b"value2".to_vec()).await?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert_eq!(
-        store.get("key1".to_owned()).await?,
-        Some("value2".to_owned())
-    );
-    store.set("key1".to_owned(), "value3".to_owned()).await?;
-    assert_eq!(
-        store.get("key1".to_owned()).await?,
-        Some("value3".to_owned())
-    );
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value2".to_vec()));
+    store.set(b"key1".to_vec(), b"value3".to_vec()).await?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value3".to_vec()));
 
     Ok(())
 }
@@ -78,13 +61,13 @@ async fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    store.set("key1".to_owned(), "value1".to_owned()).await?;
-    assert_eq!(store.get("key2".to_owned()).await?, None);
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert_eq!(store.get("key2".to_owned()).await?, None);
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
 
     Ok(())
 }
@@ -93,7 +76,7 @@ async fn get_non_existent_value() -> Result<()> {
 async fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert!(store.remove("key1".to_owned()).await.is_err());
+    assert!(store.remove(b"key1".to_vec()).await.is_err());
     Ok(())
 }
 
@@ -101,9 +84,9 @@ async fn remove_non_existent_key() -> Result<()> {
 async fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    store.set("key1".to_owned(), "value1".to_owned()).await?;
-    assert!(store.remove("key1".to_owned()).await.is_ok());
-    assert_eq!(store.get("key1".to_owned()).await?, None);
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    assert!(store.remove(b"key1".to_vec()).await.is_ok());
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
     Ok(())
 }
 
@@ -128,8 +111,8 @@ async fn compaction() -> Result<()> {
     let mut current_size = dir_size();
     for iter in 0..1000 {
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
+            let key = format!("key{}", key_id).into_bytes();
+            let value = format!("{}", iter).into_bytes();
             store.set(key, value).await?;
         }
 
@@ -144,8 +127,11 @@ async fn compaction() -> Result<()> {
         // reopen and check content
         let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key).await?, Some(format!("{}", iter)));
+            let key = format!("key{}", key_id).into_bytes();
+            assert_eq!(
+                store.get(key).await?,
+                Some(format!("{}", iter).into_bytes())
+            );
         }
         return Ok(());
     }
@@ -153,80 +139,2124 @@ async fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// paths of all non-empty logfiles under dir
+fn non_empty_logs(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .filter(|path| fs::metadata(path).unwrap().len() > 0)
+        .collect()
+}
+
+// Writes issued while a background compaction copies entries must win
+// over the copies once the compaction swaps its positions in
 #[tokio::test]
-async fn concurrent_set() -> Result<()> {
+async fn compaction_keeps_concurrent_writes() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
 
-    // concurrent set in 8 threads
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    let mut handles = vec![];
-    for i in 0..10000 {
-        // since we're using async-trait, the returned future from kvstore is not 'static
-        // and hence cannot be moved directly to tokio.
-        let store = store.clone();
-        let handle = tokio::spawn(async move {
+    for iter in 0..200 {
+        for key_id in 0..500 {
             store
-                .set(format!("key{}", i), format!("value{}", i))
-                .await
-                .unwrap();
-        });
-        handles.push(handle);
+                .set(
+                    format!("key{}", key_id).into_bytes(),
+                    format!("value{}-{}", key_id, iter).into_bytes(),
+                )
+                .await?;
+        }
+    }
+    for key_id in (0..500).step_by(2) {
+        store.remove(format!("key{}", key_id).into_bytes()).await?;
     }
-    join_all(handles).await;
 
-    // We only check concurrent set in this test, so we check sequentially here
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    for i in 0..10000 {
+    let check = |store: KvStore<RayonThreadPool>| async move {
+        for key_id in 0..500 {
+            let expected = if key_id % 2 == 0 {
+                None
+            } else {
+                Some(format!("value{}-199", key_id).into_bytes())
+            };
+            assert_eq!(
+                store.get(format!("key{}", key_id).into_bytes()).await?,
+                expected
+            );
+        }
+        Result::Ok(())
+    };
+
+    check(store.clone()).await?;
+    drop(store);
+    check(KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?).await?;
+    Ok(())
+}
+
+// Options should roll logfiles over and compact at the configured sizes
+#[tokio::test]
+async fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(16 * 1024)
+        .max_file_size(4 * 1024)
+        .sync_policy(SyncPolicy::Always)
+        .reader_cache_size(2)
+        .pool_capacity(2);
+    let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store
+                .set(
+                    format!("key{}", key_id).into_bytes(),
+                    format!("{}", iter).into_bytes(),
+                )
+                .await?;
+        }
+    }
+    assert!(non_empty_logs(temp_dir.path()).len() > 1);
+    for key_id in 0..100 {
         assert_eq!(
-            store.get(format!("key{}", i)).await?,
-            Some(format!("value{}", i))
+            store.get(format!("key{}", key_id).into_bytes()).await?,
+            Some(b"19".to_vec())
         );
     }
 
+    drop(store);
+    let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes()).await?,
+            Some(b"19".to_vec())
+        );
+    }
     Ok(())
 }
 
+// All the readers of a store should share a bounded set
+// of logfile handles, however many generations they read
+#[cfg(target_os = "linux")]
 #[tokio::test]
-async fn concurrent_get() -> Result<()> {
+async fn reader_handles_are_bounded() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    // We only check concurrent get in this test, so we set sequentially here
-    for i in 0..100 {
+    let store: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .max_file_size(1024)
+        .reader_cache_size(3)
+        .pool_capacity(4)
+        .open(temp_dir.path())?;
+    for key_id in 0..200 {
+        let key = format!("key{}", key_id).into_bytes();
         store
-            .set(format!("key{}", i), format!("value{}", i))
-            .await
+            .set(key, format!("value{}", key_id).into_bytes())
+            .await?;
+    }
+    assert!(non_empty_logs(temp_dir.path()).len() > 5);
+
+    let open_logs = || {
+        fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+            .filter(|path| {
+                path.starts_with(temp_dir.path()) && path.extension() == Some("log".as_ref())
+            })
+            .count()
+    };
+    let handles = (0..200).map(|key_id| {
+        let store = store.clone();
+        tokio::spawn(async move {
+            let key = format!("key{}", key_id).into_bytes();
+            let val = store.get(key).await.unwrap();
+            assert_eq!(val, Some(format!("value{}", key_id).into_bytes()));
+        })
+    });
+    for handle in join_all(handles).await {
+        handle.unwrap();
+    }
+    // the cached handles, plus the writer's
+    assert!(open_logs() <= 4);
+    Ok(())
+}
+
+// Gets should be served from the value cache until a write or
+// a compaction changes the key, and never return an old value
+#[tokio::test]
+async fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .value_cache_size(1024)
+        .compaction_threshold(4 * 1024)
+        .pool_capacity(2)
+        .open(temp_dir.path())?;
+    let cache_counts = |store: &KvStore<RayonThreadPool>| {
+        let stats = store.value_stats();
+        (stats.cache_hits, stats.cache_misses)
+    };
+
+    store.set(b"hot".to_vec(), b"1".to_vec()).await?;
+    assert_eq!(store.get(b"hot".to_vec()).await?, Some(b"1".to_vec()));
+    assert_eq!(store.get(b"hot".to_vec()).await?, Some(b"1".to_vec()));
+    assert_eq!(cache_counts(&store), (1, 1));
+
+    store.set(b"hot".to_vec(), b"2".to_vec()).await?;
+    assert_eq!(store.get(b"hot".to_vec()).await?, Some(b"2".to_vec()));
+    let mut batch = WriteBatch::new();
+    batch.set(b"hot".to_vec(), b"3".to_vec());
+    store.write_batch(batch).await?;
+    assert_eq!(store.get(b"hot".to_vec()).await?, Some(b"3".to_vec()));
+    assert_eq!(store.get(b"hot".to_vec()).await?, Some(b"3".to_vec()));
+    assert_eq!(cache_counts(&store), (2, 3));
+
+    // compaction moves the cached value along with its record
+    for iter in 0..100 {
+        let key = format!("cold{}", iter % 10).into_bytes();
+        store.set(key, vec![b'x'; 100]).await?;
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(store.get(b"hot".to_vec()).await?, Some(b"3".to_vec()));
+    store.remove(b"hot".to_vec()).await?;
+    assert_eq!(store.get(b"hot".to_vec()).await?, None);
+
+    // values that don't fit are never cached
+    store.set(b"big".to_vec(), vec![b'x'; 2048]).await?;
+    let (hits, misses) = cache_counts(&store);
+    store.get(b"big".to_vec()).await?;
+    store.get(b"big".to_vec()).await?;
+    assert_eq!(cache_counts(&store), (hits, misses + 2));
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"big".to_vec()).await?, Some(vec![b'x'; 2048]));
+    assert_eq!(cache_counts(&store), (0, 0));
+    Ok(())
+}
+
+// Reads through memory-mapped logfiles should see the same data, and
+// compaction should unmap the logfiles it removes
+#[tokio::test]
+async fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .mmap_reads(true)
+        .max_file_size(1024)
+        .compaction_threshold(8 * 1024)
+        .pool_capacity(4);
+    let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+
+    for iter in 0..20 {
+        for key_id in 0..50 {
+            let key = format!("key{}", key_id).into_bytes();
+            store.set(key, format!("{}", iter).into_bytes()).await?;
+        }
+        for key_id in 0..50 {
+            let key = format!("key{}", key_id).into_bytes();
+            assert_eq!(
+                store.get(key).await?,
+                Some(format!("{}", iter).into_bytes())
+            );
+        }
+    }
+    let pairs = store
+        .scan_prefix(b"key".to_vec(), 100, ScanOrder::Forward)
+        .await?;
+    assert_eq!(pairs.len(), 50);
+    assert!(pairs.iter().all(|(_, val)| val == b"19"));
+
+    // let the last compaction finish
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    #[cfg(target_os = "linux")]
+    {
+        let maps = fs::read_to_string("/proc/self/maps").unwrap();
+        let dir = temp_dir.path().to_str().unwrap();
+        assert!(!maps
+            .lines()
+            .any(|line| line.contains(dir) && line.ends_with("(deleted)")));
+    }
+    drop(store);
+
+    let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+    for key_id in 0..50 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(store.get(key).await?, Some(b"19".to_vec()));
+    }
+    Ok(())
+}
+
+// A store syncing at an interval should keep its writes across reopens,
+// including those in logfiles it moved on from
+#[tokio::test]
+async fn sync_at_interval() -> Result<()> {
+    assert_eq!(
+        "100ms".parse(),
+        Ok(SyncPolicy::Interval(Duration::from_millis(100)))
+    );
+    assert!("0s".parse::<SyncPolicy>().is_err());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(16 * 1024)
+        .max_file_size(4 * 1024)
+        .sync_policy(SyncPolicy::Interval(Duration::from_millis(10)))
+        .pool_capacity(2);
+
+    for iter in 0..3 {
+        let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+        for key_id in 0..200 {
+            store
+                .set(
+                    format!("key{}", key_id).into_bytes(),
+                    format!("{}", iter).into_bytes(),
+                )
+                .await?;
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+    }
+
+    let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes()).await?,
+            Some(b"2".to_vec())
+        );
+    }
+    Ok(())
+}
+
+// flip one bit of the last byte in every logfile under dir
+fn flip_last_byte(dir: &Path) {
+    flip_last_byte_of(non_empty_logs(dir));
+}
+
+// flip one bit of the last byte in each given file
+fn flip_last_byte_of(paths: Vec<PathBuf>) {
+    for path in paths {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
             .unwrap();
+        let mut byte = [0u8];
+        file.seek(SeekFrom::End(-1)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        byte[0] ^= 0x01;
+        file.seek(SeekFrom::End(-1)).unwrap();
+        file.write_all(&byte).unwrap();
     }
+}
 
-    let mut handles = vec![];
-    for thread_id in 0..100 {
-        for i in 0..100 {
-            let key_id = (i + thread_id) % 100;
-            let store = store.clone();
-            let h = tokio::spawn(async move {
-                let res = store.get(format!("key{}", key_id)).await.unwrap();
-                assert_eq!(res, Some(format!("value{}", key_id)));
-            });
-            handles.push(h);
+// A clean shutdown leaves hint files that open uses instead of the logs
+#[tokio::test]
+async fn open_from_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
+    store.remove(b"key1".to_vec()).await?;
+    store.set(b"key3".to_vec(), b"value3".to_vec()).await?;
+    drop(store);
+
+    let hint_files = || -> Vec<PathBuf> {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("hint".as_ref()))
+            .collect()
+    };
+    assert_eq!(hint_files().len(), 1);
+
+    // the logs are not replayed: a corrupted record is only noticed
+    // when it is read, not when the store is opened
+    flip_last_byte(temp_dir.path());
+    let store =
+        KvStore::<RayonThreadPool>::open_with_recovery(temp_dir.path(), 1, RecoveryMode::Strict)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+    let err = store.get(b"key3".to_vec()).await.unwrap_err();
+    assert_eq!(err.kind(), KVErrorKind::Corruption);
+    drop(store);
+
+    // a damaged hint file is ignored and the log replayed instead
+    flip_last_byte(temp_dir.path());
+    flip_last_byte_of(hint_files());
+    let store =
+        KvStore::<RayonThreadPool>::open_with_recovery(temp_dir.path(), 1, RecoveryMode::Strict)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+    assert_eq!(store.get(b"key3".to_vec()).await?, Some(b"value3".to_vec()));
+
+    Ok(())
+}
+
+// A flipped bit should be reported as corruption, not as a wrong value
+#[tokio::test]
+async fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
+
+    flip_last_byte(temp_dir.path());
+    let err = store.get(b"key2".to_vec()).await.unwrap_err();
+    assert_eq!(err.kind(), KVErrorKind::Corruption);
+
+    // strict recovery refuses to open a corrupted log, remove
+    // the hint files left by the shutdown so the log is replayed
+    drop(store);
+    for entry in fs::read_dir(temp_dir.path()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension() == Some("hint".as_ref()) {
+            fs::remove_file(path).unwrap();
         }
     }
-    join_all(handles).await;
+    let err =
+        KvStore::<RayonThreadPool>::open_with_recovery(temp_dir.path(), 1, RecoveryMode::Strict)
+            .err()
+            .expect("open should fail on a corrupted log");
+    assert_eq!(err.kind(), KVErrorKind::Corruption);
 
-    // reload from disk and test again
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    let mut handles = vec![];
-    for thread_id in 0..100 {
-        for i in 0..100 {
-            let key_id = (i + thread_id) % 100;
-            let store = store.clone();
-            let h = tokio::spawn(async move {
-                let res = store.get(format!("key{}", key_id)).await.unwrap();
-                assert_eq!(res, Some(format!("value{}", key_id)));
-            });
-            handles.push(h);
+    // default recovery drops the corrupted record
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
+
+    Ok(())
+}
+
+// An incomplete record left by a crash mid-write is cut off on open
+#[tokio::test]
+async fn recover_from_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
+    drop(store);
+
+    for path in non_empty_logs(temp_dir.path()) {
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 3).unwrap();
+    }
+
+    // a torn write is tolerated even in strict mode
+    let store =
+        KvStore::<RayonThreadPool>::open_with_recovery(temp_dir.path(), 1, RecoveryMode::Strict)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
+    store.set(b"key3".to_vec(), b"value3".to_vec()).await?;
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key3".to_vec()).await?, Some(b"value3".to_vec()));
+
+    Ok(())
+}
+
+// A damaged length in an older logfile is corruption, not a torn write
+#[tokio::test]
+async fn damaged_length_in_sealed_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
+    store.set(b"key3".to_vec(), b"value3".to_vec()).await?;
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key4".to_vec(), b"value4".to_vec()).await?;
+    drop(store);
+    for entry in fs::read_dir(temp_dir.path()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension() == Some("hint".as_ref()) {
+            fs::remove_file(path).unwrap();
         }
     }
-    join_all(handles).await;
+
+    // make the second record of 1.log claim more bytes than follow it
+    let first_log = temp_dir.path().join("1.log");
+    let mut log = fs::read(&first_log).unwrap();
+    let len_at = |at: usize| u32::from_le_bytes([log[at], log[at + 1], log[at + 2], log[at + 3]]);
+    let first_len = 31 + len_at(14) as usize + len_at(18) as usize;
+    log[first_len + 18..first_len + 22].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&first_log, &log).unwrap();
+
+    let err =
+        KvStore::<RayonThreadPool>::open_with_recovery(temp_dir.path(), 1, RecoveryMode::Strict)
+            .err()
+            .expect("open should fail on a damaged length");
+    assert_eq!(err.kind(), KVErrorKind::Corruption);
+    assert_eq!(fs::metadata(&first_log).unwrap().len(), log.len() as u64);
+
+    // recovery stops at the damage, newer logfiles included
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
+    assert_eq!(store.get(b"key3".to_vec()).await?, None);
+    assert_eq!(store.get(b"key4".to_vec()).await?, None);
+    assert_eq!(fs::metadata(&first_log).unwrap().len(), first_len as u64);
+
+    Ok(())
+}
+
+// Keys set with a ttl should disappear once it elapses, also after reopen
+#[tokio::test]
+async fn expire_keys_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    store
+        .set_with_ttl(
+            b"key1".to_vec(),
+            b"value1".to_vec(),
+            Duration::from_millis(300),
+        )
+        .await?;
+    store
+        .set_with_ttl(
+            b"key2".to_vec(),
+            b"value2".to_vec(),
+            Duration::from_secs(3600),
+        )
+        .await?;
+    store.set(b"key3".to_vec(), b"value3".to_vec()).await?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+    let err = store.remove(b"key1".to_vec()).await.unwrap_err();
+    assert_eq!(err.kind(), KVErrorKind::KeyNotFound);
+
+    // a plain set clears the ttl
+    store
+        .set_with_ttl(
+            b"key3".to_vec(),
+            b"value3".to_vec(),
+            Duration::from_millis(100),
+        )
+        .await?;
+    store.set(b"key3".to_vec(), b"value4".to_vec()).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(store.get(b"key3".to_vec()).await?, Some(b"value4".to_vec()));
+
+    // from hint files, then from the logs
+    store
+        .set_with_ttl(
+            b"key4".to_vec(),
+            b"value4".to_vec(),
+            Duration::from_millis(300),
+        )
+        .await?;
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key4".to_vec()).await?, Some(b"value4".to_vec()));
+    drop(store);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("hint".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+    assert_eq!(store.get(b"key4".to_vec()).await?, None);
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+
+    Ok(())
+}
+
+// Compaction should not copy expired keys
+#[tokio::test]
+async fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .compaction_threshold(4 * 1024)
+        .pool_capacity(2)
+        .open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store
+            .set_with_ttl(
+                format!("expiring{}", key_id).into_bytes(),
+                b"value".repeat(100),
+                Duration::from_millis(100),
+            )
+            .await?;
+    }
+    store.set(b"key".to_vec(), b"value".to_vec()).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // logfiles may be removed by the compaction while we read them
+    let logs_mention = |needle: &[u8]| {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| fs::read(entry.ok()?.path()).ok())
+            .any(|content| content.windows(needle.len()).any(|window| window == needle))
+    };
+    assert!(logs_mention(b"expiring"));
+    for iter in 0..100 {
+        store.set(b"other".to_vec(), b"x".repeat(100)).await?;
+        if !logs_mention(b"expiring") {
+            assert!(logs_mention(b"key"));
+            assert_eq!(store.get(b"key".to_vec()).await?, Some(b"value".to_vec()));
+            return Ok(());
+        }
+        if iter % 10 == 9 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    panic!("Expired keys survived compaction");
+}
+
+// Scans should list live keys in order, within range and limit
+#[tokio::test]
+async fn scan_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key in ["a1", "b1", "b2", "b3", "c1"] {
+        store
+            .set(key.into(), format!("value-{}", key).into_bytes())
+            .await?;
+    }
+    store.remove(b"b2".to_vec()).await?;
+    store
+        .set_with_ttl(b"b4".to_vec(), b"value".to_vec(), Duration::ZERO)
+        .await?;
+
+    let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<String> {
+        pairs
+            .into_iter()
+            .map(|(key, val)| {
+                let key = String::from_utf8(key).unwrap();
+                assert_eq!(val, format!("value-{}", key).into_bytes());
+                key
+            })
+            .collect()
+    };
+
+    let all = store
+        .scan((Bound::Unbounded, Bound::Unbounded), 10, ScanOrder::Forward)
+        .await?;
+    assert_eq!(keys(all), ["a1", "b1", "b3", "c1"]);
+    let range = (
+        Bound::Included(b"b1".to_vec()),
+        Bound::Excluded(b"c1".to_vec()),
+    );
+    let page = store.scan(range, 1, ScanOrder::Reverse).await?;
+    assert_eq!(keys(page), ["b3"]);
+    let empty = (
+        Bound::Excluded(b"b1".to_vec()),
+        Bound::Excluded(b"b1".to_vec()),
+    );
+    assert!(store.scan(empty, 10, ScanOrder::Forward).await?.is_empty());
+
+    let prefixed = store
+        .scan_prefix(b"b".to_vec(), 10, ScanOrder::Reverse)
+        .await?;
+    assert_eq!(keys(prefixed), ["b3", "b1"]);
+    assert!(store
+        .scan_prefix(b"d".to_vec(), 10, ScanOrder::Forward)
+        .await?
+        .is_empty());
+
+    Ok(())
+}
+
+// A batch should be applied as a whole or not at all
+#[tokio::test]
+async fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key2".to_vec(), b"value2".to_vec())
+        .remove(b"key1".to_vec())
+        .set(b"key3".to_vec(), b"value3".to_vec())
+        .remove(b"key3".to_vec());
+    store.write_batch(batch).await?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+    assert_eq!(store.get(b"key3".to_vec()).await?, None);
+
+    // a remove of a missing key fails the batch before anything is written
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key2".to_vec(), b"value4".to_vec())
+        .remove(b"key1".to_vec());
+    let err = store.write_batch(batch).await.unwrap_err();
+    assert_eq!(err.kind(), KVErrorKind::KeyNotFound);
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+    store.write_batch(WriteBatch::new()).await?;
+
+    // from hint files, then from the logs
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+    drop(store);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("hint".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+
+    Ok(())
+}
+
+// A batch torn by a crash should be dropped as a whole
+#[tokio::test]
+async fn recover_from_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    let mut batch = WriteBatch::new();
+    batch
+        .remove(b"key1".to_vec())
+        .set(b"key2".to_vec(), b"value2".to_vec())
+        .set(b"key3".to_vec(), b"value3".to_vec());
+    store.write_batch(batch).await?;
+    drop(store);
+
+    // cut into the last op, the first ones are intact on disk
+    for path in non_empty_logs(temp_dir.path()) {
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 3).unwrap();
+    }
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
+    assert_eq!(store.get(b"key3".to_vec()).await?, None);
+
+    Ok(())
+}
+
+// Compare-and-swap should only write when the current value matches
+#[tokio::test]
+async fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+
+    store
+        .compare_and_swap(b"key1".to_vec(), None, Some(b"value1".to_vec()))
+        .await?
+        .unwrap();
+    let err = store
+        .compare_and_swap(b"key1".to_vec(), None, Some(b"value2".to_vec()))
+        .await?
+        .unwrap_err();
+    assert_eq!(err.current, Some(b"value1".to_vec()));
+    store
+        .compare_and_swap(
+            b"key1".to_vec(),
+            Some(b"value1".to_vec()),
+            Some(b"value2".to_vec()),
+        )
+        .await?
+        .unwrap();
+    store
+        .compare_and_swap(b"key1".to_vec(), Some(b"value2".to_vec()), None)
+        .await?
+        .unwrap();
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+    let err = store
+        .compare_and_swap(b"key1".to_vec(), Some(b"value2".to_vec()), None)
+        .await?
+        .unwrap_err();
+    assert_eq!(err.current, None);
+
+    // an expired value compares as no value
+    store
+        .set_with_ttl(b"key2".to_vec(), b"value".to_vec(), Duration::ZERO)
+        .await?;
+    store
+        .compare_and_swap(b"key2".to_vec(), None, Some(b"value3".to_vec()))
+        .await?
+        .unwrap();
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value3".to_vec()));
+
+    // concurrent increments through compare-and-swap lose no update
+    store.set(b"counter".to_vec(), b"0".to_vec()).await?;
+    let increments = (0..8).map(|_| {
+        let store = store.clone();
+        async move {
+            for _ in 0..50 {
+                loop {
+                    let current = store.get(b"counter".to_vec()).await?;
+                    let next = std::str::from_utf8(current.as_ref().unwrap())
+                        .unwrap()
+                        .parse::<u64>()
+                        .unwrap()
+                        + 1;
+                    let swapped = store
+                        .compare_and_swap(
+                            b"counter".to_vec(),
+                            current,
+                            Some(next.to_string().into_bytes()),
+                        )
+                        .await?;
+                    if swapped.is_ok() {
+                        break;
+                    }
+                }
+            }
+            Result::Ok(())
+        }
+    });
+    for res in join_all(increments.map(tokio::spawn)).await {
+        res.unwrap()?;
+    }
+    assert_eq!(store.get(b"counter".to_vec()).await?, Some(b"400".to_vec()));
+
+    Ok(())
+}
+
+// Keys and values may hold any bytes, not only utf-8
+#[tokio::test]
+async fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = vec![0xff, 0x00, 0xfe];
+    let val: Vec<u8> = (0..=255).collect();
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(key.clone(), val.clone()).await?;
+    store.set(vec![0xff, 0xff], vec![0x80]).await?;
+    store.set(vec![0xfe], vec![0x81]).await?;
+    assert_eq!(store.get(key.clone()).await?, Some(val.clone()));
+    assert_eq!(
+        store
+            .scan_prefix(vec![0xff], 10, ScanOrder::Forward)
+            .await?,
+        vec![(key.clone(), val.clone()), (vec![0xff, 0xff], vec![0x80])]
+    );
+    drop(store);
+
+    // once from the logfiles, once from the hint files
+    for _ in 0..2 {
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        assert_eq!(store.get(key.clone()).await?, Some(val.clone()));
+        assert_eq!(store.get(vec![0xfe]).await?, Some(vec![0x81]));
+    }
+
+    Ok(())
+}
+
+// Values should be compressed past the threshold, and records of
+// every codec should stay readable across reopens and compactions
+#[tokio::test]
+async fn compressed_values() -> Result<()> {
+    let document = |id: u32| {
+        format!(
+            r#"{{"id": {}, "tags": [{}]}}"#,
+            id,
+            vec![r#""repetitive""#; 100].join(", ")
+        )
+        .into_bytes()
+    };
+
+    for compression in [Compression::Lz4, Compression::Zstd] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .compaction_threshold(64 * 1024)
+            .pool_capacity(1);
+
+        let store: KvStore<RayonThreadPool> = options
+            .clone()
+            .compression(compression)
+            .compression_threshold(64)
+            .open(temp_dir.path())?;
+        assert_eq!(store.value_stats().compression_ratio(), 1.0);
+        store.set(b"small".to_vec(), b"tiny value".to_vec()).await?;
+        let stats = store.value_stats();
+        assert_eq!(stats.value_bytes, stats.stored_value_bytes);
+
+        for id in 0..100 {
+            store
+                .set(format!("doc{}", id).into_bytes(), document(id))
+                .await?;
+        }
+        let mut batch = WriteBatch::new();
+        batch.set(b"doc100".to_vec(), document(100));
+        store.write_batch(batch).await?;
+        assert!(store.value_stats().compression_ratio() > 4.0);
+        assert_eq!(store.get(b"doc7".to_vec()).await?, Some(document(7)));
+        drop(store);
+
+        // mix in uncompressed records, then compact them all together
+        let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+        assert_eq!(store.get(b"doc100".to_vec()).await?, Some(document(100)));
+        for iter in 0..200 {
+            store
+                .set(format!("doc{}", iter % 50).into_bytes(), document(iter))
+                .await?;
+        }
+        assert_eq!(store.value_stats().compression_ratio(), 1.0);
+        drop(store);
+
+        let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+        assert_eq!(
+            store.get(b"small".to_vec()).await?,
+            Some(b"tiny value".to_vec())
+        );
+        for id in 0..50 {
+            let key = format!("doc{}", id).into_bytes();
+            assert_eq!(store.get(key).await?, Some(document(id + 150)));
+        }
+        for id in 50..101 {
+            let key = format!("doc{}", id).into_bytes();
+            assert_eq!(store.get(key).await?, Some(document(id)));
+        }
+    }
+
+    Ok(())
+}
+
+// A snapshot should keep seeing the data as it was when taken
+#[tokio::test]
+async fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+
+    for key_id in 0..10 {
+        store
+            .set(
+                format!("key{}", key_id).into_bytes(),
+                format!("value{}", key_id).into_bytes(),
+            )
+            .await?;
+    }
+    let snapshot = store.snapshot().await?;
+
+    store.set(b"key0".to_vec(), b"changed".to_vec()).await?;
+    store.remove(b"key1".to_vec()).await?;
+    store.set(b"key10".to_vec(), b"value10".to_vec()).await?;
+
+    assert_eq!(
+        snapshot.get(b"key0".to_vec()).await?,
+        Some(b"value0".to_vec())
+    );
+    assert_eq!(
+        snapshot.get(b"key1".to_vec()).await?,
+        Some(b"value1".to_vec())
+    );
+    assert_eq!(snapshot.get(b"key10".to_vec()).await?, None);
+    assert_eq!(
+        store.get(b"key0".to_vec()).await?,
+        Some(b"changed".to_vec())
+    );
+
+    let expected: Vec<(Vec<u8>, Vec<u8>)> = (0..10)
+        .map(|key_id| {
+            (
+                format!("key{}", key_id).into_bytes(),
+                format!("value{}", key_id).into_bytes(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        snapshot
+            .scan_prefix(b"key".to_vec(), 100, ScanOrder::Forward)
+            .await?,
+        expected
+    );
+    assert_eq!(
+        snapshot
+            .scan(
+                (Bound::Excluded(b"key7".to_vec()), Bound::Unbounded),
+                100,
+                ScanOrder::Reverse
+            )
+            .await?,
+        vec![
+            (b"key9".to_vec(), b"value9".to_vec()),
+            (b"key8".to_vec(), b"value8".to_vec()),
+        ]
+    );
+
+    Ok(())
+}
+
+// Compaction should keep the logfiles of an open snapshot
+// until it is dropped
+#[tokio::test]
+async fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .compaction_threshold(4 * 1024)
+        .pool_capacity(2)
+        .open(temp_dir.path())?;
+
+    store.set(b"key".to_vec(), b"old".to_vec()).await?;
+    let snapshot = store.snapshot().await?;
+
+    let first_log = temp_dir.path().join("1.log");
+    let retired_log = temp_dir.path().join("1.log.retired");
+    for iter in 0..1000 {
+        store.set(b"key".to_vec(), b"x".repeat(100)).await?;
+        if !first_log.exists() {
+            break;
+        }
+        if iter % 10 == 9 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+    assert!(!first_log.exists(), "compaction did not run");
+    assert!(retired_log.exists());
+    assert_eq!(snapshot.get(b"key".to_vec()).await?, Some(b"old".to_vec()));
+
+    drop(snapshot);
+    assert!(!retired_log.exists());
+    assert_eq!(store.get(b"key".to_vec()).await?, Some(b"x".repeat(100)));
+
+    Ok(())
+}
+
+// A transaction should read a consistent view plus its own
+// writes, which nobody sees before it commits
+#[tokio::test]
+async fn transaction_isolation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
+
+    let mut txn = store.begin().await?;
+    store.set(b"key1".to_vec(), b"changed".to_vec()).await?;
+    assert_eq!(txn.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+
+    txn.set(b"key3".to_vec(), b"value3".to_vec()).await?;
+    txn.remove(b"key2".to_vec()).await?;
+    assert_eq!(txn.get(b"key3".to_vec()).await?, Some(b"value3".to_vec()));
+    assert_eq!(txn.get(b"key2".to_vec()).await?, None);
+    assert_eq!(store.get(b"key3".to_vec()).await?, None);
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+    assert_eq!(
+        txn.remove(b"key4".to_vec()).await.unwrap_err().kind(),
+        KVErrorKind::KeyNotFound
+    );
+
+    txn.commit().await?;
+    assert_eq!(
+        store.get(b"key1".to_vec()).await?,
+        Some(b"changed".to_vec())
+    );
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
+    assert_eq!(store.get(b"key3".to_vec()).await?, Some(b"value3".to_vec()));
+
+    let mut txn = store.begin().await?;
+    txn.set(b"key4".to_vec(), b"value4".to_vec()).await?;
+    txn.rollback().await?;
+    assert_eq!(store.get(b"key4".to_vec()).await?, None);
+
+    // committed transactions are recovered, uncommitted ones are not
+    let mut txn = store.begin().await?;
+    txn.set(b"key5".to_vec(), b"value5".to_vec()).await?;
+    drop(txn);
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    assert_eq!(store.get(b"key2".to_vec()).await?, None);
+    assert_eq!(store.get(b"key3".to_vec()).await?, Some(b"value3".to_vec()));
+    assert_eq!(store.get(b"key5".to_vec()).await?, None);
+
+    Ok(())
+}
+
+// Of two transactions writing the same key, only the first to commit wins
+#[tokio::test]
+async fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    store.set(b"key".to_vec(), b"value".to_vec()).await?;
+
+    let mut txn1 = store.begin().await?;
+    let mut txn2 = store.begin().await?;
+    let mut txn3 = store.begin().await?;
+    txn1.set(b"key".to_vec(), b"value1".to_vec()).await?;
+    txn2.remove(b"key".to_vec()).await?;
+    txn3.set(b"other".to_vec(), b"value3".to_vec()).await?;
+
+    txn1.commit().await?;
+    assert_eq!(
+        txn2.commit().await.unwrap_err().kind(),
+        KVErrorKind::TransactionConflict
+    );
+    txn3.commit().await?;
+    assert_eq!(store.get(b"key".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(
+        store.get(b"other".to_vec()).await?,
+        Some(b"value3".to_vec())
+    );
+
+    // plain writes conflict as well
+    let mut txn = store.begin().await?;
+    txn.set(b"other".to_vec(), b"value4".to_vec()).await?;
+    store.remove(b"other".to_vec()).await?;
+    assert_eq!(
+        txn.commit().await.unwrap_err().kind(),
+        KVErrorKind::TransactionConflict
+    );
+    assert_eq!(store.get(b"other".to_vec()).await?, None);
+
+    Ok(())
+}
+
+// A transaction should keep reading the values it began with,
+// and their logfiles, through a compaction
+#[tokio::test]
+async fn transaction_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .compaction_threshold(4 * 1024)
+        .pool_capacity(2)
+        .open(temp_dir.path())?;
+
+    store.set(b"key".to_vec(), b"old".to_vec()).await?;
+    store.set(b"gone".to_vec(), b"old".to_vec()).await?;
+    let txn = store.begin().await?;
+    store.remove(b"gone".to_vec()).await?;
+
+    let first_log = temp_dir.path().join("1.log");
+    let retired_log = temp_dir.path().join("1.log.retired");
+    for iter in 0..1000 {
+        store.set(b"key".to_vec(), b"x".repeat(100)).await?;
+        if !first_log.exists() {
+            break;
+        }
+        if iter % 10 == 9 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+    assert!(!first_log.exists(), "compaction did not run");
+    assert!(retired_log.exists());
+    assert_eq!(txn.get(b"key".to_vec()).await?, Some(b"old".to_vec()));
+    assert_eq!(txn.get(b"gone".to_vec()).await?, Some(b"old".to_vec()));
+
+    drop(txn);
+    assert!(!retired_log.exists());
+    assert_eq!(store.get(b"key".to_vec()).await?, Some(b"x".repeat(100)));
+    assert_eq!(store.get(b"gone".to_vec()).await?, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // concurrent set in 8 threads
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    let mut handles = vec![];
+    for i in 0..10000 {
+        // since we're using async-trait, the returned future from kvstore is not 'static
+        // and hence cannot be moved directly to tokio.
+        let store = store.clone();
+        let handle = tokio::spawn(async move {
+            store
+                .set(
+                    format!("key{}", i).into_bytes(),
+                    format!("value{}", i).into_bytes(),
+                )
+                .await
+                .unwrap();
+        });
+        handles.push(handle);
+    }
+    join_all(handles).await;
+
+    // We only check concurrent set in this test, so we check sequentially here
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..10000 {
+        assert_eq!(
+            store.get(format!("key{}", i).into_bytes()).await?,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+
+    Ok(())
+}
+
+// Concurrent sets and removes committed together should each get
+// their own result, and all make it to disk
+#[tokio::test]
+async fn concurrent_writes_with_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .sync_policy(SyncPolicy::Always)
+        .pool_capacity(8);
+    let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+    for i in 0..500 {
+        store
+            .set(format!("old{}", i).into_bytes(), b"old".to_vec())
+            .await?;
+    }
+
+    let mut handles = vec![];
+    for i in 0..1000 {
+        let store = store.clone();
+        handles.push(tokio::spawn(async move {
+            let new_key = format!("new{}", i).into_bytes();
+            let set = store.set(new_key, b"new".to_vec()).await;
+            // the removes past old499 target keys that never existed
+            let old_key = format!("old{}", i).into_bytes();
+            let remove = store.remove(old_key).await.map_err(|err| err.kind());
+            (set.is_ok(), remove)
+        }));
+    }
+    for (i, handle) in join_all(handles).await.into_iter().enumerate() {
+        let (set_ok, remove) = handle.unwrap();
+        assert!(set_ok);
+        if i < 500 {
+            assert_eq!(remove, Ok(()));
+        } else {
+            assert_eq!(remove, Err(KVErrorKind::KeyNotFound));
+        }
+    }
+    drop(store);
+
+    let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(
+            store.get(format!("new{}", i).into_bytes()).await?,
+            Some(b"new".to_vec())
+        );
+        assert_eq!(store.get(format!("old{}", i).into_bytes()).await?, None);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    // We only check concurrent get in this test, so we set sequentially here
+    for i in 0..100 {
+        store
+            .set(
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .await
+            .unwrap();
+    }
+
+    let mut handles = vec![];
+    for thread_id in 0..100 {
+        for i in 0..100 {
+            let key_id = (i + thread_id) % 100;
+            let store = store.clone();
+            let h = tokio::spawn(async move {
+                let res = store
+                    .get(format!("key{}", key_id).into_bytes())
+                    .await
+                    .unwrap();
+                assert_eq!(res, Some(format!("value{}", key_id).into_bytes()));
+            });
+            handles.push(h);
+        }
+    }
+    join_all(handles).await;
+
+    // reload from disk and test again
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    let mut handles = vec![];
+    for thread_id in 0..100 {
+        for i in 0..100 {
+            let key_id = (i + thread_id) % 100;
+            let store = store.clone();
+            let h = tokio::spawn(async move {
+                let res = store
+                    .get(format!("key{}", key_id).into_bytes())
+                    .await
+                    .unwrap();
+                assert_eq!(res, Some(format!("value{}", key_id).into_bytes()));
+            });
+            handles.push(h);
+        }
+    }
+    join_all(handles).await;
+
+    Ok(())
+}
+
+// Gets and scans running alongside writes and compactions should
+// only ever see values that were written, and no write should be lost
+#[tokio::test]
+async fn reads_during_writes_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .compaction_threshold(8 * 1024)
+        .pool_capacity(8)
+        .open(temp_dir.path())?;
+    let value_of = |val: Option<Vec<u8>>| -> u32 {
+        String::from_utf8(val.expect("key disappeared"))
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    for key_id in 0..50 {
+        store
+            .set(format!("key{}", key_id).into_bytes(), b"0".to_vec())
+            .await?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        tokio::spawn(async move {
+            for iter in 1..=40 {
+                for key_id in 0..50 {
+                    let key = format!("key{}", key_id).into_bytes();
+                    store
+                        .set(key, format!("{}", iter).into_bytes())
+                        .await
+                        .unwrap();
+                }
+            }
+        })
+    };
+    let mut readers = vec![];
+    for reader_id in 0..4 {
+        let store = store.clone();
+        readers.push(tokio::spawn(async move {
+            for i in 0..200 {
+                let key = format!("key{}", (i + reader_id) % 50).into_bytes();
+                assert!(value_of(store.get(key).await.unwrap()) <= 40);
+                let pairs = store
+                    .scan((Bound::Unbounded, Bound::Unbounded), 50, ScanOrder::Forward)
+                    .await
+                    .unwrap();
+                assert_eq!(pairs.len(), 50);
+            }
+        }));
+    }
+    writer.await.unwrap();
+    for reader in join_all(readers).await {
+        reader.unwrap();
+    }
+
+    for key_id in 0..50 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(value_of(store.get(key).await?), 40);
+    }
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key_id in 0..50 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(value_of(store.get(key).await?), 40);
+    }
+    Ok(())
+}
+
+// A store directory should only be open for writing once, while
+// read-only stores can open it alongside the writer
+#[tokio::test]
+async fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key".to_vec(), b"value".to_vec()).await?;
+
+    assert_eq!(
+        KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)
+            .err()
+            .map(|err| err.kind()),
+        Some(KVErrorKind::DirectoryLocked)
+    );
+
+    let reader: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .read_only(true)
+        .open(temp_dir.path())?;
+    let other_reader: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .read_only(true)
+        .open(temp_dir.path())?;
+    assert_eq!(reader.get(b"key".to_vec()).await?, Some(b"value".to_vec()));
+    assert_eq!(
+        other_reader.get(b"key".to_vec()).await?,
+        Some(b"value".to_vec())
+    );
+    assert_eq!(
+        reader
+            .set(b"key".to_vec(), b"other".to_vec())
+            .await
+            .unwrap_err()
+            .kind(),
+        KVErrorKind::ReadOnly
+    );
+    assert_eq!(
+        reader.remove(b"key".to_vec()).await.unwrap_err().kind(),
+        KVErrorKind::ReadOnly
+    );
+    assert!(reader.begin().await.is_err());
+
+    // the lock goes away with the writer, readers don't hold it
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key".to_vec()).await?, Some(b"value".to_vec()));
+
+    Ok(())
+}
+
+// A read-only store should keep reading the values it saw when it
+// opened, while the writer compacts their logfiles away
+#[tokio::test]
+async fn read_only_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id).into_bytes();
+        store.set(key, b"old".to_vec()).await?;
+    }
+
+    let reader: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .read_only(true)
+        .open(temp_dir.path())?;
+    for _ in 0..10 {
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id).into_bytes();
+            store.set(key, b"new".to_vec()).await?;
+        }
+    }
+    // let the last compaction finish
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(reader.get(key).await?, Some(b"old".to_vec()));
+    }
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key0".to_vec()).await?, Some(b"new".to_vec()));
+
+    Ok(())
+}
+
+// A read-only store should not map the logfile the writer appends to
+#[tokio::test]
+async fn read_only_leaves_active_log_unmapped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key".to_vec(), b"value".to_vec()).await?;
+
+    let reader: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .read_only(true)
+        .mmap_reads(true)
+        .open(temp_dir.path())?;
+    assert_eq!(reader.get(b"key".to_vec()).await?, Some(b"value".to_vec()));
+    #[cfg(target_os = "linux")]
+    {
+        let maps = fs::read_to_string("/proc/self/maps").unwrap();
+        let active_log = temp_dir.path().join("1.log");
+        assert!(!maps.contains(active_log.to_str().unwrap()));
+    }
+
+    store.set(b"other".to_vec(), b"value".to_vec()).await?;
+    assert_eq!(reader.get(b"key".to_vec()).await?, Some(b"value".to_vec()));
+
+    Ok(())
+}
+
+// A checkpoint taken while writes and compactions go on should
+// restore to the data as it was when it was taken
+#[tokio::test]
+async fn checkpoint_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(4 * 1024)
+        .max_file_size(4 * 1024)
+        .pool_capacity(4);
+    let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id).into_bytes();
+        store.set(key, b"0".to_vec()).await?;
+    }
+    store.remove(b"key0".to_vec()).await?;
+
+    let writer = {
+        let store = store.clone();
+        tokio::spawn(async move {
+            for iter in 1..=20 {
+                for key_id in 1..100 {
+                    let key = format!("key{}", key_id).into_bytes();
+                    store
+                        .set(key, format!("{}", iter).into_bytes())
+                        .await
+                        .unwrap();
+                }
+            }
+        })
+    };
+    let first = backup_dir.path().join("first");
+    store.checkpoint(first.clone()).await?;
+    writer.await.unwrap();
+    let second = backup_dir.path().join("second");
+    store.checkpoint(second.clone()).await?;
+    assert!(store.checkpoint(second.clone()).await.is_err());
+    // copies, which recovering the store can never truncate
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        for entry in fs::read_dir(&second)? {
+            assert_eq!(entry?.metadata()?.nlink(), 1);
+        }
+    }
+
+    // taken while the writer was running, any value will do
+    let restored: KvStore<RayonThreadPool> =
+        KvStore::restore(&first, backup_dir.path().join("restored"), &options)?;
+    assert_eq!(restored.get(b"key0".to_vec()).await?, None);
+    for key_id in 1..100 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert!(restored.get(key).await?.is_some());
+    }
+
+    // a store that is open can't be restored over
+    store.set(b"key0".to_vec(), b"new".to_vec()).await?;
+    assert_eq!(
+        KvStore::<RayonThreadPool>::restore(&second, temp_dir.path(), &options)
+            .err()
+            .map(|err| err.kind()),
+        Some(KVErrorKind::DirectoryLocked)
+    );
+    drop(store);
+    let store: KvStore<RayonThreadPool> = KvStore::restore(&second, temp_dir.path(), &options)?;
+    assert_eq!(store.get(b"key0".to_vec()).await?, None);
+    for key_id in 1..100 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(store.get(key).await?, Some(b"20".to_vec()));
+    }
+
+    Ok(())
+}
+
+// Merges should read and write a value with no other write in between,
+// keep its ttl, and fail on values they cannot merge into
+#[tokio::test]
+async fn merge_operators() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .pool_capacity(4)
+        .merge_operator(|_, current, operand| {
+            // keep the biggest operand, an empty one removing the key
+            if operand.is_empty() {
+                return None;
+            }
+            Some(
+                current
+                    .map_or(operand, |current| current.max(operand))
+                    .to_vec(),
+            )
+        });
+    let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+
+    assert_eq!(store.incr_by(b"counter".to_vec(), 5).await?, 5);
+    assert_eq!(store.incr_by(b"counter".to_vec(), -7).await?, -2);
+    assert_eq!(store.get(b"counter".to_vec()).await?, Some(b"-2".to_vec()));
+    let incrs = (0..200).map(|_| {
+        let store = store.clone();
+        tokio::spawn(async move { store.incr_by(b"counter".to_vec(), 1).await })
+    });
+    for res in join_all(incrs).await {
+        res.unwrap()?;
+    }
+    assert_eq!(store.get(b"counter".to_vec()).await?, Some(b"198".to_vec()));
+
+    store.set(b"text".to_vec(), b"abc".to_vec()).await?;
+    let err = store.incr_by(b"text".to_vec(), 1).await.unwrap_err();
+    assert_eq!(err.kind(), KVErrorKind::InvalidInteger);
+    store
+        .set(b"max".to_vec(), i64::MAX.to_string().into_bytes())
+        .await?;
+    let err = store.incr_by(b"max".to_vec(), 1).await.unwrap_err();
+    assert_eq!(err.kind(), KVErrorKind::InvalidInteger);
+
+    store.append(b"text".to_vec(), b"def".to_vec()).await?;
+    store.append(b"list".to_vec(), b"a,".to_vec()).await?;
+    store.append(b"list".to_vec(), b"b,".to_vec()).await?;
+    assert_eq!(store.get(b"text".to_vec()).await?, Some(b"abcdef".to_vec()));
+    assert_eq!(store.get(b"list".to_vec()).await?, Some(b"a,b,".to_vec()));
+
+    store.merge(b"high".to_vec(), b"m".to_vec()).await?;
+    store.merge(b"high".to_vec(), b"c".to_vec()).await?;
+    store.merge(b"high".to_vec(), b"x".to_vec()).await?;
+    assert_eq!(store.get(b"high".to_vec()).await?, Some(b"x".to_vec()));
+    store.merge(b"high".to_vec(), Vec::new()).await?;
+    assert_eq!(store.get(b"high".to_vec()).await?, None);
+
+    store
+        .set_with_ttl(b"limit".to_vec(), b"1".to_vec(), Duration::from_millis(200))
+        .await?;
+    assert_eq!(store.incr_by(b"limit".to_vec(), 1).await?, 2);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(store.get(b"limit".to_vec()).await?, None);
+    assert_eq!(store.incr_by(b"limit".to_vec(), 1).await?, 1);
+
+    drop(store);
+    let store: KvStore<RayonThreadPool> = KvStoreOptions::new().open(temp_dir.path())?;
+    assert_eq!(store.get(b"counter".to_vec()).await?, Some(b"198".to_vec()));
+    assert_eq!(store.get(b"list".to_vec()).await?, Some(b"a,b,".to_vec()));
+    let err = store
+        .merge(b"high".to_vec(), b"a".to_vec())
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), KVErrorKind::NoMergeOperator);
+
+    Ok(())
+}
+
+// stats should count live keys and stale bytes, and note compactions
+#[tokio::test]
+async fn engine_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .compaction_threshold(4 * 1024)
+        .pool_capacity(2)
+        .open(temp_dir.path())?;
+    let stats = store.stats().await?;
+    assert_eq!(stats.key_count, 0);
+    assert_eq!(stats.uncompacted_bytes, Some(0));
+    assert_eq!(stats.generations, Some(1));
+    assert_eq!(stats.last_compaction, None);
+
+    for key_id in 0..10 {
+        let key = format!("key{}", key_id).into_bytes();
+        store.set(key, b"value".repeat(20)).await?;
+    }
+    store
+        .set_with_ttl(
+            b"ttl".to_vec(),
+            b"value".to_vec(),
+            Duration::from_millis(50),
+        )
+        .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let stats = store.stats().await?;
+    assert_eq!(stats.key_count, 10);
+    assert!(stats.live_bytes > 10 * 100);
+    assert!(stats.disk_bytes > stats.live_bytes);
+    assert_eq!(stats.uncompacted_bytes, Some(0));
+
+    store.remove(b"key0".to_vec()).await?;
+    let stats = store.stats().await?;
+    assert_eq!(stats.key_count, 9);
+    assert!(stats.uncompacted_bytes > Some(100));
+
+    // a read-only store can't tell what the writer would compact
+    let reader: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .read_only(true)
+        .open(temp_dir.path())?;
+    let stats = reader.stats().await?;
+    assert_eq!(stats.key_count, 9);
+    assert_eq!(stats.uncompacted_bytes, None);
+    drop(reader);
+
+    for iter in 0..100 {
+        store.set(b"key1".to_vec(), b"value".repeat(20)).await?;
+        let stats = store.stats().await?;
+        if stats.last_compaction.is_some() {
+            assert_eq!(stats.key_count, 9);
+            assert!(stats.generations > Some(1));
+            return Ok(());
+        }
+        if iter % 10 == 9 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    panic!("No compaction was reported");
+}
+
+// The archive should keep every write through compactions and
+// reopens, and restore the data as it was at any point since
+#[tokio::test]
+async fn archive_and_point_in_time_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(2 * 1024)
+        .max_file_size(2 * 1024)
+        .archive_dir(archive_dir.path());
+    let before = SystemTime::now();
+    std::thread::sleep(Duration::from_millis(10));
+
+    let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+    for key_id in 0..50 {
+        let key = format!("key{}", key_id).into_bytes();
+        store.set(key, b"1".to_vec()).await?;
+    }
+    std::thread::sleep(Duration::from_millis(10));
+    let first_pass = SystemTime::now();
+    std::thread::sleep(Duration::from_millis(10));
+    for iter in 2..=20 {
+        for key_id in 0..50 {
+            let key = format!("key{}", key_id).into_bytes();
+            store.set(key, format!("{}", iter).into_bytes()).await?;
+        }
+    }
+    drop(store);
+    let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+    store.remove(b"key0".to_vec()).await?;
+    drop(store);
+
+    let restore = |name: &str, point: RestorePoint| {
+        KvStore::<RayonThreadPool>::restore_archive(
+            archive_dir.path(),
+            restore_dir.path().join(name),
+            point,
+            &KvStoreOptions::new(),
+        )
+    };
+    let latest = restore("latest", RestorePoint::Latest)?;
+    assert_eq!(latest.get(b"key0".to_vec()).await?, None);
+    assert_eq!(latest.get(b"key1".to_vec()).await?, Some(b"20".to_vec()));
+
+    let restored = restore("first_pass", RestorePoint::Time(first_pass))?;
+    for key_id in 0..50 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(restored.get(key).await?, Some(b"1".to_vec()));
+    }
+
+    // the 75th write is the second pass setting key24
+    let restored = restore("seq", RestorePoint::Seq(75))?;
+    assert_eq!(restored.get(b"key24".to_vec()).await?, Some(b"2".to_vec()));
+    assert_eq!(restored.get(b"key25".to_vec()).await?, Some(b"1".to_vec()));
+
+    assert!(restore("before", RestorePoint::Time(before)).is_err());
+    Ok(())
+}
+
+// Reopening the store should archive the records a crash
+// kept from reaching the archive
+#[tokio::test]
+async fn archive_catches_up_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().archive_dir(archive_dir.path());
+
+    let store: KvStore<RayonThreadPool> = options.open(temp_dir.path())?;
+    for key_id in 0..10 {
+        let key = format!("key{}", key_id).into_bytes();
+        store.set(key, b"value".to_vec()).await?;
+    }
+    drop(store);
+
+    // lose the second half of the archived logfile
+    let archived = OpenOptions::new()
+        .write(true)
+        .open(archive_dir.path().join("1.log"))?;
+    let len = archived.metadata()?.len();
+    archived.set_len(len / 2)?;
+    drop(archived);
+
+    drop(options.open::<RayonThreadPool>(temp_dir.path())?);
+    let restored = KvStore::<RayonThreadPool>::restore_archive(
+        archive_dir.path(),
+        restore_dir.path().join("restored"),
+        RestorePoint::Latest,
+        &KvStoreOptions::new(),
+    )?;
+    for key_id in 0..10 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(restored.get(key).await?, Some(b"value".to_vec()));
+    }
+
+    Ok(())
+}
+
+// SledKvsEngine should persist values across reopen
+#[tokio::test]
+async fn sled_get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key3".to_vec()).await?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sled_remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open_with_flush(
+        temp_dir.path(),
+        1,
+        FlushPolicy::Periodic(100),
+    )?;
+    assert!(store.remove(b"key1".to_vec()).await.is_err());
+
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    assert!(store.remove(b"key1".to_vec()).await.is_ok());
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn sled_expire_keys_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    store
+        .set_with_ttl(
+            b"key1".to_vec(),
+            b"value1".to_vec(),
+            Duration::from_millis(300),
+        )
+        .await?;
+    store
+        .set_with_ttl(
+            b"key2".to_vec(),
+            b"value2".to_vec(),
+            Duration::from_millis(300),
+        )
+        .await?;
+    store.set(b"key2".to_vec(), b"value3".to_vec()).await?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value3".to_vec()));
+    assert!(store.remove(b"key1".to_vec()).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn sled_scan_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key in ["a1", "b1", "b2", "b3", "c1"] {
+        store
+            .set(key.into(), format!("value-{}", key).into_bytes())
+            .await?;
+    }
+    store.remove(b"b2".to_vec()).await?;
+    store
+        .set_with_ttl(b"b4".to_vec(), b"value".to_vec(), Duration::ZERO)
+        .await?;
+
+    let all = store
+        .scan((Bound::Unbounded, Bound::Unbounded), 2, ScanOrder::Reverse)
+        .await?;
+    assert_eq!(
+        all,
+        [
+            (b"c1".to_vec(), b"value-c1".to_vec()),
+            (b"b3".to_vec(), b"value-b3".to_vec())
+        ]
+    );
+    let prefixed = store
+        .scan_prefix(b"b".to_vec(), 10, ScanOrder::Forward)
+        .await?;
+    assert_eq!(
+        prefixed,
+        [
+            (b"b1".to_vec(), b"value-b1".to_vec()),
+            (b"b3".to_vec(), b"value-b3".to_vec())
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn sled_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key2".to_vec(), b"value2".to_vec())
+        .remove(b"key1".to_vec());
+    store.write_batch(batch).await?;
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key2".to_vec(), b"value3".to_vec())
+        .remove(b"key1".to_vec());
+    let err = store.write_batch(batch).await.unwrap_err();
+    assert_eq!(err.kind(), KVErrorKind::KeyNotFound);
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn sled_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    store
+        .compare_and_swap(b"key1".to_vec(), None, Some(b"value1".to_vec()))
+        .await?
+        .unwrap();
+    let err = store
+        .compare_and_swap(
+            b"key1".to_vec(),
+            Some(b"value2".to_vec()),
+            Some(b"value3".to_vec()),
+        )
+        .await?
+        .unwrap_err();
+    assert_eq!(err.current, Some(b"value1".to_vec()));
+    store
+        .compare_and_swap(b"key1".to_vec(), Some(b"value1".to_vec()), None)
+        .await?
+        .unwrap();
+    assert_eq!(store.get(b"key1".to_vec()).await?, None);
+
+    store
+        .set_with_ttl(b"key2".to_vec(), b"value".to_vec(), Duration::ZERO)
+        .await?;
+    store
+        .compare_and_swap(b"key2".to_vec(), None, Some(b"value2".to_vec()))
+        .await?
+        .unwrap();
+    assert_eq!(store.get(b"key2".to_vec()).await?, Some(b"value2".to_vec()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn sled_snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
+    let snapshot = store.snapshot().await?;
+
+    store.set(b"key1".to_vec(), b"changed".to_vec()).await?;
+    store.remove(b"key2".to_vec()).await?;
+    store.set(b"key3".to_vec(), b"value3".to_vec()).await?;
+
+    assert_eq!(
+        snapshot.get(b"key1".to_vec()).await?,
+        Some(b"value1".to_vec())
+    );
+    assert_eq!(snapshot.get(b"key3".to_vec()).await?, None);
+    assert_eq!(
+        snapshot
+            .scan_prefix(b"key".to_vec(), 100, ScanOrder::Reverse)
+            .await?,
+        vec![
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"key1".to_vec(), b"value1".to_vec()),
+        ]
+    );
+    assert_eq!(
+        snapshot
+            .scan_prefix(b"key".to_vec(), 2, ScanOrder::Forward)
+            .await?,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sled_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    store.set(b"key".to_vec(), b"value".to_vec()).await?;
+
+    let mut txn1 = store.begin().await?;
+    let mut txn2 = store.begin().await?;
+    txn1.set(b"key".to_vec(), b"value1".to_vec()).await?;
+    txn1.set(b"other".to_vec(), b"value1".to_vec()).await?;
+    txn2.remove(b"key".to_vec()).await?;
+    assert_eq!(store.get(b"other".to_vec()).await?, None);
+
+    txn1.commit().await?;
+    assert_eq!(
+        txn2.commit().await.unwrap_err().kind(),
+        KVErrorKind::TransactionConflict
+    );
+    assert_eq!(store.get(b"key".to_vec()).await?, Some(b"value1".to_vec()));
+    assert_eq!(
+        store.get(b"other".to_vec()).await?,
+        Some(b"value1".to_vec())
+    );
+
+    Ok(())
+}
+
+// A sled transaction should read the data as it was when it began
+#[tokio::test]
+async fn sled_transaction_reads_as_of_begin() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    store.set(b"key".to_vec(), b"old".to_vec()).await?;
+    store.set(b"gone".to_vec(), b"old".to_vec()).await?;
+
+    let txn = store.begin().await?;
+    store.set(b"key".to_vec(), b"new".to_vec()).await?;
+    store.remove(b"gone".to_vec()).await?;
+    store.set(b"added".to_vec(), b"new".to_vec()).await?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key".to_vec(), b"newer".to_vec());
+    store.write_batch(batch).await?;
+
+    assert_eq!(txn.get(b"key".to_vec()).await?, Some(b"old".to_vec()));
+    assert_eq!(txn.get(b"gone".to_vec()).await?, Some(b"old".to_vec()));
+    assert_eq!(txn.get(b"added".to_vec()).await?, None);
+    txn.rollback().await?;
+
+    // a transaction begun later sees the writes
+    let txn = store.begin().await?;
+    assert_eq!(txn.get(b"key".to_vec()).await?, Some(b"newer".to_vec()));
+    assert_eq!(txn.get(b"gone".to_vec()).await?, None);
+
+    Ok(())
+}
+
+// A SledKvsEngine checkpoint should open as a database of its own
+#[tokio::test]
+async fn sled_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    store.set(b"key".to_vec(), b"value".to_vec()).await?;
+    store
+        .set_with_ttl(
+            b"long".to_vec(),
+            b"value".to_vec(),
+            Duration::from_secs(3600),
+        )
+        .await?;
+    store
+        .set_with_ttl(
+            b"short".to_vec(),
+            b"value".to_vec(),
+            Duration::from_millis(100),
+        )
+        .await?;
+    store.checkpoint(backup_dir.path().to_path_buf()).await?;
+    store.set(b"key".to_vec(), b"changed".to_vec()).await?;
+
+    let copy = SledKvsEngine::<RayonThreadPool>::open(backup_dir.path(), 1)?;
+    assert_eq!(copy.get(b"key".to_vec()).await?, Some(b"value".to_vec()));
+    assert_eq!(copy.get(b"long".to_vec()).await?, Some(b"value".to_vec()));
+    // the expiration time came along, so the short one expires in
+    // the copy too, however long the checkpoint took
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while copy.get(b"short".to_vec()).await?.is_some() {
+        assert!(Instant::now() < deadline, "copied key never expired");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    Ok(())
+}
+
+// SledKvsEngine merges should behave like those of KvStore
+#[tokio::test]
+async fn sled_merge_operators() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let err = store
+        .merge(b"key".to_vec(), b"a".to_vec())
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), KVErrorKind::NoMergeOperator);
+    let store = store.with_merge_operator(|_, current, operand| {
+        let mut val = operand.to_vec();
+        val.extend_from_slice(current.unwrap_or_default());
+        Some(val)
+    });
+
+    let incrs = (0..100).map(|_| {
+        let store = store.clone();
+        tokio::spawn(async move { store.incr_by(b"counter".to_vec(), 2).await })
+    });
+    for res in join_all(incrs).await {
+        res.unwrap()?;
+    }
+    assert_eq!(store.incr_by(b"counter".to_vec(), -1).await?, 199);
+    store.set(b"text".to_vec(), b"abc".to_vec()).await?;
+    let err = store.incr_by(b"text".to_vec(), 1).await.unwrap_err();
+    assert_eq!(err.kind(), KVErrorKind::InvalidInteger);
+
+    store.append(b"text".to_vec(), b"def".to_vec()).await?;
+    store.merge(b"text".to_vec(), b"_".to_vec()).await?;
+    assert_eq!(
+        store.get(b"text".to_vec()).await?,
+        Some(b"_abcdef".to_vec())
+    );
+
+    store
+        .set_with_ttl(b"limit".to_vec(), b"1".to_vec(), Duration::from_millis(200))
+        .await?;
+    assert_eq!(store.incr_by(b"limit".to_vec(), 1).await?, 2);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(store.get(b"limit".to_vec()).await?, None);
+    assert_eq!(store.incr_by(b"limit".to_vec(), 1).await?, 1);
+
+    Ok(())
+}
+
+// SledKvsEngine stats should count live keys and leave out
+// what sled has no equivalent for
+#[tokio::test]
+async fn sled_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).await?;
+    store
+        .set_with_ttl(
+            b"ttl".to_vec(),
+            b"value".to_vec(),
+            Duration::from_millis(50),
+        )
+        .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let stats = store.stats().await?;
+    assert_eq!(stats.key_count, 2);
+    assert_eq!(stats.live_bytes, 20);
+    assert!(stats.disk_bytes > 0);
+    assert_eq!(stats.uncompacted_bytes, None);
+    assert_eq!(stats.generations, None);
+    assert_eq!(stats.last_compaction, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sled_binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let key = vec![0xff, 0x00, 0xfe];
+    let val: Vec<u8> = (0..=255).collect();
+
+    store.set(key.clone(), val.clone()).await?;
+    store.set(vec![0xff, 0xff], vec![0x80]).await?;
+    assert_eq!(store.get(key.clone()).await?, Some(val.clone()));
+    assert_eq!(
+        store
+            .scan_prefix(vec![0xff], 10, ScanOrder::Reverse)
+            .await?,
+        vec![(vec![0xff, 0xff], vec![0x80]), (key, val)]
+    );
 
     Ok(())
 }
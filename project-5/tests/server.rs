@@ -0,0 +1,242 @@
+use kvs_project_5::{
+    thread_pool::SharedQueueThreadPool, KvClient, KvServer, KvStore, KvsEngine, Response, Result,
+    ScanOrder, WriteBatch,
+};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Clients should be able to walk all keys one page at a time
+#[tokio::test]
+async fn scan_in_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 2)?;
+    tokio::spawn(KvServer::new(store).run("127.0.0.1:4010"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut client = KvClient::connect("127.0.0.1:4010").await?;
+    for key_id in 0..250 {
+        client
+            .send_set(
+                format!("key{:03}", key_id).into_bytes(),
+                format!("value{}", key_id).into_bytes(),
+            )
+            .await?;
+    }
+    client
+        .send_set(b"other".to_vec(), b"value".to_vec())
+        .await?;
+
+    for order in [ScanOrder::Forward, ScanOrder::Reverse] {
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = client
+                .send_scan(Some(b"key".to_vec()), 100, order, cursor)
+                .await?;
+            assert!(page.entries.len() <= 100);
+            keys.extend(page.entries.into_iter().map(|(key, _)| key));
+            cursor = page.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        let mut expected: Vec<Vec<u8>> = (0..250)
+            .map(|id| format!("key{:03}", id).into_bytes())
+            .collect();
+        if order == ScanOrder::Reverse {
+            expected.reverse();
+        }
+        assert_eq!(keys, expected);
+    }
+
+    // a page of no pairs is refused
+    assert!(client
+        .send_scan(Some(b"key".to_vec()), 0, ScanOrder::Forward, None)
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+// A batch should travel as one command
+#[tokio::test]
+async fn write_batch_in_one_command() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 2)?;
+    tokio::spawn(KvServer::new(store).run("127.0.0.1:4011"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut client = KvClient::connect("127.0.0.1:4011").await?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key1".to_vec(), b"value1".to_vec())
+        .remove(b"key2".to_vec());
+    let response = client.send_write_batch(batch.clone()).await?;
+    assert!(!response.success);
+    assert_eq!(client.send_get(b"key1".to_vec()).await?, None);
+
+    client
+        .send_set(b"key2".to_vec(), b"value2".to_vec())
+        .await?;
+    assert_eq!(
+        client.send_write_batch(batch).await?,
+        Response::success(b"".to_vec())
+    );
+    assert_eq!(
+        client.send_get(b"key1".to_vec()).await?,
+        Some(b"value1".to_vec())
+    );
+    assert_eq!(client.send_get(b"key2".to_vec()).await?, None);
+
+    Ok(())
+}
+
+// A transaction should span the commands of one connection
+#[tokio::test]
+async fn transaction_on_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 2)?;
+    tokio::spawn(KvServer::new(store).run("127.0.0.1:4012"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut client = KvClient::connect("127.0.0.1:4012").await?;
+    let mut other = KvClient::connect("127.0.0.1:4012").await?;
+    assert!(!client.send_commit().await?.success);
+
+    assert_eq!(client.send_begin().await?, Response::success(b"".to_vec()));
+    assert!(!client.send_begin().await?.success);
+    client.send_set(b"key".to_vec(), b"value".to_vec()).await?;
+    assert_eq!(
+        client.send_get(b"key".to_vec()).await?,
+        Some(b"value".to_vec())
+    );
+    assert_eq!(other.send_get(b"key".to_vec()).await?, None);
+    assert_eq!(client.send_commit().await?, Response::success(b"".to_vec()));
+    assert_eq!(
+        other.send_get(b"key".to_vec()).await?,
+        Some(b"value".to_vec())
+    );
+
+    client.send_begin().await?;
+    client.send_rm(b"key".to_vec()).await?;
+    other.send_set(b"key".to_vec(), b"changed".to_vec()).await?;
+    assert_eq!(
+        client.send_commit().await?,
+        Response::failure(b"Transaction conflict".to_vec())
+    );
+
+    // a transaction left open by a client that went away is rolled back
+    other.send_begin().await?;
+    other
+        .send_set(b"dropped".to_vec(), b"value".to_vec())
+        .await?;
+    drop(other);
+    assert_eq!(client.send_get(b"dropped".to_vec()).await?, None);
+
+    Ok(())
+}
+
+// Binary values should go over the wire unchanged
+#[tokio::test]
+async fn binary_values_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 2)?;
+    tokio::spawn(KvServer::new(store).run("127.0.0.1:4013"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut client = KvClient::connect("127.0.0.1:4013").await?;
+    let key = vec![0xc3, 0x28];
+    let val: Vec<u8> = (0..=255).cycle().take(1 << 20).collect();
+    assert_eq!(
+        client.send_set(key.clone(), val.clone()).await?,
+        Response::success(Vec::new())
+    );
+    assert_eq!(client.send_get(key).await?, Some(val));
+
+    Ok(())
+}
+
+// Clients should be able to have the server checkpoint its data
+#[tokio::test]
+async fn checkpoint_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 2)?;
+    let server = KvServer::new(store).checkpoint_dir(backup_dir.path());
+    tokio::spawn(server.run("127.0.0.1:4016"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut client = KvClient::connect("127.0.0.1:4016").await?;
+    client.send_set(b"key".to_vec(), b"value".to_vec()).await?;
+    assert_eq!(
+        client.send_checkpoint("backup").await?,
+        Response::success(Vec::new())
+    );
+    assert!(!client.send_checkpoint("backup").await?.success);
+    client
+        .send_set(b"key".to_vec(), b"changed".to_vec())
+        .await?;
+
+    // nothing outside of the checkpoint directory can be written to
+    let outside = temp_dir.path().join("outside");
+    assert!(!client.send_checkpoint(&outside).await?.success);
+    assert!(!client.send_checkpoint("../outside").await?.success);
+    assert!(!client.send_checkpoint(".").await?.success);
+    assert!(!outside.exists());
+    assert!(!backup_dir.path().join("../outside").exists());
+
+    let copy = KvStore::<SharedQueueThreadPool>::open(backup_dir.path().join("backup"), 1)?;
+    assert_eq!(copy.get(b"key".to_vec()).await?, Some(b"value".to_vec()));
+
+    Ok(())
+}
+
+// Stats should report on the server's store
+#[tokio::test]
+async fn stats_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 2)?;
+    tokio::spawn(KvServer::new(store).run("127.0.0.1:4017"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut client = KvClient::connect("127.0.0.1:4017").await?;
+    client.send_set(b"key1".to_vec(), b"value".to_vec()).await?;
+    client.send_set(b"key2".to_vec(), b"value".to_vec()).await?;
+    client.send_set(b"key2".to_vec(), b"other".to_vec()).await?;
+    let stats = client.send_stats().await?;
+    assert_eq!(stats.key_count, 2);
+    assert_eq!(stats.generations, Some(1));
+    assert!(stats.uncompacted_bytes > Some(0));
+
+    Ok(())
+}
+
+// IncrBy and Append should merge into values on the server
+#[tokio::test]
+async fn merges_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<SharedQueueThreadPool>::open(temp_dir.path(), 2)?;
+    tokio::spawn(KvServer::new(store).run("127.0.0.1:4018"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut client = KvClient::connect("127.0.0.1:4018").await?;
+    assert_eq!(
+        client.send_incr_by(b"counter".to_vec(), 3).await?,
+        Response::success("3")
+    );
+    assert_eq!(
+        client.send_incr_by(b"counter".to_vec(), -1).await?,
+        Response::success("2")
+    );
+    client
+        .send_append(b"counter".to_vec(), b"x".to_vec())
+        .await?;
+    assert_eq!(
+        client.send_get(b"counter".to_vec()).await?,
+        Some(b"2x".to_vec())
+    );
+    assert!(!client.send_incr_by(b"counter".to_vec(), 1).await?.success);
+
+    Ok(())
+}